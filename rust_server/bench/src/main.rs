@@ -0,0 +1,320 @@
+//! Measures recall and latency of tree queries against an exact brute force scan
+//!
+//! Ground truth is computed by reading every `RecordPage` once and scoring all queries against it,
+//! so the tree only has to fit on disk, not in memory.
+
+use kd_tree::tree::{ImmutTree, TopHits, SearchParams, TreeRecord};
+use kd_tree::data::Descriptor;
+use kd_tree::node::PagePointer;
+use kd_tree::encoding::PreparedQuery;
+
+use kdam::tqdm;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::time::Instant;
+
+use clap::Parser;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+
+    ///Directory containing the tree
+    #[arg(short, long)]
+    dirname: String,
+
+    ///File of query descriptors, one comma-separated descriptor per line. `#` lines are skipped
+    #[arg(short, long)]
+    query_file: String,
+
+    ///Number of neighbors per query
+    #[arg(short, long, default_value_t = 10)]
+    k: usize,
+
+    ///Write the JSON report here instead of stdout
+    #[arg(short, long)]
+    output: Option<String>,
+
+    ///Approximate search: stop after this many record pages
+    #[arg(long)]
+    max_pages: Option<usize>,
+
+    ///Approximate search: (1 + epsilon) pruning factor
+    #[arg(long, default_value_t = 0.0)]
+    epsilon: f32,
+}
+
+#[derive(Debug, Serialize)]
+struct Summary {
+    mean: f64,
+    min: f64,
+    p50: f64,
+    p90: f64,
+    p99: f64,
+    max: f64,
+}
+
+impl Summary {
+
+    fn from_values(values: &Vec<f64>) -> Self {
+
+        let mut sorted = values.clone();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        return Self {
+            mean: sorted.iter().sum::<f64>() / sorted.len().max(1) as f64,
+            min: percentile(&sorted, 0.0),
+            p50: percentile(&sorted, 0.50),
+            p90: percentile(&sorted, 0.90),
+            p99: percentile(&sorted, 0.99),
+            max: percentile(&sorted, 1.0),
+        };
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct BenchReport {
+    directory: String,
+    query_file: String,
+    num_queries: usize,
+    k: usize,
+    search_params: SearchParams,
+    exact_fraction: f64,
+    desc_length: usize,
+    num_records: usize,
+    num_record_pages: usize,
+    recall: Summary,
+    latency_ms: Summary,
+    nodes_visited: Summary,
+    record_pages_visited: Summary,
+    cache_hits: usize,
+    cache_misses: usize,
+    cache_hit_rate: f64,
+}
+
+///Nearest-rank percentile of already sorted values
+fn percentile(sorted: &Vec<f64>, q: f64) -> f64 {
+
+    if sorted.is_empty() {
+        return 0.0;
+    }
+
+    let idx = ((sorted.len() - 1) as f64 * q).round() as usize;
+    return sorted[idx];
+}
+
+fn read_queries(filename: &str, desc_length: usize) -> Vec<Descriptor> {
+
+    let contents = std::fs::read_to_string(filename).expect("Could not read query file");
+
+    let mut queries: Vec<Descriptor> = Vec::new();
+
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("#") {
+            continue;
+        }
+
+        let values: Result<Vec<f32>, _> = line.split(",")
+            .map(|x| x.replace("[", "").replace("]", "").trim().parse::<f32>())
+            .collect();
+
+        let values = match values {
+            Ok(x) => x,
+            Err(e) => panic!("Could not parse query on line {}: {}", i + 1, e),
+        };
+
+        if values.len() != desc_length {
+            panic!("Query on line {} has {} values, tree desc_length is {}", i + 1, values.len(), desc_length);
+        }
+
+        queries.push(Descriptor::from_vec(values, desc_length));
+    }
+
+    return queries;
+}
+
+///Exact k nearest neighbors of every query from a single pass over the record file, or over the
+///full precision vectors if the tree stores lossy descriptors
+fn brute_force(tree: &ImmutTree, queries: &Vec<Descriptor>, k: usize) -> (Vec<TopHits>, usize) {
+
+    //records and full precision vectors are both stored rotated on `pca_rotated` trees
+    let queries: Vec<Descriptor> = queries.iter().map(|x| tree.config.to_tree_space(x)).collect();
+
+    if let Some(vectors) = &tree.vectors {
+
+        let mut truth: Vec<TopHits> = queries.iter().map(|_| TopHits::new(k)).collect();
+        let pointer = PagePointer::Leaf(0);
+
+        for index in tqdm!(0..vectors.len()) {
+
+            let descriptor = vectors.get(index).unwrap();
+            let record = TreeRecord { index, length: descriptor.length, descriptor };
+
+            for (query, hits) in queries.iter().zip(truth.iter_mut()) {
+                hits.try_add(query.distance(&record.descriptor), &record, &pointer).unwrap();
+            }
+        }
+
+        return (truth, vectors.len() as usize);
+    }
+
+    let mut truth: Vec<TopHits> = queries.iter().map(|_| TopHits::new(k)).collect();
+    let prepared: Vec<PreparedQuery> = queries.iter().map(|x| tree.record_handler.codec.prepare(x)).collect();
+    let mut num_records: usize = 0;
+
+    for page_index in tqdm!(0..tree.record_handler.len()) {
+
+        let page = tree.record_handler.get_record_page_no_cache(&page_index).unwrap();
+        let pointer = PagePointer::Leaf(page_index);
+
+        num_records += page.len();
+
+        for (query, hits) in prepared.iter().zip(truth.iter_mut()) {
+            page.scan_into(query, hits, &pointer).unwrap();
+        }
+    }
+
+    return (truth, num_records);
+}
+
+fn hit_indices(hits: &TopHits) -> HashSet<u64> {
+    return hits.iter().map(|x| x.record.index).collect();
+}
+
+fn main() {
+
+    let args = Args::parse();
+
+    let tree = ImmutTree::read_from_directory(args.dirname.clone());
+
+    let queries = read_queries(&args.query_file, tree.config.desc_length);
+
+    let (truth, num_records) = brute_force(&tree, &queries, args.k);
+
+    //don't count the brute force scan against the cache numbers
+    let (base_hits, base_misses) = tree.record_handler.get_cache_stats();
+
+    let mut recalls: Vec<f64> = Vec::with_capacity(queries.len());
+    let mut latencies: Vec<f64> = Vec::with_capacity(queries.len());
+    let mut nodes_visited: Vec<f64> = Vec::with_capacity(queries.len());
+    let mut record_pages_visited: Vec<f64> = Vec::with_capacity(queries.len());
+    let mut num_exact: usize = 0;
+
+    let params = SearchParams { max_record_pages: args.max_pages, epsilon: args.epsilon, ..SearchParams::exact() };
+
+    for (query, true_hits) in tqdm!(queries.iter().zip(truth.iter())) {
+
+        let start = Instant::now();
+        let (hits, stats) = tree.get_top_hits_best_first(query, args.k, &params);
+        latencies.push(start.elapsed().as_secs_f64() * 1000.0);
+
+        let expected = hit_indices(true_hits);
+        let found = hit_indices(&hits);
+
+        let recall = match expected.len() {
+            0 => 1.0,
+            _ => expected.intersection(&found).count() as f64 / expected.len() as f64,
+        };
+
+        recalls.push(recall);
+        nodes_visited.push(stats.nodes_visited as f64);
+        record_pages_visited.push(stats.record_pages_visited as f64);
+        if stats.exact {
+            num_exact += 1;
+        }
+    }
+
+    let (hits, misses) = tree.record_handler.get_cache_stats();
+    let cache_hits = hits - base_hits;
+    let cache_misses = misses - base_misses;
+
+    let report = BenchReport {
+        directory: args.dirname.clone(),
+        query_file: args.query_file.clone(),
+        num_queries: queries.len(),
+        k: args.k,
+        search_params: params,
+        exact_fraction: num_exact as f64 / queries.len().max(1) as f64,
+        desc_length: tree.config.desc_length,
+        num_records,
+        num_record_pages: tree.record_handler.len(),
+        recall: Summary::from_values(&recalls),
+        latency_ms: Summary::from_values(&latencies),
+        nodes_visited: Summary::from_values(&nodes_visited),
+        record_pages_visited: Summary::from_values(&record_pages_visited),
+        cache_hits,
+        cache_misses,
+        cache_hit_rate: cache_hits as f64 / (cache_hits + cache_misses).max(1) as f64,
+    };
+
+    let s = serde_json::to_string_pretty(&report).unwrap();
+
+    match &args.output {
+        Some(filename) => std::fs::write(filename, s).unwrap(),
+        None => println!("{}", s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use kd_tree::tree::{Tree, TreeConfig};
+    use kd_tree::split::{PcaFitter, SplitStrategy};
+    use kd_tree::data::CompoundRecord;
+
+    #[test]
+    fn quick_brute_force_matches_tree() {
+
+        let n = 8;
+
+        //uneven variances so the rotation is far from the identity
+        let records: Vec<CompoundRecord> = (0..5000).map(|_| {
+            let mut record = CompoundRecord::random(n);
+            for i in 0..n {
+                record.descriptor.data[i] *= 0.5_f32.powi(i as i32);
+            }
+            record
+        }).collect();
+        let queries: Vec<Descriptor> = (0..20).map(|_| Descriptor::random(n)).collect();
+
+        let mut fitter = PcaFitter::new(n);
+        for record in records.iter() {
+            fitter.update(&record.descriptor);
+        }
+        let rotation = fitter.fit().unwrap();
+
+        for strategy in [SplitStrategy::Cyclic, SplitStrategy::PcaRotated] {
+
+            let mut config = TreeConfig::default();
+            config.desc_length = n;
+            config.directory = "/tmp/bench_brute_force/".to_string();
+            config.split_strategy = strategy;
+            config.rotation = match strategy {
+                SplitStrategy::PcaRotated => Some(rotation.clone()),
+                _ => None,
+            };
+
+            let mut build_tree = Tree::force_create_with_config(config.clone());
+
+            for record in records.iter() {
+                build_tree.add_record(record).unwrap();
+            }
+
+            build_tree.flush();
+
+            let tree = ImmutTree::read_from_directory(config.directory.clone());
+
+            let (truth, num_records) = brute_force(&tree, &queries, 10);
+            assert_eq!(num_records, records.len());
+
+            for (query, true_hits) in queries.iter().zip(truth.iter()) {
+
+                let (hits, _) = tree.get_top_hits_best_first(query, 10, &SearchParams::exact());
+
+                assert_eq!(hit_indices(&hits), hit_indices(true_hits), "{:?}", strategy);
+            }
+        }
+    }
+}
@@ -0,0 +1,306 @@
+//! Catching records that repeat an earlier record during `build-from-files`
+//!
+//! Catalogs often list the same molecule under several IDs (salts, stereo variants, vendor
+//! duplicates). With `--dedup`, each record is checked against what's already been added, by
+//! descriptor (looked up in the tree itself) and/or by SMILES, before it goes in. A duplicate is
+//! then skipped, merged into the earlier record, or added anyway and flagged.
+//!
+//! Every duplicate gets a line in `dedup_report.txt` next to `build_log.txt`. Merged identifiers
+//! are added to the database as aliases of the earlier record, along with their dataset, so it's
+//! reported under all of its IDs and catalogs.
+//!
+//! SMILES are compared as given, so they should be canonicalized before the build.
+
+use clap::ValueEnum;
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{BufWriter, Write};
+
+use kd_tree::data::{CompoundIndex, CompoundRecord};
+
+use crate::Index;
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum DedupMode {
+    ///Leave duplicates out of the index
+    Skip,
+    ///Leave duplicates out, keeping their identifiers as aliases of the earlier record
+    Merge,
+    ///Add duplicates as usual, but list them in the report
+    Flag,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum DedupKey {
+    ///Identical descriptors. Lossy trees need `keep_full_vectors` to compare them exactly.
+    Descriptor,
+    ///Identical SMILES strings
+    Smiles,
+    ///Either of the above
+    Either,
+}
+
+pub struct Deduplicator {
+    pub mode: DedupMode,
+    key: DedupKey,
+    ///Indexes of added records by a hash of their SMILES, confirmed against the database on a hit
+    smiles: HashMap<u64, Vec<CompoundIndex>>,
+    report: BufWriter<File>,
+    pub num_duplicates: usize,
+}
+
+///An earlier record that a new one repeats
+pub struct Duplicate {
+    pub index: CompoundIndex,
+    pub matched_on: &'static str,
+}
+
+impl Deduplicator {
+
+    pub fn create(directory: &str, mode: DedupMode, key: DedupKey) -> Result<Self, String> {
+
+        let report_path = format!("{}/dedup_report.txt", directory);
+        let file = File::create(&report_path).map_err(|e| format!("Could not create {}: {}", report_path, e))?;
+        let mut report = BufWriter::new(file);
+        report.write_all(b"action\tidentifier\tduplicate_of\tduplicate_of_identifier\tmatched_on\n").map_err(|e| e.to_string())?;
+
+        return Ok(Self {
+            mode,
+            key,
+            smiles: HashMap::new(),
+            report,
+            num_duplicates: 0,
+        });
+    }
+
+    ///The earlier record `record` repeats, if any
+    pub fn find(&self, index: &mut Index, record: &CompoundRecord) -> Result<Option<Duplicate>, String> {
+
+        if matches!(self.key, DedupKey::Descriptor | DedupKey::Either) {
+            if let Some(x) = index.find_descriptor(&record.descriptor)? {
+                return Ok(Some(Duplicate { index: x, matched_on: "descriptor" }));
+            }
+        }
+
+        if matches!(self.key, DedupKey::Smiles | DedupKey::Either) {
+            let candidates = match self.smiles.get(&hash_smiles(&record.smiles)) {
+                Some(x) => x,
+                None => return Ok(None),
+            };
+
+            for candidate in candidates.iter() {
+                let earlier = index.database().query(candidate);
+                if earlier.map(|x| x.smiles == record.smiles).unwrap_or(false) {
+                    return Ok(Some(Duplicate { index: *candidate, matched_on: "smiles" }));
+                }
+            }
+        }
+
+        return Ok(None);
+    }
+
+    ///Remembers a record that went into the index at `compound_index`
+    pub fn added(&mut self, compound_index: CompoundIndex, record: &CompoundRecord) {
+
+        if matches!(self.key, DedupKey::Smiles | DedupKey::Either) {
+            self.smiles.entry(hash_smiles(&record.smiles)).or_default().push(compound_index);
+        }
+    }
+
+    ///Writes `record`'s line in the report, and adds its alias when merging
+    pub fn report(&mut self, index: &mut Index, record: &CompoundRecord, duplicate: &Duplicate) -> Result<(), String> {
+
+        self.num_duplicates += 1;
+
+        let action = match self.mode {
+            DedupMode::Skip => "skipped",
+            DedupMode::Merge => "merged",
+            DedupMode::Flag => "flagged",
+        };
+
+        let identifier = record.compound_identifier.to_string();
+        let earlier_identifier = match index.database().query(&duplicate.index) {
+            Some(x) => x.identifier.to_string(),
+            None => "".to_string(),
+        };
+
+        let line = format!("{}\t{}\t{}\t{}\t{}\n", action, identifier, duplicate.index, earlier_identifier, duplicate.matched_on);
+        self.report.write_all(line.as_bytes()).map_err(|e| e.to_string())?;
+
+        if self.mode == DedupMode::Merge {
+            index.database().add_alias(&duplicate.index, &record.compound_identifier, record.dataset)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), String> {
+
+        self.report.flush().map_err(|e| e.to_string())?;
+
+        Ok(())
+    }
+}
+
+fn hash_smiles(smiles: &str) -> u64 {
+
+    let mut hasher = DefaultHasher::new();
+    smiles.hash(&mut hasher);
+
+    return hasher.finish();
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use kd_tree::data::{CompoundIdentifier, Descriptor};
+    use kd_tree::database::ImmutDatabase;
+    use kd_tree::tree::{Tree, TreeConfig};
+
+    fn test_config(name: &str) -> TreeConfig {
+
+        let mut config = TreeConfig::default();
+        config.directory = format!("/tmp/builder_dedup_{}", name);
+
+        return config;
+    }
+
+    fn record(smiles: &str, identifier: &str, descriptor: &Descriptor) -> CompoundRecord {
+
+        let mut record = CompoundRecord::random(descriptor.length);
+        record.smiles = smiles.to_string();
+        record.compound_identifier = CompoundIdentifier::from_str(identifier);
+        record.descriptor = descriptor.clone();
+
+        return record;
+    }
+
+    ///An original, one repeating its descriptor, one repeating its SMILES, and an unrelated one
+    fn test_records() -> Vec<CompoundRecord> {
+
+        let first = Descriptor::random(8);
+
+        return vec![
+            record("CCO", "first", &first),
+            record("CCN", "same_descriptor", &first),
+            record("CCO", "same_smiles", &Descriptor::random(8)),
+            record("c1ccccc1", "unrelated", &Descriptor::random(8)),
+        ];
+    }
+
+    ///Runs `records` through the same steps as `build_from_files`, returning what each matched
+    fn add_all(config: &TreeConfig, mode: DedupMode, key: DedupKey, records: &[CompoundRecord]) -> Vec<Option<(CompoundIndex, &'static str)>> {
+
+        let mut index = Index::KdTree(Tree::force_create_with_config(config.clone()));
+        let mut dedup = Deduplicator::create(&config.directory, mode, key).unwrap();
+
+        let mut matches = Vec::new();
+
+        for record in records.iter() {
+
+            let duplicate = dedup.find(&mut index, record).unwrap();
+            matches.push(duplicate.as_ref().map(|x| (x.index, x.matched_on)));
+
+            if let Some(duplicate) = &duplicate {
+                dedup.report(&mut index, record, duplicate).unwrap();
+
+                if mode != DedupMode::Flag {
+                    continue
+                }
+            }
+
+            let compound_index = index.database().len();
+            index.add_record(record, None, &[]).unwrap();
+            dedup.added(compound_index, record);
+        }
+
+        dedup.finish().unwrap();
+        index.finish().unwrap();
+
+        return matches;
+    }
+
+    fn report_actions(config: &TreeConfig) -> Vec<String> {
+
+        let report = std::fs::read_to_string(format!("{}/dedup_report.txt", config.directory)).unwrap();
+
+        return report.lines().skip(1).map(|x| x.split("\t").next().unwrap().to_string()).collect();
+    }
+
+    #[test]
+    fn quick_dedup_keys() {
+
+        let records = test_records();
+        let config = test_config("keys");
+
+        let matches = add_all(&config, DedupMode::Skip, DedupKey::Descriptor, &records);
+        assert_eq!(matches, vec![None, Some((0, "descriptor")), None, None]);
+
+        let matches = add_all(&config, DedupMode::Skip, DedupKey::Smiles, &records);
+        assert_eq!(matches, vec![None, None, Some((0, "smiles")), None]);
+
+        let matches = add_all(&config, DedupMode::Skip, DedupKey::Either, &records);
+        assert_eq!(matches, vec![None, Some((0, "descriptor")), Some((0, "smiles")), None]);
+    }
+
+    #[test]
+    fn quick_dedup_modes() {
+
+        let records = test_records();
+
+        //skipped duplicates are only in the report
+        let config = test_config("skip");
+        add_all(&config, DedupMode::Skip, DedupKey::Either, &records);
+
+        let database = ImmutDatabase::open(&config.get_database_filename());
+        assert_eq!(database.len(), 2);
+        assert!(database.query(&0).unwrap().aliases.is_empty());
+        assert_eq!(database.query(&1).unwrap().identifier.to_string(), "unrelated");
+        assert_eq!(report_actions(&config), vec!["skipped", "skipped"]);
+
+        //merged duplicates become aliases of the first record
+        let config = test_config("merge");
+        add_all(&config, DedupMode::Merge, DedupKey::Either, &records);
+
+        let database = ImmutDatabase::open(&config.get_database_filename());
+        assert_eq!(database.len(), 2);
+        let aliases: Vec<String> = database.query(&0).unwrap().aliases.iter().map(|x| x.to_string()).collect();
+        assert_eq!(aliases, vec!["same_descriptor", "same_smiles"]);
+        assert_eq!(report_actions(&config), vec!["merged", "merged"]);
+
+        //flagged duplicates are added anyway
+        let config = test_config("flag");
+        add_all(&config, DedupMode::Flag, DedupKey::Either, &records);
+
+        let database = ImmutDatabase::open(&config.get_database_filename());
+        assert_eq!(database.len(), 4);
+        assert!(database.query(&0).unwrap().aliases.is_empty());
+        assert_eq!(report_actions(&config), vec!["flagged", "flagged"]);
+    }
+
+    #[test]
+    fn quick_dedup_smiles_hash_collision() {
+
+        let config = test_config("collision");
+        let records = test_records();
+
+        let mut index = Index::KdTree(Tree::force_create_with_config(config.clone()));
+        let mut dedup = Deduplicator::create(&config.directory, DedupMode::Skip, DedupKey::Smiles).unwrap();
+
+        index.add_record(&records[0], None, &[]).unwrap();
+        dedup.added(0, &records[0]);
+
+        //pretend another SMILES hashes the same as the first record's
+        dedup.smiles.entry(hash_smiles("CCN")).or_default().push(0);
+
+        //the database says they differ, so it isn't a duplicate
+        assert!(dedup.find(&mut index, &records[1]).unwrap().is_none());
+
+        //while the real repeat is still caught
+        let duplicate = dedup.find(&mut index, &records[2]).unwrap().unwrap();
+        assert_eq!((duplicate.index, duplicate.matched_on), (0, "smiles"));
+    }
+}
@@ -0,0 +1,1002 @@
+//! Declarative description of the files fed to `build-from-files`
+//!
+//! An `InputSpec` says how to pull a SMILES string, an identifier and a descriptor out of each row
+//! of a delimited text file. Descriptors can either live in the text file itself or in a sidecar
+//! `.npy` / `.parquet` file next to it, matched up by row number.
+//!
+//! Text inputs and `.npy` sidecars ending in `.gz` or `.zst` are decompressed on the fly, and a
+//! filename of `-` reads from stdin.
+
+use serde::{Serialize, Deserialize};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use parquet::file::reader::SerializedFileReader;
+use parquet::record::{Field, Row};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum ColumnRef {
+    Index(usize),
+    Name(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SidecarFormat {
+    Npy,
+    Parquet,
+}
+
+///Descriptors stored outside of the text file, one row per data line
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DescriptorSidecar {
+    pub format: SidecarFormat,
+
+    ///Extension swapped in for the input file's own to find the sidecar, e.g. `npy`
+    pub extension: String,
+
+    ///Parquet only: columns holding the descriptor, concatenated in the order given. Either a
+    ///single list column or several numeric columns. If not set, every column of the row is used
+    ///in file order.
+    #[serde(default)]
+    pub columns: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InputSpec {
+    #[serde(default = "default_delimiter")]
+    pub delimiter: char,
+
+    ///Whether the first line of each file is a header. Column names can only be used if it is.
+    #[serde(default = "default_true")]
+    pub header: bool,
+
+    #[serde(default = "default_smiles_column")]
+    pub smiles_column: ColumnRef,
+
+    #[serde(default = "default_id_column")]
+    pub id_column: ColumnRef,
+
+    ///If empty, every column other than the SMILES, ID, fingerprint, dataset and attribute columns
+    ///is read as a descriptor value
+    #[serde(default)]
+    pub descriptor_columns: Vec<ColumnRef>,
+
+    ///Column holding an FPS hex fingerprint, for trees that keep fingerprints for re-ranking
+    #[serde(default)]
+    pub fingerprint_column: Option<ColumnRef>,
+
+    ///Column naming the source dataset (vendor, catalog) of each row. Overrides `--dataset`
+    #[serde(default)]
+    pub dataset_column: Option<ColumnRef>,
+
+    ///Columns holding the tree config's `attributes`, in the same order. An empty field is a
+    ///missing value.
+    #[serde(default)]
+    pub attribute_columns: Vec<ColumnRef>,
+
+    ///Remove CXSMILES `|...|` blocks before splitting the line
+    #[serde(default = "default_true")]
+    pub strip_cxsmiles: bool,
+
+    ///Prefix identifiers with the part of the filename before the first underscore
+    #[serde(default = "default_true")]
+    pub stem_prefix: bool,
+
+    #[serde(default)]
+    pub descriptor_sidecar: Option<DescriptorSidecar>,
+}
+
+fn default_delimiter() -> char { ',' }
+fn default_true() -> bool { true }
+fn default_smiles_column() -> ColumnRef { ColumnRef::Index(0) }
+fn default_id_column() -> ColumnRef { ColumnRef::Index(1) }
+
+impl InputSpec {
+
+    ///Matches the `smiles,id,desc...` CSV layout the builder has always expected
+    pub fn default() -> Self {
+        return Self {
+            delimiter: default_delimiter(),
+            header: true,
+            smiles_column: default_smiles_column(),
+            id_column: default_id_column(),
+            descriptor_columns: Vec::new(),
+            fingerprint_column: None,
+            dataset_column: None,
+            attribute_columns: Vec::new(),
+            strip_cxsmiles: true,
+            stem_prefix: true,
+            descriptor_sidecar: None,
+        }
+    }
+
+    pub fn from_file(filename: &str) -> Self {
+
+        let serialized = std::fs::read_to_string(filename).expect("InputSpec file can't be found or read");
+
+        let deserialized: Self = serde_yaml::from_str(&serialized).unwrap();
+
+        return deserialized;
+    }
+
+    ///Opens `filename` and returns an iterator over its parsed records
+    pub fn open(&self, filename: &str) -> Result<RecordReader, String> {
+
+        let mut lines = open_input(filename).map_err(|e| format!("Could not read file {:?}: {}", filename, e))?.lines();
+
+        let header: Option<Vec<String>> = match self.header {
+            true => match lines.next() {
+                Some(Ok(line)) => Some(line.split(self.delimiter).map(|x| x.trim().to_string()).collect()),
+                Some(Err(e)) => return Err(format!("Could not read header of {:?}: {}", filename, e)),
+                None => Some(Vec::new()),
+            },
+            false => None,
+        };
+
+        let smiles_column = resolve_column(&self.smiles_column, &header)?;
+        let id_column = resolve_column(&self.id_column, &header)?;
+
+        let mut descriptor_columns: Vec<usize> = Vec::new();
+        for column in self.descriptor_columns.iter() {
+            descriptor_columns.push(resolve_column(column, &header)?);
+        }
+
+        let fingerprint_column = match &self.fingerprint_column {
+            Some(column) => Some(resolve_column(column, &header)?),
+            None => None,
+        };
+
+        let dataset_column = match &self.dataset_column {
+            Some(column) => Some(resolve_column(column, &header)?),
+            None => None,
+        };
+
+        let mut attribute_columns: Vec<usize> = Vec::new();
+        for column in self.attribute_columns.iter() {
+            attribute_columns.push(resolve_column(column, &header)?);
+        }
+
+        let sidecar: Option<Box<dyn Iterator<Item = Result<Vec<f32>, String>>>> = match &self.descriptor_sidecar {
+            None => None,
+            Some(_) if filename == STDIN_FILENAME => {
+                return Err("Descriptor sidecars can't be used when reading from stdin".to_string());
+            },
+            Some(sidecar) => {
+                let sidecar_filename = strip_compression_extension(filename).with_extension(&sidecar.extension);
+                let sidecar_filename = sidecar_filename.to_str().unwrap().to_string();
+
+                match sidecar.format {
+                    SidecarFormat::Npy => Some(Box::new(NpyReader::open(&sidecar_filename)?)),
+                    SidecarFormat::Parquet => Some(Box::new(ParquetReader::open(&sidecar_filename, &sidecar.columns)?)),
+                }
+            }
+        };
+
+        let prefix = match self.stem_prefix && filename != STDIN_FILENAME {
+            true => filename.split("/").last().unwrap().split("_").next().unwrap().to_string(),
+            false => "".to_string(),
+        };
+
+        return Ok(RecordReader {
+            lines,
+            spec: self.clone(),
+            smiles_column,
+            id_column,
+            descriptor_columns,
+            fingerprint_column,
+            dataset_column,
+            attribute_columns,
+            sidecar,
+            prefix,
+        });
+    }
+}
+
+pub const STDIN_FILENAME: &str = "-";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+
+    pub fn from_filename(filename: &str) -> Self {
+
+        match Path::new(filename).extension().and_then(|x| x.to_str()) {
+            Some("gz") => Compression::Gzip,
+            Some("zst") | Some("zstd") => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+}
+
+///Opens a file for buffered reading, decompressing by extension. `-` is stdin.
+pub fn open_input(filename: &str) -> io::Result<Box<dyn BufRead>> {
+
+    let reader: Box<dyn Read> = match filename {
+        STDIN_FILENAME => Box::new(io::stdin().lock()),
+        _ => Box::new(File::open(filename)?),
+    };
+
+    return decompress(reader, Compression::from_filename(filename));
+}
+
+fn decompress(reader: Box<dyn Read>, compression: Compression) -> io::Result<Box<dyn BufRead>> {
+
+    let reader: Box<dyn BufRead> = match compression {
+        Compression::None => Box::new(BufReader::new(reader)),
+        Compression::Gzip => Box::new(BufReader::new(flate2::read::MultiGzDecoder::new(reader))),
+        Compression::Zstd => Box::new(BufReader::new(zstd::stream::read::Decoder::new(reader)?)),
+    };
+
+    return Ok(reader);
+}
+
+///`shard_1.csv.gz` -> `shard_1.csv`, so sidecars are looked up next to the uncompressed name
+fn strip_compression_extension(filename: &str) -> PathBuf {
+
+    match Compression::from_filename(filename) {
+        Compression::None => PathBuf::from(filename),
+        _ => Path::new(filename).with_extension(""),
+    }
+}
+
+fn resolve_column(column: &ColumnRef, header: &Option<Vec<String>>) -> Result<usize, String> {
+
+    match column {
+        ColumnRef::Index(x) => Ok(*x),
+        ColumnRef::Name(name) => match header {
+            None => Err(format!("Column {:?} referenced by name but input has no header", name)),
+            Some(names) => match names.iter().position(|x| x == name) {
+                Some(x) => Ok(x),
+                None => Err(format!("Column {:?} not found in header", name)),
+            },
+        },
+    }
+}
+
+///Removes every `|...|` block from the line
+pub fn strip_cxsmiles(line: &str) -> String {
+
+    let mut keep_string: Vec<char> = Vec::new();
+    let mut keep = true;
+    for char in line.chars() {
+        if char == '|' { keep = !keep; continue }
+        if keep {
+            keep_string.push(char);
+        }
+    }
+
+    return keep_string.into_iter().collect();
+}
+
+pub fn parse_descriptor_vec(v: Vec<&str>) -> Result<Vec<f32>, std::num::ParseFloatError> {
+
+    let descriptor_values: Result<Vec<f32>,_> = v.iter()
+        .map(|x| x
+                  .replace("[","")
+                  .replace("]","")
+                  .replace(" ","")
+                  .parse::<f32>()
+            )
+        .collect();
+    return descriptor_values;
+}
+
+#[derive(Debug, Clone)]
+pub struct InputRecord {
+    pub smiles: String,
+    pub identifier: String,
+    pub descriptor: Vec<f32>,
+    ///Unparsed, since its length depends on the tree config
+    pub fingerprint: Option<String>,
+    ///Source dataset name, if the spec has a dataset column
+    pub dataset: Option<String>,
+    ///Unparsed values of the attribute columns, in order
+    pub attributes: Vec<String>,
+}
+
+///Yields one `InputRecord` per data line. Errors carry the offending line so they can be logged.
+pub struct RecordReader {
+    lines: io::Lines<Box<dyn BufRead>>,
+    spec: InputSpec,
+    smiles_column: usize,
+    id_column: usize,
+    descriptor_columns: Vec<usize>,
+    fingerprint_column: Option<usize>,
+    dataset_column: Option<usize>,
+    attribute_columns: Vec<usize>,
+    sidecar: Option<Box<dyn Iterator<Item = Result<Vec<f32>, String>>>>,
+    prefix: String,
+}
+
+impl RecordReader {
+
+    fn parse_line(&self, line: &str, sidecar_descriptor: Option<Result<Vec<f32>, String>>) -> Result<InputRecord, String> {
+
+        let line = match self.spec.strip_cxsmiles && line.contains("|") {
+            true => strip_cxsmiles(line),
+            false => line.to_string(),
+        };
+
+        let fields: Vec<&str> = line.split(self.spec.delimiter).collect();
+
+        let smiles = match fields.get(self.smiles_column) {
+            Some(x) => x.trim(),
+            None => return Err(format!("No SMILES column:\n\t{}\n", &line)),
+        };
+
+        let id_val = match fields.get(self.id_column) {
+            Some(x) => x.trim(),
+            None => return Err(format!("No ID column:\n\t{}\n", &line)),
+        };
+
+        let descriptor = match sidecar_descriptor {
+            Some(Ok(x)) => x,
+            Some(Err(e)) => return Err(format!("Error reading descriptor sidecar:\n\t{}\n\t{}\n", &line, &e)),
+            None => {
+                let descriptor_fields: Vec<&str> = match self.descriptor_columns.len() {
+                    0 => fields.iter()
+                        .enumerate()
+                        .filter(|(i, _)| *i != self.smiles_column && *i != self.id_column && Some(*i) != self.fingerprint_column && Some(*i) != self.dataset_column && !self.attribute_columns.contains(i))
+                        .map(|(_, x)| *x)
+                        .collect(),
+                    _ => {
+                        let mut v: Vec<&str> = Vec::with_capacity(self.descriptor_columns.len());
+                        for column in self.descriptor_columns.iter() {
+                            match fields.get(*column) {
+                                Some(x) => v.push(x),
+                                None => return Err(format!("No descriptor column {}:\n\t{}\n", column, &line)),
+                            }
+                        }
+                        v
+                    },
+                };
+
+                match parse_descriptor_vec(descriptor_fields) {
+                    Ok(a) => a,
+                    Err(s) => return Err(format!("Error parsing descriptor vec:\n\t{}\n\t{}\n", &line, &s)),
+                }
+            },
+        };
+
+        let fingerprint = match self.fingerprint_column {
+            Some(column) => match fields.get(column) {
+                Some(x) => Some(x.trim().to_string()),
+                None => return Err(format!("No fingerprint column:\n\t{}\n", &line)),
+            },
+            None => None,
+        };
+
+        let dataset = match self.dataset_column {
+            Some(column) => match fields.get(column) {
+                Some(x) => Some(x.trim().to_string()),
+                None => return Err(format!("No dataset column:\n\t{}\n", &line)),
+            },
+            None => None,
+        };
+
+        let mut attributes: Vec<String> = Vec::with_capacity(self.attribute_columns.len());
+        for column in self.attribute_columns.iter() {
+            match fields.get(*column) {
+                Some(x) => attributes.push(x.trim().to_string()),
+                None => return Err(format!("No attribute column {}:\n\t{}\n", column, &line)),
+            }
+        }
+
+        return Ok(InputRecord {
+            smiles: smiles.to_string(),
+            identifier: format!("{}{}", self.prefix, id_val),
+            descriptor,
+            fingerprint,
+            dataset,
+            attributes,
+        });
+    }
+}
+
+impl Iterator for RecordReader {
+    type Item = Result<InputRecord, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+
+        let line = match self.lines.next() {
+            None => {
+                //leftover sidecar rows mean the two files don't line up, so report them once
+                return match self.sidecar.take().and_then(|mut x| x.next()) {
+                    Some(_) => Some(Err("Descriptor sidecar has more rows than input".to_string())),
+                    None => None,
+                }
+            },
+            Some(Ok(line)) => line,
+            Some(Err(e)) => return Some(Err(format!("Error reading line: {}\n", e))),
+        };
+
+        //always advance the sidecar so rows stay aligned even if this line is bad
+        let sidecar_descriptor = match &mut self.sidecar {
+            None => None,
+            Some(sidecar) => match sidecar.next() {
+                Some(x) => Some(x),
+                None => Some(Err("Descriptor sidecar has fewer rows than input".to_string())),
+            },
+        };
+
+        return Some(self.parse_line(&line, sidecar_descriptor));
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NpyDtype {
+    F4,
+    F8,
+}
+
+///Streams rows of a 2D little- or big-endian float `.npy` array
+pub struct NpyReader {
+    reader: Box<dyn BufRead>,
+    dtype: NpyDtype,
+    big_endian: bool,
+    num_rows: usize,
+    num_cols: usize,
+    next_row: usize,
+}
+
+impl NpyReader {
+
+    pub fn open(filename: &str) -> Result<Self, String> {
+
+        let mut reader = open_input(filename).map_err(|e| format!("Could not read npy file {:?}: {}", filename, e))?;
+
+        let mut preamble = [0u8; 8];
+        reader.read_exact(&mut preamble).map_err(|e| e.to_string())?;
+
+        if &preamble[0..6] != b"\x93NUMPY" {
+            return Err(format!("{:?} is not an npy file", filename));
+        }
+
+        let header_length = match preamble[6] {
+            1 => {
+                let mut buf = [0u8; 2];
+                reader.read_exact(&mut buf).map_err(|e| e.to_string())?;
+                u16::from_le_bytes(buf) as usize
+            },
+            _ => {
+                let mut buf = [0u8; 4];
+                reader.read_exact(&mut buf).map_err(|e| e.to_string())?;
+                u32::from_le_bytes(buf) as usize
+            },
+        };
+
+        let mut header = vec![0u8; header_length];
+        reader.read_exact(&mut header).map_err(|e| e.to_string())?;
+        let header = String::from_utf8_lossy(&header).to_string();
+
+        let descr = npy_header_value(&header, "descr").ok_or("npy header has no descr")?;
+        let descr = descr.trim_matches(|c| c == '\'' || c == '"');
+
+        let (big_endian, dtype) = match descr {
+            "<f4" | "|f4" | "=f4" => (false, NpyDtype::F4),
+            ">f4" => (true, NpyDtype::F4),
+            "<f8" | "|f8" | "=f8" => (false, NpyDtype::F8),
+            ">f8" => (true, NpyDtype::F8),
+            _ => return Err(format!("Unsupported npy dtype: {}", descr)),
+        };
+
+        let fortran_order = npy_header_value(&header, "fortran_order").ok_or("npy header has no fortran_order")?;
+        if fortran_order == "True" {
+            return Err("Fortran-ordered npy arrays are not supported".to_string());
+        }
+
+        let shape = npy_header_value(&header, "shape").ok_or("npy header has no shape")?;
+        let shape: Vec<usize> = shape.trim_matches(|c| c == '(' || c == ')')
+            .split(",")
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+            .map(|x| x.parse::<usize>())
+            .collect::<Result<Vec<usize>, _>>()
+            .map_err(|e| e.to_string())?;
+
+        if shape.len() != 2 {
+            return Err(format!("Expected a 2D npy array, got shape {:?}", shape));
+        }
+
+        return Ok(Self {
+            reader,
+            dtype,
+            big_endian,
+            num_rows: shape[0],
+            num_cols: shape[1],
+            next_row: 0,
+        });
+    }
+}
+
+///Pulls the raw value for `key` out of the python dict literal in an npy header
+fn npy_header_value(header: &str, key: &str) -> Option<String> {
+
+    let key_start = header.find(&format!("'{}'", key))?;
+    let rest = &header[key_start + key.len() + 2..];
+    let rest = rest[rest.find(":")? + 1..].trim_start();
+
+    let end = match rest.starts_with("(") {
+        true => rest.find(")")? + 1,
+        false => rest.find(",").unwrap_or(rest.len()),
+    };
+
+    return Some(rest[..end].trim().to_string());
+}
+
+impl Iterator for NpyReader {
+    type Item = Result<Vec<f32>, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+
+        if self.next_row >= self.num_rows {
+            return None;
+        }
+        self.next_row += 1;
+
+        let width = match self.dtype {
+            NpyDtype::F4 => 4,
+            NpyDtype::F8 => 8,
+        };
+
+        let mut buf = vec![0u8; self.num_cols * width];
+        match self.reader.read_exact(&mut buf) {
+            Ok(_) => {},
+            Err(e) => return Some(Err(e.to_string())),
+        }
+
+        let values: Vec<f32> = buf.chunks_exact(width).map(|x| {
+            match (self.dtype, self.big_endian) {
+                (NpyDtype::F4, false) => f32::from_le_bytes(x.try_into().unwrap()),
+                (NpyDtype::F4, true) => f32::from_be_bytes(x.try_into().unwrap()),
+                (NpyDtype::F8, false) => f64::from_le_bytes(x.try_into().unwrap()) as f32,
+                (NpyDtype::F8, true) => f64::from_be_bytes(x.try_into().unwrap()) as f32,
+            }
+        }).collect();
+
+        return Some(Ok(values));
+    }
+}
+
+///Streams descriptors out of a parquet file, one row at a time
+pub struct ParquetReader {
+    rows: parquet::record::reader::RowIter<'static>,
+    columns: Vec<String>,
+}
+
+impl ParquetReader {
+
+    pub fn open(filename: &str, columns: &Vec<String>) -> Result<Self, String> {
+
+        let file = File::open(filename).map_err(|e| format!("Could not read parquet file {:?}: {}", filename, e))?;
+        let reader = SerializedFileReader::try_from(file).map_err(|e| e.to_string())?;
+
+        return Ok(Self {
+            rows: reader.into_iter(),
+            columns: columns.clone(),
+        });
+    }
+
+    fn row_to_descriptor(&self, row: &Row) -> Result<Vec<f32>, String> {
+
+        let mut values: Vec<f32> = Vec::new();
+
+        if self.columns.is_empty() {
+            for (_, field) in row.get_column_iter() {
+                push_field_values(field, &mut values)?;
+            }
+
+            return Ok(values);
+        }
+
+        for column in self.columns.iter() {
+            let field = match row.get_column_iter().find(|(name, _)| *name == column) {
+                Some((_, field)) => field,
+                None => return Err(format!("No descriptor column {:?} in parquet file", column)),
+            };
+
+            push_field_values(field, &mut values)?;
+        }
+
+        return Ok(values);
+    }
+}
+
+///Appends a numeric field, or every element of a list field, to `values`
+fn push_field_values(field: &Field, values: &mut Vec<f32>) -> Result<(), String> {
+
+    match field {
+        Field::ListInternal(list) => {
+            for element in list.elements() {
+                values.push(field_to_f32(element)?);
+            }
+        },
+        _ => values.push(field_to_f32(field)?),
+    }
+
+    Ok(())
+}
+
+fn field_to_f32(field: &Field) -> Result<f32, String> {
+
+    match field {
+        Field::Float(x) => Ok(*x),
+        Field::Double(x) => Ok(*x as f32),
+        Field::Float16(x) => Ok(x.to_f32()),
+        Field::Int(x) => Ok(*x as f32),
+        Field::Long(x) => Ok(*x as f32),
+        _ => Err(format!("Non-numeric descriptor value in parquet: {:?}", field)),
+    }
+}
+
+impl Iterator for ParquetReader {
+    type Item = Result<Vec<f32>, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+
+        let row = match self.rows.next()? {
+            Ok(row) => row,
+            Err(e) => return Some(Err(e.to_string())),
+        };
+
+        return Some(self.row_to_descriptor(&row));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::sync::Arc;
+    use parquet::data_type::{FloatType, ByteArrayType, ByteArray};
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::writer::SerializedFileWriter;
+    use parquet::schema::parser::parse_message_type;
+
+    fn fixture_dir(name: &str) -> String {
+
+        let dir = format!("/tmp/builder_input/{}", name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        return dir;
+    }
+
+    fn write_fixture(dir: &str, filename: &str, contents: &str) -> String {
+
+        let path = format!("{}/{}", dir, filename);
+        std::fs::write(&path, contents).unwrap();
+
+        return path;
+    }
+
+    ///Writes a version 1 npy file with the given header fields and raw data
+    fn write_npy(path: &str, descr: &str, fortran_order: bool, shape: &str, data: &[u8]) {
+
+        let fortran_order = match fortran_order {
+            true => "True",
+            false => "False",
+        };
+        let mut header = format!("{{'descr': '{}', 'fortran_order': {}, 'shape': {}, }}", descr, fortran_order, shape);
+        while (10 + header.len() + 1) % 64 != 0 {
+            header.push(' ');
+        }
+        header.push('\n');
+
+        let mut bytes: Vec<u8> = b"\x93NUMPY\x01\x00".to_vec();
+        bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(header.as_bytes());
+        bytes.extend_from_slice(data);
+
+        std::fs::write(path, bytes).unwrap();
+    }
+
+    fn read_all(spec: &InputSpec, filename: &str) -> Vec<Result<InputRecord, String>> {
+
+        return spec.open(filename).unwrap().collect();
+    }
+
+    #[test]
+    fn quick_text_input_columns() {
+
+        let dir = fixture_dir("columns");
+
+        //the default layout: smiles, id, then every other column is a descriptor
+        let path = write_fixture(&dir, "enamine_1.csv", "smiles,id,a,b\nCCO,z1,1.0,2.0\nc1ccccc1,z2,[3.0, 4.0]\n");
+        let records: Vec<InputRecord> = read_all(&InputSpec::default(), &path).into_iter().map(|x| x.unwrap()).collect();
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].smiles, "CCO");
+        assert_eq!(records[0].identifier, "enaminez1");
+        assert_eq!(records[0].descriptor, vec![1.0, 2.0]);
+        assert_eq!(records[1].descriptor, vec![3.0, 4.0]);
+
+        //columns by name, in any order, with the extra columns kept out of the descriptor
+        let path = write_fixture(&dir, "mcule_1.csv", "d1,vendor,id,mw,smiles,fp,d0\n0.5,mcule,m1,300,CC,ff00,0.25\n");
+        let mut spec = InputSpec::default();
+        spec.smiles_column = ColumnRef::Name("smiles".to_string());
+        spec.id_column = ColumnRef::Name("id".to_string());
+        spec.fingerprint_column = Some(ColumnRef::Name("fp".to_string()));
+        spec.dataset_column = Some(ColumnRef::Name("vendor".to_string()));
+        spec.attribute_columns = vec![ColumnRef::Name("mw".to_string())];
+        spec.stem_prefix = false;
+
+        let record = read_all(&spec, &path).remove(0).unwrap();
+        assert_eq!(record.smiles, "CC");
+        assert_eq!(record.identifier, "m1");
+        assert_eq!(record.descriptor, vec![0.5, 0.25]);
+        assert_eq!(record.fingerprint, Some("ff00".to_string()));
+        assert_eq!(record.dataset, Some("mcule".to_string()));
+        assert_eq!(record.attributes, vec!["300".to_string()]);
+
+        //explicit descriptor columns pick and order values
+        spec.descriptor_columns = vec![ColumnRef::Name("d0".to_string()), ColumnRef::Index(0)];
+        let record = read_all(&spec, &path).remove(0).unwrap();
+        assert_eq!(record.descriptor, vec![0.25, 0.5]);
+
+        //no header, a tab delimiter and indices
+        let path = write_fixture(&dir, "plain.tsv", "x1\tCCN\t7\t8\n");
+        let mut spec = InputSpec::default();
+        spec.header = false;
+        spec.delimiter = '\t';
+        spec.smiles_column = ColumnRef::Index(1);
+        spec.id_column = ColumnRef::Index(0);
+        spec.stem_prefix = false;
+
+        let record = read_all(&spec, &path).remove(0).unwrap();
+        assert_eq!(record.smiles, "CCN");
+        assert_eq!(record.identifier, "x1");
+        assert_eq!(record.descriptor, vec![7.0, 8.0]);
+    }
+
+    #[test]
+    fn quick_cxsmiles_stripping() {
+
+        assert_eq!(strip_cxsmiles("C[C@H](N)O |&1:1|,id,1.0"), "C[C@H](N)O ,id,1.0");
+        assert_eq!(strip_cxsmiles("CC |a| |b|,x"), "CC  ,x");
+        assert_eq!(strip_cxsmiles("CCO,x"), "CCO,x");
+
+        let dir = fixture_dir("cxsmiles");
+
+        //the block holds the delimiter, so it has to go before the line is split
+        let path = write_fixture(&dir, "cx_1.csv", "smiles,id,a\nC[C@H](N)O |&1:1,2|,s1,0.5\n");
+        let mut spec = InputSpec::default();
+        spec.stem_prefix = false;
+
+        let record = read_all(&spec, &path).remove(0).unwrap();
+        assert_eq!(record.smiles, "C[C@H](N)O");
+        assert_eq!(record.identifier, "s1");
+        assert_eq!(record.descriptor, vec![0.5]);
+
+        spec.strip_cxsmiles = false;
+        assert!(read_all(&spec, &path).remove(0).is_err());
+    }
+
+    #[test]
+    fn quick_text_input_errors() {
+
+        let dir = fixture_dir("errors");
+
+        let path = write_fixture(&dir, "bad.csv", "smiles,id,a\nCCO,z1,notanumber\nCCO\nCCO,z3,1.5\n");
+        let mut spec = InputSpec::default();
+        spec.stem_prefix = false;
+
+        //bad rows are errors, and don't stop the rows after them
+        let records = read_all(&spec, &path);
+        assert_eq!(records.len(), 3);
+        assert!(records[0].as_ref().unwrap_err().contains("Error parsing descriptor vec"));
+        assert!(records[1].as_ref().unwrap_err().contains("No ID column"));
+        assert_eq!(records[2].as_ref().unwrap().descriptor, vec![1.5]);
+
+        //names need a header that has them
+        let mut by_name = spec.clone();
+        by_name.smiles_column = ColumnRef::Name("nope".to_string());
+        assert!(by_name.open(&path).is_err());
+
+        by_name.smiles_column = ColumnRef::Name("smiles".to_string());
+        by_name.header = false;
+        assert!(by_name.open(&path).is_err());
+
+        let mut missing = spec.clone();
+        missing.attribute_columns = vec![ColumnRef::Index(5)];
+        assert!(read_all(&missing, &path)[2].as_ref().unwrap_err().contains("No attribute column"));
+
+        let mut missing = spec.clone();
+        missing.descriptor_columns = vec![ColumnRef::Index(9)];
+        assert!(read_all(&missing, &path)[2].as_ref().unwrap_err().contains("No descriptor column"));
+
+        assert!(spec.open(&format!("{}/does_not_exist.csv", dir)).is_err());
+    }
+
+    #[test]
+    fn quick_npy_sidecar() {
+
+        let dir = fixture_dir("npy");
+
+        let path = write_fixture(&dir, "shard_1.csv", "smiles,id\nCC,a\nCCC,b\nCCCC,c\n");
+
+        let mut spec = InputSpec::default();
+        spec.stem_prefix = false;
+        spec.descriptor_sidecar = Some(DescriptorSidecar {
+            format: SidecarFormat::Npy,
+            extension: "npy".to_string(),
+            columns: Vec::new(),
+        });
+
+        //little-endian f4 with one row fewer than the text file
+        let data: Vec<u8> = [1.0f32, 2.0, 3.0, 4.0].iter().flat_map(|x| x.to_le_bytes()).collect();
+        write_npy(&format!("{}/shard_1.npy", dir), "<f4", false, "(2, 2)", &data);
+
+        let records = read_all(&spec, &path);
+        assert_eq!(records[0].as_ref().unwrap().descriptor, vec![1.0, 2.0]);
+        assert_eq!(records[1].as_ref().unwrap().descriptor, vec![3.0, 4.0]);
+        assert!(records[2].as_ref().unwrap_err().contains("fewer rows"));
+
+        //and one row more, e.g. from counting a header row
+        let data: Vec<u8> = [1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0].iter().flat_map(|x| x.to_le_bytes()).collect();
+        write_npy(&format!("{}/shard_1.npy", dir), "<f4", false, "(4, 2)", &data);
+
+        let records = read_all(&spec, &path);
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[2].as_ref().unwrap().descriptor, vec![5.0, 6.0]);
+        assert!(records[3].as_ref().unwrap_err().contains("more rows"));
+
+        //big-endian f8
+        let data: Vec<u8> = [0.5f64, 1.5, 2.5].iter().flat_map(|x| x.to_be_bytes()).collect();
+        write_npy(&format!("{}/shard_1.npy", dir), ">f8", false, "(3, 1)", &data);
+
+        let records = read_all(&spec, &path);
+        let descriptors: Vec<Vec<f32>> = records.into_iter().map(|x| x.unwrap().descriptor).collect();
+        assert_eq!(descriptors, vec![vec![0.5], vec![1.5], vec![2.5]]);
+
+        //unsupported layouts are refused when opening
+        for (descr, fortran_order, shape) in [("<i4", false, "(3, 1)"), ("<f4", true, "(3, 1)"), ("<f4", false, "(3,)")] {
+            write_npy(&format!("{}/shard_1.npy", dir), descr, fortran_order, shape, &[0u8; 12]);
+            assert!(spec.open(&path).is_err());
+        }
+
+        std::fs::write(format!("{}/shard_1.npy", dir), b"not an npy file").unwrap();
+        assert!(spec.open(&path).is_err());
+
+        std::fs::remove_file(format!("{}/shard_1.npy", dir)).unwrap();
+        assert!(spec.open(&path).is_err());
+
+        //stdin has no name to find a sidecar by
+        spec.header = false;
+        assert!(spec.open(STDIN_FILENAME).is_err());
+    }
+
+    #[test]
+    fn quick_parquet_sidecar() {
+
+        let dir = fixture_dir("parquet");
+
+        let path = write_fixture(&dir, "shard_1.csv", "smiles,id\nCC,a\nCCC,b\n");
+
+        //a list column next to a string column
+        let schema = "message schema {
+            REQUIRED BYTE_ARRAY name (UTF8);
+            REQUIRED GROUP descriptor (LIST) {
+                REPEATED GROUP list {
+                    REQUIRED FLOAT element;
+                }
+            }
+            REQUIRED FLOAT extra;
+        }";
+        let schema = Arc::new(parse_message_type(schema).unwrap());
+        let file = File::create(format!("{}/shard_1.parquet", dir)).unwrap();
+        let mut writer = SerializedFileWriter::new(file, schema, Arc::new(WriterProperties::builder().build())).unwrap();
+        let mut row_group = writer.next_row_group().unwrap();
+
+        let mut column = row_group.next_column().unwrap().unwrap();
+        column.typed::<ByteArrayType>().write_batch(&[ByteArray::from("a"), ByteArray::from("b")], None, None).unwrap();
+        column.close().unwrap();
+
+        let mut column = row_group.next_column().unwrap().unwrap();
+        column.typed::<FloatType>().write_batch(&[1.0, 2.0, 3.0, 4.0], Some(&[1, 1, 1, 1]), Some(&[0, 1, 0, 1])).unwrap();
+        column.close().unwrap();
+
+        let mut column = row_group.next_column().unwrap().unwrap();
+        column.typed::<FloatType>().write_batch(&[9.0, 8.0], None, None).unwrap();
+        column.close().unwrap();
+
+        row_group.close().unwrap();
+        writer.close().unwrap();
+
+        let mut spec = InputSpec::default();
+        spec.stem_prefix = false;
+        spec.descriptor_sidecar = Some(DescriptorSidecar {
+            format: SidecarFormat::Parquet,
+            extension: "parquet".to_string(),
+            columns: vec!["descriptor".to_string()],
+        });
+
+        let descriptors: Vec<Vec<f32>> = read_all(&spec, &path).into_iter().map(|x| x.unwrap().descriptor).collect();
+        assert_eq!(descriptors, vec![vec![1.0, 2.0], vec![3.0, 4.0]]);
+
+        //several numeric columns, in the order the spec lists them
+        spec.descriptor_sidecar.as_mut().unwrap().columns = vec!["extra".to_string(), "descriptor".to_string()];
+        let descriptors: Vec<Vec<f32>> = read_all(&spec, &path).into_iter().map(|x| x.unwrap().descriptor).collect();
+        assert_eq!(descriptors, vec![vec![9.0, 1.0, 2.0], vec![8.0, 3.0, 4.0]]);
+
+        //a misspelled column is an error, not skipped
+        spec.descriptor_sidecar.as_mut().unwrap().columns = vec!["descriptor".to_string(), "extar".to_string()];
+        let records = read_all(&spec, &path);
+        assert!(records.iter().all(|x| x.as_ref().unwrap_err().contains("No descriptor column \"extar\"")));
+
+        //every column, including the string one, is an error per row
+        spec.descriptor_sidecar.as_mut().unwrap().columns = Vec::new();
+        let records = read_all(&spec, &path);
+        assert!(records.iter().all(|x| x.as_ref().unwrap_err().contains("Non-numeric")));
+
+        std::fs::write(format!("{}/shard_1.parquet", dir), b"not parquet").unwrap();
+        assert!(spec.open(&path).is_err());
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, data).unwrap();
+
+        return encoder.finish().unwrap();
+    }
+
+    #[test]
+    fn quick_compressed_inputs() {
+
+        assert_eq!(Compression::from_filename("a.csv.gz"), Compression::Gzip);
+        assert_eq!(Compression::from_filename("a.csv.zst"), Compression::Zstd);
+        assert_eq!(Compression::from_filename("a.csv.zstd"), Compression::Zstd);
+        assert_eq!(Compression::from_filename("a.csv"), Compression::None);
+        assert_eq!(Compression::from_filename(STDIN_FILENAME), Compression::None);
+
+        let dir = fixture_dir("compressed");
+        let text = "smiles,id,a\nCC,a,1.0\nCCC,b,2.0\n";
+
+        //bgzip and pigz write several gzip members, which all have to be read
+        let mut gz = gzip(&text.as_bytes()[..12]);
+        gz.extend(gzip(&text.as_bytes()[12..]));
+        std::fs::write(format!("{}/shard_1.csv.gz", dir), &gz).unwrap();
+
+        std::fs::write(format!("{}/shard_2.csv.zst", dir), zstd::stream::encode_all(text.as_bytes(), 0).unwrap()).unwrap();
+
+        let mut spec = InputSpec::default();
+        spec.stem_prefix = false;
+
+        for filename in ["shard_1.csv.gz", "shard_2.csv.zst"] {
+            let records = read_all(&spec, &format!("{}/{}", dir, filename));
+            let descriptors: Vec<Vec<f32>> = records.into_iter().map(|x| x.unwrap().descriptor).collect();
+            assert_eq!(descriptors, vec![vec![1.0], vec![2.0]]);
+        }
+
+        //sidecars are found next to the uncompressed name, and may be compressed themselves
+        let data: Vec<u8> = [5.0f32, 6.0].iter().flat_map(|x| x.to_le_bytes()).collect();
+        write_npy(&format!("{}/sidecar.npy", dir), "<f4", false, "(2, 1)", &data);
+        std::fs::write(format!("{}/shard_1.npy.gz", dir), gzip(&std::fs::read(format!("{}/sidecar.npy", dir)).unwrap())).unwrap();
+
+        spec.descriptor_sidecar = Some(DescriptorSidecar {
+            format: SidecarFormat::Npy,
+            extension: "npy.gz".to_string(),
+            columns: Vec::new(),
+        });
+        let descriptors: Vec<Vec<f32>> = read_all(&spec, &format!("{}/shard_1.csv.gz", dir)).into_iter().map(|x| x.unwrap().descriptor).collect();
+        assert_eq!(descriptors, vec![vec![5.0], vec![6.0]]);
+
+        //stdin goes through the same path as an uncompressed file
+        let stream: Box<dyn Read> = Box::new(std::io::Cursor::new(text.as_bytes().to_vec()));
+        let lines: Vec<String> = decompress(stream, Compression::from_filename(STDIN_FILENAME)).unwrap().lines().map(|x| x.unwrap()).collect();
+        assert_eq!(lines, vec!["smiles,id,a", "CC,a,1.0", "CCC,b,2.0"]);
+
+        //corrupt data is an error, not an empty file
+        std::fs::write(format!("{}/shard_3.csv.gz", dir), b"not gzip").unwrap();
+        assert!(spec.open(&format!("{}/shard_3.csv.gz", dir)).is_err());
+    }
+}
@@ -1,27 +1,28 @@
-use kd_tree::data::{CompoundIdentifier, Descriptor, CompoundRecord};
+use kd_tree::data::{CompoundIdentifier, CompoundIndex, Descriptor, CompoundRecord};
 use kd_tree::database::{Database, DatabaseRecord};
 
 use kdam::{tqdm, BarExt};
 use kd_tree::tree;
+use kd_tree::encoding::{DescriptorEncoding, ScalarQuantizer, ProductQuantizer};
+use kd_tree::fingerprint::{Fingerprint, FingerprintIndex};
+use kd_tree::split::{PcaFitter, SplitStrategy};
 use glob::glob;
 use std::io::prelude::*;
-use std::io::{self, BufRead};
-use std::path::Path;
-use std::fs::{File, OpenOptions};
+use std::fs::OpenOptions;
 use std::time::Instant;
 
 use rand::thread_rng;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
 use rand::seq::SliceRandom;
 
 
-
-// The output is wrapped in a Result to allow matching on errors
-// Returns an Iterator to the Reader of the lines of the file.
-fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
-where P: AsRef<Path>, {
-    let file = File::open(filename)?;
-    Ok(io::BufReader::new(file).lines())
-}
+mod dedup;
+mod input;
+mod synthetic;
+use dedup::{DedupKey, DedupMode, Deduplicator};
+use input::{ColumnRef, InputSpec};
+use synthetic::{Distribution, SyntheticGenerator};
 
 use clap::{Args, Parser, Subcommand};
 
@@ -48,11 +49,7 @@ enum Command {
 #[derive(Debug, Args, Clone)]
 struct TestRandomArgs {
 
-    ///Filenames of source data
-    #[arg(long, num_args(1..))]
-    filenames: Vec<String>,
-
-    ///Config filename
+    ///Config filename. `num_records` sets how many compounds are generated
     #[clap(long)]
     config_filename: String,
 
@@ -60,23 +57,99 @@ struct TestRandomArgs {
     #[clap(long, default_value_t = 1.0)]
     cache_size: f32,
 
+    ///Overrides `num_records` from the config
+    #[clap(long)]
+    num_records: Option<usize>,
+
+    ///Overrides `desc_length` from the config
+    #[clap(long)]
+    desc_length: Option<usize>,
+
+    ///Overrides `record_page_length` from the config
+    #[clap(long)]
+    record_page_length: Option<usize>,
+
+    ///How the synthetic descriptors are distributed
+    #[clap(long, value_enum, default_value_t = Distribution::Uniform)]
+    distribution: Distribution,
+
+    ///Number of Gaussian clusters for the clustered distribution
+    #[clap(long, default_value_t = 100)]
+    num_clusters: usize,
+
+    ///Standard deviation of each Gaussian cluster
+    #[clap(long, default_value_t = 0.05)]
+    cluster_std: f32,
+
+    ///Dimension i is scaled by `scale_decay^i`, to mimic descriptors whose dimensions have very
+    ///different variances
+    #[clap(long, default_value_t = 1.0)]
+    scale_decay: f32,
+
+    ///Overrides `split_strategy` from the config, e.g. `max_variance`
+    #[clap(long)]
+    split_strategy: Option<String>,
+
+    ///Number of nearest neighbor queries to run after the build
+    #[clap(long, default_value_t = 1000)]
+    num_queries: usize,
+
+    ///Number of neighbors per query
+    #[clap(long, default_value_t = 10)]
+    k: usize,
+
+    ///Seed for the data and query generators
+    #[clap(long, default_value_t = 0)]
+    seed: u64,
 }
 
 #[derive(Debug, Args, Clone)]
 struct BuildFromFileArgs {
 
-    ///Filenames of source data
+    ///Filenames of source data. `.gz` and `.zst` files are decompressed on the fly, `-` reads stdin
     #[arg(long, num_args(1..))]
     filenames: Vec<String>,
 
+    ///Glob patterns for source data, quoted so the shell doesn't expand them. Matches are sorted
+    #[clap(long)]
+    input_glob: Vec<String>,
+
+    ///Directories whose files are all source data. Files are sorted, hidden files and descriptor
+    ///sidecars are skipped
+    #[clap(long)]
+    input_dir: Vec<String>,
+
+    ///Files listing one source data path per line. Blank lines and `#` comments are ignored
+    #[clap(long)]
+    input_manifest: Vec<String>,
+
     ///Config filename
     #[clap(long)]
     config_filename: String,
 
+    ///YAML file describing the input layout (delimiter, header, columns, descriptor sidecars).
+    ///Defaults to `smiles,id,desc...` CSV with a header line
+    #[clap(long)]
+    input_spec: Option<String>,
+
     ///Cache size for build, in GB
     #[clap(long, default_value_t = 1.0)]
     cache_size: f32,
 
+    ///What to do with records that repeat an earlier record. Duplicates are listed in
+    ///`dedup_report.txt` next to `build_log.txt`
+    #[clap(long, value_enum)]
+    dedup: Option<DedupMode>,
+
+    ///What makes two records duplicates
+    #[clap(long, value_enum, default_value_t = DedupKey::Descriptor)]
+    dedup_key: DedupKey,
+
+    ///Source dataset (vendor, catalog) name for every record, unless the input spec has a
+    ///`dataset_column`. Queries report and can filter by these
+    #[clap(long)]
+    dataset: Option<String>,
+
 }
 
 #[derive(Debug, Args, Clone)]
@@ -133,66 +206,314 @@ fn main() {
 
     dbg!(&args.command);
     match &args.command {
-        Command::TestRandom(_) => {dbg!("TEST RANDOM");},
+        Command::TestRandom(targs) => {test_random(&targs);},
         Command::BuildFromFiles(bargs) => {build_from_files(&bargs);},
     }
 }
 
-/*
-fn build_random(args: GlobalOpts) {
+///Builds a tree of synthetic compounds, then times a nearest neighbor workload against it
+fn test_random(args: &TestRandomArgs) {
 
-    let config_filename = args.config_filename.unwrap();
+    let mut config = tree::TreeConfig::from_file(args.config_filename.clone());
 
-    let cache_limit = args.cache_limit.unwrap_or(50.0);
+    if let Some(x) = args.num_records { config.num_records = Some(x); }
+    if let Some(x) = args.desc_length { config.desc_length = x; }
+    if let Some(x) = args.record_page_length { config.record_page_length = x; }
+    if let Some(x) = &args.split_strategy {
+        config.split_strategy = match serde_yaml::from_str::<SplitStrategy>(x) {
+            Ok(x) => x,
+            Err(_) => panic!("Unknown split strategy {:?}", x),
+        };
+    }
+    config.cache_limit = Some(args.cache_size);
 
-    dbg!(&config_filename);
+    let num_records = match config.num_records {
+        Some(x) => x,
+        None => panic!("num_records must be set in the config or with --num-records"),
+    };
 
-    let mut config = tree::TreeConfig::from_file(config_filename);
+    if config.index_type != tree::IndexType::KdTree {
+        panic!("test-random only builds kd trees");
+    }
+
+    //rotations and quantizers are fit on dry runs of the same seeded generator
+    if needs_rotation(&config) {
+        let mut generator = SyntheticGenerator::new(args.distribution, config.desc_length, args.num_clusters, args.cluster_std, args.scale_decay, args.seed);
+        let descriptors = (0..num_records).map(|_| Ok(generator.descriptor()));
+        if let Err(e) = fit_rotation(&mut config, descriptors) {
+            panic!("{}", e);
+        }
+    }
+
+    if needs_fitting(&config) {
+        let mut generator = SyntheticGenerator::new(args.distribution, config.desc_length, args.num_clusters, args.cluster_std, args.scale_decay, args.seed);
+        let descriptors = (0..num_records).map(|_| Ok(generator.descriptor()));
+        if let Err(e) = fit_encoding(&mut config, descriptors) {
+            panic!("{}", e);
+        }
+    }
 
     dbg!(&config);
 
     let mut tree = tree::Tree::create_with_config(config.clone());
 
-    let mut start = Instant::now();
-    for i in tqdm!(0..config.num_records.unwrap() as usize) {
-        let rec = CompoundRecord::random(config.desc_length);
-        let add_result = tree.add_record(&rec);
-        match add_result {
-            Ok(_) => {},
-            Err(e) => {
-                dbg!(e);
-                panic!("Error adding record");
-            },
+    let mut generator = SyntheticGenerator::new(args.distribution, config.desc_length, args.num_clusters, args.cluster_std, args.scale_decay, args.seed);
+
+    let start = Instant::now();
+    for _ in tqdm!(0..num_records) {
+        let rec = generator.record();
+        tree.add_record(&rec).unwrap();
+    }
+    tree.reorder_nodes().unwrap();
+    tree.flush();
+    let build_seconds = start.elapsed().as_secs_f64();
+
+    let num_nodes = tree.num_nodes();
+    let num_record_pages = tree.record_handler.len();
+    drop(tree);
+
+    let query_tree = tree::ImmutTree::read_from_directory(config.directory.clone());
+
+    //queries come from the same distribution as the data, but not the same points
+    let mut query_generator = SyntheticGenerator::new(args.distribution, config.desc_length, args.num_clusters, args.cluster_std, args.scale_decay, args.seed);
+    for _ in 0..num_records {
+        query_generator.descriptor();
+    }
+
+    let mut latencies: Vec<f64> = Vec::with_capacity(args.num_queries);
+    let mut nodes_visited: Vec<usize> = Vec::with_capacity(args.num_queries);
+    let mut record_pages_visited: Vec<usize> = Vec::with_capacity(args.num_queries);
+
+    for _ in tqdm!(0..args.num_queries) {
+        let descriptor = query_generator.descriptor();
+
+        let query_start = Instant::now();
+        let (_nn, stats) = query_tree.get_nearest_neighbors_with_stats(&descriptor, args.k);
+        latencies.push(query_start.elapsed().as_secs_f64() * 1000.0);
+
+        nodes_visited.push(stats.nodes_visited);
+        record_pages_visited.push(stats.record_pages_visited);
+    }
+
+    latencies.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    let mean = |v: &Vec<usize>| v.iter().sum::<usize>() as f64 / v.len().max(1) as f64;
+
+    println!("num_records: {}", num_records);
+    println!("desc_length: {}", config.desc_length);
+    println!("record_page_length: {}", config.record_page_length);
+    println!("distribution: {:?}", args.distribution);
+    println!("num_nodes: {}", num_nodes);
+    println!("num_record_pages: {}", num_record_pages);
+    println!("build_seconds: {:.3}", build_seconds);
+    println!("build_records_per_second: {:.1}", num_records as f64 / build_seconds);
+    println!("num_queries: {}", args.num_queries);
+    println!("k: {}", args.k);
+    println!("latency_ms_mean: {:.4}", latencies.iter().sum::<f64>() / latencies.len().max(1) as f64);
+    println!("latency_ms_p50: {:.4}", percentile(&latencies, 0.50));
+    println!("latency_ms_p90: {:.4}", percentile(&latencies, 0.90));
+    println!("latency_ms_p99: {:.4}", percentile(&latencies, 0.99));
+    println!("latency_ms_max: {:.4}", percentile(&latencies, 1.0));
+    println!("nodes_visited_mean: {:.2}", mean(&nodes_visited));
+    println!("record_pages_visited_mean: {:.2}", mean(&record_pages_visited));
+    println!("record_pages_visited_max: {}", record_pages_visited.iter().max().unwrap_or(&0));
+}
+
+///Nearest-rank percentile of already sorted values
+fn percentile(sorted: &Vec<f64>, q: f64) -> f64 {
+
+    if sorted.is_empty() {
+        return 0.0;
+    }
+
+    let idx = ((sorted.len() - 1) as f64 * q).round() as usize;
+    return sorted[idx];
+}
+
+///Descriptors sampled to train a product quantizer codebook
+const PQ_TRAINING_SAMPLES: usize = 100000;
+const PQ_TRAINING_ITERATIONS: usize = 20;
+
+///Whether the config's descriptor encoding still needs bounds or a codebook before building
+fn needs_fitting(config: &tree::TreeConfig) -> bool {
+
+    return match config.descriptor_encoding {
+        DescriptorEncoding::Int8 => config.quantizer.is_none(),
+        DescriptorEncoding::Pq => config.product_quantizer.is_none(),
+        _ => false,
+    }
+}
+
+///Whether the config's split strategy still needs its rotation before building
+fn needs_rotation(config: &tree::TreeConfig) -> bool {
+
+    return config.split_strategy == SplitStrategy::PcaRotated && config.rotation.is_none();
+}
+
+///Fits the principal axes for `pca_rotated` splits from a pass over every descriptor
+fn fit_rotation(config: &mut tree::TreeConfig, descriptors: impl Iterator<Item = Result<Descriptor, String>>) -> Result<(), String> {
+
+    let mut fitter = PcaFitter::new(config.desc_length);
+
+    for descriptor in descriptors {
+
+        let descriptor = descriptor?;
+
+        if descriptor.length != config.desc_length {
+            continue;
         }
-    
 
+        fitter.update(&descriptor);
+    }
+
+    config.rotation = Some(fitter.fit()?);
+
+    return Ok(());
+}
+
+///Fills in int8 bounds or a pq codebook from a first pass over every descriptor. Fit after the
+///rotation, since the tree stores rotated descriptors.
+fn fit_encoding(config: &mut tree::TreeConfig, descriptors: impl Iterator<Item = Result<Descriptor, String>>) -> Result<(), String> {
+
+    let mut quantizer = ScalarQuantizer::empty(config.desc_length);
 
-        if i % 100000 == 0 {
-            let elapsed = start.elapsed();
-            println!("\nTIME REPORT: {} {}\n", i, elapsed.as_secs_f64());
-            dbg!(tree.record_handler.get_cache_size_gb());
+    //reservoir sample, so the codebook sees the whole input and not just the first files
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut samples: Vec<Descriptor> = Vec::with_capacity(PQ_TRAINING_SAMPLES);
+
+    for (i, descriptor) in descriptors.enumerate() {
+
+        let descriptor = descriptor?;
+
+        if descriptor.length != config.desc_length {
+            continue;
+        }
+
+        let descriptor = config.to_tree_space(&descriptor);
+
+        quantizer.update(&descriptor);
+
+        match samples.len() < PQ_TRAINING_SAMPLES {
+            true => samples.push(descriptor),
+            false => {
+                let j = rng.gen_range(0..=i);
+                if j < PQ_TRAINING_SAMPLES {
+                    samples[j] = descriptor;
+                }
+            },
         }
     }
 
-    tree.flush();
+    match config.descriptor_encoding {
+        DescriptorEncoding::Int8 => config.quantizer = Some(quantizer),
+        DescriptorEncoding::Pq => {
+            println!("Training pq codebook on {} descriptors", samples.len());
+            config.product_quantizer = Some(ProductQuantizer::train(&samples, config.pq_subspaces, PQ_TRAINING_ITERATIONS, 0)?);
+        },
+        _ => {},
+    }
 
-    println!("Tree construction complete");
+    return Ok(());
+}
+
+///Every well formed descriptor in the inputs, for `fit_encoding`
+fn input_descriptors<'a>(spec: &'a InputSpec, filenames: &'a Vec<String>, desc_length: usize) -> impl Iterator<Item = Result<Descriptor, String>> + 'a {
+
+    return filenames.iter().flat_map(move |filename| {
+
+        let reader: Box<dyn Iterator<Item = Result<Descriptor, String>>> = match filename == input::STDIN_FILENAME {
+            true => Box::new(std::iter::once(Err("int8 and pq encodings need their quantizer in the config when reading from stdin".to_string()))),
+            false => match spec.open(filename) {
+                Ok(reader) => Box::new(reader
+                    .filter_map(|x| x.ok())
+                    //wrong length records are reported when they're added to the tree
+                    .filter(move |x| x.descriptor.len() == desc_length)
+                    .map(move |x| Ok(Descriptor::from_vec(x.descriptor, desc_length)))),
+                Err(e) => Box::new(std::iter::once(Err(e))),
+            },
+        };
+
+        reader
+    });
 }
-*/
 
 fn build_from_files(args: &BuildFromFileArgs) {
 
 
-    let config = tree::TreeConfig::from_file(args.config_filename.clone());
+    let mut config = tree::TreeConfig::from_file(args.config_filename.clone());
 
-    let mut tree = tree::Tree::create_with_config(config.clone());
+    let mut spec = match &args.input_spec {
+        Some(filename) => InputSpec::from_file(filename),
+        None => InputSpec::default(),
+    };
+
+    //attribute columns default to the ones named like the attributes
+    if spec.attribute_columns.is_empty() {
+        spec.attribute_columns = config.attributes.iter().map(|x| ColumnRef::Name(x.name.clone())).collect();
+    }
 
-    match args.filenames.len() {
+    if spec.attribute_columns.len() != config.attributes.len() {
+        panic!("Input spec has {} attribute columns, config has {} attributes", spec.attribute_columns.len(), config.attributes.len());
+    }
+
+    if !config.attributes.is_empty() && config.index_type != tree::IndexType::KdTree {
+        panic!("Only kd trees can keep attributes");
+    }
+
+    if config.fingerprint_bits.is_some() && spec.fingerprint_column.is_none() {
+        panic!("Config keeps {}-bit fingerprints, input spec has no fingerprint_column", config.fingerprint_bits.unwrap());
+    }
+
+    let filenames = match resolve_input_files(args, &spec) {
+        Ok(x) => x,
+        Err(e) => panic!("{}", e),
+    };
+
+    match filenames.len() {
         0 => panic!("No filenames supplied"),
         _ => {},
     }
 
-    let log_file_path = config.directory + "/build_log.txt";
+    if needs_rotation(&config) {
+        println!("Fitting rotation for pca_rotated splits");
+        let desc_length = config.desc_length;
+        if let Err(e) = fit_rotation(&mut config, input_descriptors(&spec, &filenames, desc_length)) {
+            panic!("{}", e);
+        }
+    }
+
+    if needs_fitting(&config) {
+        println!("Fitting {:?} descriptor encoding", config.descriptor_encoding);
+        let desc_length = config.desc_length;
+        if let Err(e) = fit_encoding(&mut config, input_descriptors(&spec, &filenames, desc_length)) {
+            panic!("{}", e);
+        }
+    }
+
+    if args.dedup.is_some() && args.dedup_key != DedupKey::Smiles && config.index_type != tree::IndexType::KdTree {
+        panic!("Only kd trees can look up duplicate descriptors, use --dedup-key smiles");
+    }
+
+    //distinct descriptors can share a lossy encoding, and only full precision vectors tell them apart
+    if args.dedup.is_some() && args.dedup_key != DedupKey::Smiles && config.descriptor_encoding.is_lossy() && !config.reranks() {
+        panic!("{:?} trees can only look up exact duplicate descriptors with keep_full_vectors, use --dedup-key smiles", config.descriptor_encoding);
+    }
+
+    let mut index = Index::create_with_config(config.clone());
+
+    let mut dedup = match args.dedup {
+        Some(mode) => match Deduplicator::create(&config.directory, mode, args.dedup_key) {
+            Ok(x) => Some(x),
+            Err(e) => panic!("{}", e),
+        },
+        None => None,
+    };
+
+    //record exactly what went into the tree
+    let input_files_path = config.directory.clone() + "/input_files.txt";
+    std::fs::write(&input_files_path, filenames.join("\n") + "\n").unwrap();
+
+    let log_file_path = config.directory.clone() + "/build_log.txt";
 
     let mut log_file = OpenOptions::new()
             .create(true)
@@ -204,118 +525,289 @@ fn build_from_files(args: &BuildFromFileArgs) {
     let mut error_counter: usize = 0;
 
     let start = Instant::now();
-    for filename in tqdm!(args.filenames.iter()) {
+    for filename in tqdm!(filenames.iter()) {
 
-        let clean_filename = filename.clone();
-        println!("{:?}", clean_filename);
+        println!("{:?}", filename);
+
+        let reader = spec.open(filename).unwrap();
+
+        for input_record in reader {
+
+            if (success_counter % 1000000 == 0) & (success_counter != 0) {
+                let elapsed = start.elapsed().as_secs_f64();
+                let log_string = format!("Total records added: {:?} in {:?} seconds\n", success_counter, elapsed);
+                log_file.write_all(log_string.as_bytes()).ok();
+            }
 
-        let stem = clean_filename.split("/").last().unwrap().split("_").next().unwrap();
+            let input_record = match input_record {
+                Ok(x) => x,
+                Err(e) => {
+                    log_file.write_all(e.as_bytes()).ok();
+                    error_counter += 1;
+                    continue
+                },
+            };
+
+            if input_record.descriptor.len() != config.desc_length {
+                let error_line = format!("Descriptor length {} does not match config desc_length {}:\n\t{}\n", input_record.descriptor.len(), config.desc_length, &input_record.identifier);
+                log_file.write_all(error_line.as_bytes()).ok();
+                error_counter += 1;
+                continue
+            }
 
-        let lines = read_lines(&clean_filename).expect(&format!("Could not read file: {:?}", &clean_filename));
+            let descriptor = Descriptor{ data: input_record.descriptor, length: config.desc_length};
 
-        let mut counter = -1;
-        for line in lines {
+            let fingerprint = match (config.fingerprint_bits, &input_record.fingerprint) {
+                (Some(bits), Some(hex)) => match Fingerprint::from_hex(hex, bits) {
+                    Ok(x) => Some(x),
+                    Err(e) => {
+                        let error_line = format!("Error parsing fingerprint:\n\t{}\n\t{}\n", &input_record.identifier, &e);
+                        log_file.write_all(error_line.as_bytes()).ok();
+                        error_counter += 1;
+                        continue
+                    },
+                },
+                _ => None,
+            };
 
-            if let Ok(mut good_line) = line {
+            let dataset = match input_record.dataset.as_ref().or(args.dataset.as_ref()) {
+                Some(name) => match index.database().dataset_id(name) {
+                    Ok(x) => Some(x),
+                    Err(e) => {
+                        let error_line = format!("Error assigning dataset:\n\t{}\n\t{}\n", &input_record.identifier, &e);
+                        log_file.write_all(error_line.as_bytes()).ok();
+                        error_counter += 1;
+                        continue
+                    },
+                },
+                None => None,
+            };
+
+            let identifier = CompoundIdentifier::from_string(input_record.identifier.clone());
+
+            let record = CompoundRecord{ 
+                compound_identifier: identifier, 
+                smiles: input_record.smiles,
+                descriptor,
+                length: config.desc_length,
+                dataset,};
+
+            let duplicate = match &dedup {
+                Some(x) => x.find(&mut index, &record),
+                None => Ok(None),
+            };
+
+            let duplicate = match duplicate {
+                Ok(x) => x,
+                Err(e) => {
+                    let error_line = format!("Error checking for duplicates:\n\t{}\n\t{}\n", &input_record.identifier, &e);
+                    log_file.write_all(error_line.as_bytes()).ok();
+                    error_counter += 1;
+                    continue
+                },
+            };
+
+            if let (Some(dedup), Some(duplicate)) = (&mut dedup, &duplicate) {
+                if let Err(e) = dedup.report(&mut index, &record, duplicate) {
+                    panic!("{}", e);
+                }
 
-                //ignore header
-                if counter == -1 {
-                    counter += 1;
-                    continue;
+                if dedup.mode != DedupMode::Flag {
+                    continue
                 }
+            }
 
-                //strip out smiles extension stuff
-                if good_line.contains("|") {
+            let compound_index = index.database().len();
 
-                    let mut keep_string: Vec<char> = Vec::new();
-                    let mut keep = true;
-                    for char in good_line.chars() {
-                        if char == '|' { keep = !keep; continue }
-                        if keep {
-                            
-                            keep_string.push(char.clone());
-                        }
+            match index.add_record(&record, fingerprint.as_ref(), &input_record.attributes) {
+                Ok(_) => {},
+                Err(e) => {
+                    let error_line = format!("Error adding record to tree:\n\t{}\n\t{}\n", &input_record.identifier, &e);
+                    log_file.write_all(error_line.as_bytes()).ok();
+                    error_counter += 1;
+                    continue
+                },
+            }
 
-                    }
+            if let Some(dedup) = &mut dedup {
+                dedup.added(compound_index, &record);
+            }
 
-                    good_line = keep_string.into_iter().collect();
-                }
+            success_counter += 1;
+        }
+    }
 
+    if let Err(e) = index.finish() {
+        panic!("{}", e);
+    }
 
-                if (success_counter % 1000000 == 0) & (success_counter != 0) {
-                    let elapsed = start.elapsed().as_secs_f64();
-                    let log_string = format!("Total records added: {:?} in {:?} seconds\n", success_counter, elapsed);
-                    log_file.write(log_string.as_bytes());
-                }
+    if let Some(dedup) = dedup {
+        let log_string = format!("{} duplicate records, dedup mode {:?}\n", dedup.num_duplicates, dedup.mode);
+        log_file.write_all(log_string.as_bytes()).ok();
+        println!("{} duplicate records, listed in {}/dedup_report.txt", dedup.num_duplicates, config.directory);
 
-                let s = good_line.split(",");
+        if let Err(e) = dedup.finish() {
+            panic!("{}", e);
+        }
+    }
 
-                let fields: Vec<&str> = s.collect();
+    println!("{} records failed to be added to tree, logged in {}", error_counter, log_file_path);
+}
 
-                let mut field_iter = fields.into_iter();
+///What `build-from-files` fills, picked by `index_type` in the config
+enum Index {
+    KdTree(tree::Tree),
+    Fingerprint(FingerprintIndex),
+}
 
-                let smiles = field_iter.next().unwrap();
-                let id_val = field_iter.next().unwrap();
+impl Index {
 
-                let descriptor_fields: Vec<&str> = field_iter.collect();
+    fn create_with_config(config: tree::TreeConfig) -> Self {
 
-                let descriptor_values = match parse_descriptor_vec(descriptor_fields) {
-                    Ok(a) => a,
-                    Err(s) => {
-                        let error_line = format!("Error parsing descriptor vec:\n\t{}\n\t{}\n", &good_line, &s);
-                        log_file.write(error_line.as_bytes());
-                        error_counter += 1;
-                        continue},
-                };
-                
-                let mut descriptor = Descriptor{ data: descriptor_values.clone(), length: config.desc_length};
+        return match config.index_type {
+            tree::IndexType::KdTree => Index::KdTree(tree::Tree::create_with_config(config)),
+            tree::IndexType::Fingerprint => Index::Fingerprint(FingerprintIndex::create_with_config(config)),
+        }
+    }
 
-                descriptor.add_small_noise();
+    fn add_record(&mut self, record: &CompoundRecord, fingerprint: Option<&Fingerprint>, attributes: &[String]) -> Result<(), String> {
 
-                let id_string = format!("{}{}", stem, id_val);
+        if let Index::KdTree(x) = self {
+            if let Some(store) = &mut x.attributes {
+                let values = store.parse_row(attributes)?;
+                return x.add_record_with_attributes(record, fingerprint, &values);
+            }
+        }
 
-                let identifier = CompoundIdentifier::from_string(id_string);
+        return match (self, fingerprint) {
+            (Index::KdTree(x), Some(fingerprint)) => x.add_record_with_fingerprint(record, fingerprint),
+            (Index::KdTree(x), None) => x.add_record(record),
+            (Index::Fingerprint(x), _) => x.add_record(record),
+        }
+    }
 
-                let record = CompoundRecord{ 
-                    compound_identifier: identifier, 
-                    smiles: smiles.to_string(),
-                    descriptor,
-                    length: config.desc_length,};
+    fn database(&mut self) -> &mut Database {
 
-                match tree.add_record(&record) {
-                    Ok(_) => {},
-                    Err(e) => {
-                        let error_line = format!("Error adding record to tree:\n\t{}\n\t{}\n", &good_line, &e);
-                        log_file.write(error_line.as_bytes());
-                        error_counter += 1;
-                        continue
-                    },
-                }
+        return match self {
+            Index::KdTree(x) => &mut x.database,
+            Index::Fingerprint(x) => &mut x.database,
+        }
+    }
+
+    fn find_descriptor(&mut self, descriptor: &Descriptor) -> Result<Option<CompoundIndex>, String> {
+
+        return match self {
+            Index::KdTree(x) => x.find_descriptor(descriptor),
+            Index::Fingerprint(_) => Err("Fingerprint indexes can't look up descriptors".to_string()),
+        }
+    }
+
+    fn finish(self) -> Result<(), String> {
+
+        match self {
+            Index::KdTree(mut x) => {
+                x.reorder_nodes()?;
+                x.flush();
+
+                let report = x.check_integrity()?;
+                println!("Checked {} nodes, {} record pages and {} records", report.num_nodes, report.num_record_pages, report.num_records);
+            },
+            Index::Fingerprint(x) => x.finish()?,
+        }
+
+        Ok(())
+    }
+}
 
-                success_counter += 1;
-                counter += 1;
+///Collects source files from every input option, in the order: explicit filenames, manifests,
+///globs, directories. Duplicates keep their first position.
+fn resolve_input_files(args: &BuildFromFileArgs, spec: &InputSpec) -> Result<Vec<String>, String> {
+
+    let mut filenames: Vec<String> = args.filenames.clone();
+
+    for manifest in args.input_manifest.iter() {
+        let contents = std::fs::read_to_string(manifest).map_err(|e| format!("Could not read manifest {:?}: {}", manifest, e))?;
+
+        //relative entries are relative to the manifest, not wherever the build is run from
+        let manifest_dir = std::path::Path::new(manifest).parent().unwrap_or(std::path::Path::new(""));
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("#") {
+                continue;
             }
+            filenames.push(manifest_dir.join(line).to_string_lossy().to_string());
         }
     }
 
-    tree.flush();
+    let sidecar_extension = spec.descriptor_sidecar.as_ref().map(|x| x.extension.clone());
 
-    println!("{} records failed to be added to tree, logged in {}", error_counter, log_file_path);
+    for pattern in args.input_glob.iter() {
+        let mut matches: Vec<String> = Vec::new();
+
+        for entry in glob(pattern).map_err(|e| format!("Bad glob pattern {:?}: {}", pattern, e))? {
+            let path = entry.map_err(|e| e.to_string())?;
+            if is_input_data_file(&path, &sidecar_extension) {
+                matches.push(path.to_string_lossy().to_string());
+            }
+        }
+
+        if matches.is_empty() {
+            return Err(format!("Glob pattern matched no files: {:?}", pattern));
+        }
+
+        matches.sort();
+        filenames.extend(matches);
+    }
+
+    for dir in args.input_dir.iter() {
+        let mut matches: Vec<String> = Vec::new();
+
+        for entry in std::fs::read_dir(dir).map_err(|e| format!("Could not read directory {:?}: {}", dir, e))? {
+            let path = entry.map_err(|e| e.to_string())?.path();
+
+            if is_input_data_file(&path, &sidecar_extension) {
+                matches.push(path.to_string_lossy().to_string());
+            }
+        }
+
+        matches.sort();
+        filenames.extend(matches);
+    }
+
+    let mut seen: std::collections::HashSet<String> = std::collections::HashSet::new();
+    filenames.retain(|x| seen.insert(x.clone()));
+
+    //catch bad entries before anything is built rather than partway through
+    for filename in filenames.iter() {
+        if filename != input::STDIN_FILENAME && !std::path::Path::new(filename).is_file() {
+            return Err(format!("Input file not found: {:?}", filename));
+        }
+    }
+
+    return Ok(filenames);
 }
 
-fn parse_descriptor_vec(v: Vec<&str>) -> Result<Vec<f32>, std::num::ParseFloatError> {
+///Whether a file found by a glob or in an input directory is source data, rather than a
+///directory, a hidden file or a descriptor sidecar
+fn is_input_data_file(path: &std::path::Path, sidecar_extension: &Option<String>) -> bool {
+
+    if !path.is_file() {
+        return false;
+    }
 
-    let descriptor_values: Result<Vec<f32>,_> = v.iter()
-        .map(|x| x
-                  .replace("[","")
-                  .replace("]","")
-                  .replace(" ","")
-                  .parse::<f32>()
-            )
-        .collect();
-    return descriptor_values;
+    let name = path.file_name().unwrap().to_string_lossy().to_string();
+    if name.starts_with(".") {
+        return false;
+    }
 
+    if let Some(extension) = sidecar_extension {
+        if name.ends_with(&format!(".{}", extension)) {
+            return false;
+        }
+    }
 
+    return true;
 }
 
 /*
@@ -489,3 +981,132 @@ mod tests {
 }
 */
 
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use input::{DescriptorSidecar, SidecarFormat};
+
+    fn build_args(extra: &[&str]) -> BuildFromFileArgs {
+
+        let mut argv = vec!["builder", "build-from-files", "--config-filename", "unused.yaml"];
+        argv.extend_from_slice(extra);
+
+        return match App::try_parse_from(argv).unwrap().command {
+            Command::BuildFromFiles(x) => x,
+            _ => panic!("expected build-from-files"),
+        }
+    }
+
+    #[test]
+    fn quick_resolve_input_files() {
+
+        let dir = "/tmp/builder_resolve";
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(format!("{}/nested", dir)).unwrap();
+
+        for name in ["b_1.csv", "a_1.csv", "a_1.npy", ".hidden.csv", "nested/c_1.csv"] {
+            std::fs::write(format!("{}/{}", dir, name), "").unwrap();
+        }
+        let manifest = format!("{}.manifest.txt", dir);
+        std::fs::write(&manifest, format!("# inputs\n\n{}/nested/c_1.csv\n", dir)).unwrap();
+
+        //entries relative to the manifest's own directory
+        let relative_manifest = format!("{}/nested/manifest.txt", dir);
+        std::fs::write(&relative_manifest, "c_1.csv\n").unwrap();
+
+        let mut spec = InputSpec::default();
+        spec.descriptor_sidecar = Some(DescriptorSidecar {
+            format: SidecarFormat::Npy,
+            extension: "npy".to_string(),
+            columns: Vec::new(),
+        });
+
+        let expected = vec![format!("{}/a_1.csv", dir), format!("{}/b_1.csv", dir)];
+
+        //globs and directories both skip sidecars, hidden files and directories
+        let glob_pattern = format!("{}/*", dir);
+        let from_glob = resolve_input_files(&build_args(&["--input-glob", &glob_pattern]), &spec).unwrap();
+        assert_eq!(from_glob, expected);
+
+        let from_dir = resolve_input_files(&build_args(&["--input-dir", dir]), &spec).unwrap();
+        assert_eq!(from_dir, expected);
+
+        //without a sidecar, an npy file is taken as data
+        let from_glob = resolve_input_files(&build_args(&["--input-glob", &glob_pattern]), &InputSpec::default()).unwrap();
+        assert_eq!(from_glob.len(), 3);
+
+        //explicit files come first, then manifests, globs and directories, without repeats
+        let b = format!("{}/b_1.csv", dir);
+        let all = resolve_input_files(&build_args(&["--filenames", &b, "--input-manifest", &manifest, "--input-dir", dir]), &spec).unwrap();
+        assert_eq!(all, vec![b.clone(), format!("{}/nested/c_1.csv", dir), format!("{}/a_1.csv", dir)]);
+
+        let from_relative = resolve_input_files(&build_args(&["--input-manifest", &relative_manifest]), &spec).unwrap();
+        assert_eq!(from_relative, vec![format!("{}/nested/c_1.csv", dir)]);
+
+        let no_match = format!("{}/*.parquet", dir);
+        assert!(resolve_input_files(&build_args(&["--input-glob", &no_match]), &spec).is_err());
+
+        //missing files are caught up front, whether listed in a manifest or given directly
+        std::fs::write(&relative_manifest, "c_1.csv\nc_2.csv\n").unwrap();
+        let missing = resolve_input_files(&build_args(&["--input-manifest", &relative_manifest]), &spec).unwrap_err();
+        assert!(missing.contains("nested/c_2.csv"));
+
+        let missing = format!("{}/d_1.csv", dir);
+        assert!(resolve_input_files(&build_args(&["--filenames", &missing]), &spec).is_err());
+    }
+
+    #[test]
+    fn quick_input_descriptors_skip_wrong_lengths() {
+
+        let dir = "/tmp/builder_input_descriptors";
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir).unwrap();
+
+        let filename = format!("{}/a_1.csv", dir);
+        std::fs::write(&filename, "smiles,id,a,b\nCCO,z1,1.0,2.0\nCC,z2,3.0\nCCC,z3,4.0,5.0\n").unwrap();
+
+        let filenames = vec![filename];
+        let descriptors: Vec<Descriptor> = input_descriptors(&InputSpec::default(), &filenames, 2).map(|x| x.unwrap()).collect();
+
+        assert_eq!(descriptors.len(), 2);
+        assert_eq!(descriptors[1].data, vec![4.0, 5.0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "no fingerprint_column")]
+    fn quick_fingerprints_need_a_column() {
+
+        let mut config = tree::TreeConfig::default();
+        config.directory = "/tmp/builder_fingerprint_column".to_string();
+        config.fingerprint_bits = Some(1024);
+
+        let config_filename = "/tmp/builder_fingerprint_column.yaml";
+        config.to_file(config_filename.to_string());
+
+        let mut args = build_args(&["--filenames", "/tmp/builder_fingerprint_column_missing.csv"]);
+        args.config_filename = config_filename.to_string();
+
+        build_from_files(&args);
+    }
+
+    #[test]
+    #[should_panic(expected = "keep_full_vectors")]
+    fn quick_lossy_descriptor_dedup_needs_vectors() {
+
+        let mut config = tree::TreeConfig::default();
+        config.directory = "/tmp/builder_lossy_dedup".to_string();
+        config.descriptor_encoding = DescriptorEncoding::F16;
+
+        let config_filename = "/tmp/builder_lossy_dedup.yaml";
+        config.to_file(config_filename.to_string());
+
+        let input = "/tmp/builder_lossy_dedup.csv";
+        std::fs::write(input, "smiles,id,a,b,c,d,e,f,g,h\nCC,a,1,2,3,4,5,6,7,8\n").unwrap();
+
+        let mut args = build_args(&["--filenames", input, "--dedup", "skip"]);
+        args.config_filename = config_filename.to_string();
+
+        build_from_files(&args);
+    }
+}
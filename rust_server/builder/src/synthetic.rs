@@ -0,0 +1,95 @@
+//! Seeded generators of synthetic compounds for benchmarking tree builds and queries
+
+use kd_tree::data::{CompoundIdentifier, Descriptor, CompoundRecord};
+
+use clap::ValueEnum;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Distribution {
+    ///Every component uniform in [-1, 1], like `Descriptor::random`
+    Uniform,
+    ///Gaussian mixture with centers uniform in [-1, 1]
+    Clustered,
+}
+
+pub struct SyntheticGenerator {
+    rng: StdRng,
+    distribution: Distribution,
+    desc_length: usize,
+    centers: Vec<Vec<f32>>,
+    cluster_std: f32,
+    scales: Vec<f32>,
+    counter: usize,
+}
+
+impl SyntheticGenerator {
+
+    ///Dimension i of every descriptor is scaled by `scale_decay^i`
+    pub fn new(distribution: Distribution, desc_length: usize, num_clusters: usize, cluster_std: f32, scale_decay: f32, seed: u64) -> Self {
+
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        let centers: Vec<Vec<f32>> = match distribution {
+            Distribution::Uniform => Vec::new(),
+            Distribution::Clustered => (0..num_clusters.max(1))
+                .map(|_| (0..desc_length).map(|_| rng.gen_range(-1.0..1.0)).collect())
+                .collect(),
+        };
+
+        return Self {
+            rng,
+            distribution,
+            desc_length,
+            centers,
+            cluster_std,
+            scales: (0..desc_length).map(|i| scale_decay.powi(i as i32)).collect(),
+            counter: 0,
+        };
+    }
+
+    ///Standard normal sample via Box-Muller
+    fn standard_normal(&mut self) -> f32 {
+
+        let u1: f32 = self.rng.gen_range(f32::EPSILON..1.0);
+        let u2: f32 = self.rng.gen_range(0.0..1.0);
+
+        return (-2.0 * u1.ln()).sqrt() * (2.0 * std::f32::consts::PI * u2).cos();
+    }
+
+    pub fn descriptor(&mut self) -> Descriptor {
+
+        let data: Vec<f32> = match self.distribution {
+            Distribution::Uniform => (0..self.desc_length).map(|_| self.rng.gen_range(-1.0..1.0)).collect(),
+            Distribution::Clustered => {
+                let center = self.rng.gen_range(0..self.centers.len());
+                let mut v: Vec<f32> = Vec::with_capacity(self.desc_length);
+                for i in 0..self.desc_length {
+                    let noise = self.standard_normal() * self.cluster_std;
+                    v.push(self.centers[center][i] + noise);
+                }
+                v
+            },
+        };
+
+        let data: Vec<f32> = data.iter().zip(self.scales.iter()).map(|(x, scale)| x * scale).collect();
+
+        return Descriptor::from_vec(data, self.desc_length);
+    }
+
+    pub fn record(&mut self) -> CompoundRecord {
+
+        let descriptor = self.descriptor();
+        let identifier = CompoundIdentifier::from_string(format!("SYNTH{}", self.counter));
+        self.counter += 1;
+
+        return CompoundRecord {
+            smiles: "no smiles".to_string(),
+            compound_identifier: identifier,
+            descriptor,
+            length: self.desc_length,
+            dataset: None,
+        };
+    }
+}
@@ -0,0 +1,439 @@
+//! Per-compound attributes (molecular weight, logP, vendor, ...) for filtering searches
+//!
+//! Columns are declared with `attributes` in the `TreeConfig`. Every compound gets a fixed width row
+//! in the tree's `attributes` file, four bytes per column: an f32 for numeric columns and a category
+//! id for categorical ones. Category names are kept in `attribute_categories.yaml`.
+//!
+//! A `Filter` is a list of conditions like `mw<450` or `vendor=enamine|mcule` that must all hold.
+//! Searches check it before a record enters `TopHits`, so a selective filter still returns `k`
+//! results instead of whatever survives out of the unfiltered top `k`.
+
+use serde::{Serialize, Deserialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::os::unix::fs::FileExt;
+
+use crate::data::CompoundIndex;
+
+const ATTRIBUTE_SIZE: usize = 4;
+
+const MISSING_CATEGORY: u32 = u32::MAX;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AttributeKind {
+    Numeric,
+    Categorical,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AttributeColumn {
+    pub name: String,
+    pub kind: AttributeKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AttributeValue {
+    Number(f32),
+    ///Id into the column's category names
+    Category(u32),
+    Missing,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+}
+
+impl Comparison {
+
+    fn holds(&self, a: f32, b: f32) -> bool {
+
+        return match self {
+            Comparison::Less => a < b,
+            Comparison::LessEqual => a <= b,
+            Comparison::Greater => a > b,
+            Comparison::GreaterEqual => a >= b,
+            Comparison::Equal => a == b,
+            Comparison::NotEqual => a != b,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Condition {
+    ///Numeric column compared against `value`
+    Compare { column: usize, comparison: Comparison, value: f32 },
+    ///Categorical column is one of `categories`, or with `negate` none of them
+    OneOf { column: usize, categories: Vec<u32>, negate: bool },
+}
+
+///Conditions that must all hold. A missing value fails every condition on its column.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Filter {
+    pub conditions: Vec<Condition>,
+}
+
+impl Filter {
+
+    pub fn matches(&self, row: &[AttributeValue]) -> bool {
+
+        for condition in self.conditions.iter() {
+
+            let holds = match condition {
+                Condition::Compare { column, comparison, value } => match row[*column] {
+                    AttributeValue::Number(x) => comparison.holds(x, *value),
+                    _ => false,
+                },
+                Condition::OneOf { column, categories, negate } => match row[*column] {
+                    AttributeValue::Category(x) => categories.contains(&x) != *negate,
+                    _ => false,
+                },
+            };
+
+            if !holds {
+                return false;
+            }
+        }
+
+        return true;
+    }
+}
+
+///Operators in the order they're looked for, so `<=` isn't read as `<`
+const OPERATORS: [(&str, Comparison); 6] = [
+    ("<=", Comparison::LessEqual),
+    (">=", Comparison::GreaterEqual),
+    ("!=", Comparison::NotEqual),
+    ("<", Comparison::Less),
+    (">", Comparison::Greater),
+    ("=", Comparison::Equal),
+];
+
+#[derive(Debug)]
+pub struct AttributeStore {
+    file: File,
+    writer: Option<BufWriter<File>>,
+    columns: Vec<AttributeColumn>,
+    ///Names of each categorical column's categories, by id. Empty for numeric columns.
+    categories: Vec<Vec<String>>,
+    categories_filename: String,
+    num_rows: u64,
+}
+
+impl AttributeStore {
+
+    pub fn create(filename: &str, categories_filename: &str, columns: &[AttributeColumn]) -> Result<Self, String> {
+
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(filename)
+            .map_err(|e| format!("Could not create attribute file {}: {}", filename, e))?;
+
+        let writer = BufWriter::new(file.try_clone().map_err(|e| e.to_string())?);
+
+        return Ok(Self {
+            file,
+            writer: Some(writer),
+            columns: columns.to_vec(),
+            categories: vec![Vec::new(); columns.len()],
+            categories_filename: categories_filename.to_string(),
+            num_rows: 0,
+        });
+    }
+
+    pub fn open(filename: &str, categories_filename: &str, columns: &[AttributeColumn]) -> Result<Self, String> {
+
+        let file = File::open(filename)
+            .map_err(|e| format!("Could not open attribute file {}: {}", filename, e))?;
+
+        let file_length = file.metadata().map_err(|e| e.to_string())?.len();
+
+        let categories: Vec<Vec<String>> = match std::fs::read_to_string(categories_filename) {
+            Ok(x) => serde_yaml::from_str(&x).map_err(|e| e.to_string())?,
+            Err(_) => vec![Vec::new(); columns.len()],
+        };
+
+        if categories.len() != columns.len() {
+            return Err(format!("{} has categories for {} columns, config has {}", categories_filename, categories.len(), columns.len()));
+        }
+
+        return Ok(Self {
+            file,
+            writer: None,
+            columns: columns.to_vec(),
+            categories,
+            categories_filename: categories_filename.to_string(),
+            num_rows: file_length / (columns.len() * ATTRIBUTE_SIZE).max(1) as u64,
+        });
+    }
+
+    ///Opens an existing file to keep adding rows to it
+    pub fn open_append(filename: &str, categories_filename: &str, columns: &[AttributeColumn]) -> Result<Self, String> {
+
+        let mut store = Self::open(filename, categories_filename, columns)?;
+
+        let file = OpenOptions::new()
+            .append(true)
+            .open(filename)
+            .map_err(|e| format!("Could not open attribute file {}: {}", filename, e))?;
+
+        store.writer = Some(BufWriter::new(file));
+
+        return Ok(store);
+    }
+
+    pub fn columns(&self) -> &[AttributeColumn] {
+        return &self.columns;
+    }
+
+    pub fn len(&self) -> u64 {
+        return self.num_rows;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.num_rows == 0;
+    }
+
+    ///Values for one compound from their text, one per column. Empty text is a missing value, and
+    ///new category names get the next id.
+    pub fn parse_row(&mut self, raw: &[String]) -> Result<Vec<AttributeValue>, String> {
+
+        if raw.len() != self.columns.len() {
+            return Err(format!("Got {} attribute values, expected {}", raw.len(), self.columns.len()));
+        }
+
+        let mut row: Vec<AttributeValue> = Vec::with_capacity(raw.len());
+
+        for (i, text) in raw.iter().enumerate() {
+
+            let text = text.trim();
+
+            if text.is_empty() {
+                row.push(AttributeValue::Missing);
+                continue;
+            }
+
+            let value = match self.columns[i].kind {
+                AttributeKind::Numeric => match text.parse::<f32>() {
+                    Ok(x) => AttributeValue::Number(x),
+                    Err(_) => return Err(format!("Attribute {} isn't a number: {:?}", self.columns[i].name, text)),
+                },
+                AttributeKind::Categorical => {
+                    let names = &mut self.categories[i];
+                    let id = match names.iter().position(|x| x == text) {
+                        Some(x) => x,
+                        None => {
+                            names.push(text.to_string());
+                            names.len() - 1
+                        },
+                    };
+                    AttributeValue::Category(id as u32)
+                },
+            };
+
+            row.push(value);
+        }
+
+        return Ok(row);
+    }
+
+    ///Rows have to be appended in compound index order
+    pub fn append(&mut self, index: CompoundIndex, row: &[AttributeValue]) -> Result<(), String> {
+
+        if index != self.num_rows {
+            return Err(format!("Attributes for compound {} written out of order, expected {}", index, self.num_rows));
+        }
+
+        if row.len() != self.columns.len() {
+            return Err(format!("Got {} attribute values, expected {}", row.len(), self.columns.len()));
+        }
+
+        let writer = match &mut self.writer {
+            Some(x) => x,
+            None => return Err("Attribute file was opened read only".to_string()),
+        };
+
+        for (value, column) in row.iter().zip(self.columns.iter()) {
+
+            let bytes = match (value, column.kind) {
+                (AttributeValue::Number(x), AttributeKind::Numeric) => x.to_be_bytes(),
+                (AttributeValue::Category(x), AttributeKind::Categorical) => x.to_be_bytes(),
+                (AttributeValue::Missing, AttributeKind::Numeric) => f32::NAN.to_be_bytes(),
+                (AttributeValue::Missing, AttributeKind::Categorical) => MISSING_CATEGORY.to_be_bytes(),
+                _ => return Err(format!("Wrong kind of value for attribute {}", column.name)),
+            };
+
+            writer.write_all(&bytes).map_err(|e| e.to_string())?;
+        }
+
+        self.num_rows += 1;
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), String> {
+
+        if let Some(writer) = &mut self.writer {
+            writer.flush().map_err(|e| e.to_string())?;
+
+            let serialized = serde_yaml::to_string(&self.categories).unwrap();
+            std::fs::write(&self.categories_filename, serialized).map_err(|e| e.to_string())?;
+        }
+
+        Ok(())
+    }
+
+    pub fn get(&self, index: CompoundIndex) -> Result<Vec<AttributeValue>, String> {
+
+        if index >= self.num_rows {
+            return Err(format!("No stored attributes for compound {}", index));
+        }
+
+        let row_size = self.columns.len() * ATTRIBUTE_SIZE;
+        let mut bytes = vec![0u8; row_size];
+        self.file.read_exact_at(&mut bytes, index * row_size as u64).map_err(|e| e.to_string())?;
+
+        let row = bytes.chunks_exact(ATTRIBUTE_SIZE).zip(self.columns.iter()).map(|(chunk, column)| {
+            let chunk: [u8; ATTRIBUTE_SIZE] = chunk.try_into().unwrap();
+            match column.kind {
+                AttributeKind::Numeric => match f32::from_be_bytes(chunk) {
+                    x if x.is_nan() => AttributeValue::Missing,
+                    x => AttributeValue::Number(x),
+                },
+                AttributeKind::Categorical => match u32::from_be_bytes(chunk) {
+                    MISSING_CATEGORY => AttributeValue::Missing,
+                    x => AttributeValue::Category(x),
+                },
+            }
+        }).collect();
+
+        return Ok(row);
+    }
+
+    ///Text for a stored value, e.g. to report it with a result
+    pub fn display(&self, column: usize, value: &AttributeValue) -> String {
+
+        return match value {
+            AttributeValue::Number(x) => x.to_string(),
+            AttributeValue::Category(x) => self.categories[column].get(*x as usize).cloned().unwrap_or_default(),
+            AttributeValue::Missing => "".to_string(),
+        }
+    }
+
+    ///Reads a filter like `mw<450,logp>=1,vendor=enamine|mcule`: comma separated conditions that
+    ///must all hold. Numeric columns take `<`, `<=`, `>`, `>=`, `=` and `!=`, categorical columns
+    ///`=` and `!=` with `|` between alternatives. Category names the store hasn't seen match nothing.
+    pub fn parse_filter(&self, text: &str) -> Result<Filter, String> {
+
+        let mut conditions: Vec<Condition> = Vec::new();
+
+        for clause in text.split(",").map(|x| x.trim()).filter(|x| !x.is_empty()) {
+
+            let (position, operator, comparison) = match OPERATORS.iter().filter_map(|(op, comparison)| clause.find(op).map(|i| (i, *op, *comparison))).min_by_key(|x| x.0) {
+                Some(x) => x,
+                None => return Err(format!("No comparison in filter condition {:?}", clause)),
+            };
+
+            let name = clause[..position].trim();
+            let value = clause[position + operator.len()..].trim();
+
+            let column = match self.columns.iter().position(|x| x.name == name) {
+                Some(x) => x,
+                None => return Err(format!("Unknown attribute {:?}", name)),
+            };
+
+            let condition = match self.columns[column].kind {
+                AttributeKind::Numeric => match value.parse::<f32>() {
+                    Ok(value) => Condition::Compare { column, comparison, value },
+                    Err(_) => return Err(format!("Attribute {} needs a number, got {:?}", name, value)),
+                },
+                AttributeKind::Categorical => {
+                    let negate = match comparison {
+                        Comparison::Equal => false,
+                        Comparison::NotEqual => true,
+                        _ => return Err(format!("Attribute {} is categorical, use = or !=", name)),
+                    };
+
+                    let categories: Vec<u32> = value.split("|")
+                        .filter_map(|x| self.categories[column].iter().position(|y| y == x.trim()))
+                        .map(|x| x as u32)
+                        .collect();
+
+                    Condition::OneOf { column, categories, negate }
+                },
+            };
+
+            conditions.push(condition);
+        }
+
+        return Ok(Filter { conditions });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn quick_attribute_store_and_filters() {
+
+        let columns = vec![
+            AttributeColumn { name: "mw".to_string(), kind: AttributeKind::Numeric },
+            AttributeColumn { name: "vendor".to_string(), kind: AttributeKind::Categorical },
+        ];
+
+        let filename = "/tmp/quick_attributes";
+        let categories_filename = "/tmp/quick_attribute_categories.yaml";
+
+        let mut store = AttributeStore::create(filename, categories_filename, &columns).unwrap();
+
+        let raw = [("300", "enamine"), ("450", "mcule"), ("", "enamine"), ("520.5", "")];
+        for (i, (mw, vendor)) in raw.iter().enumerate() {
+            let row = store.parse_row(&[mw.to_string(), vendor.to_string()]).unwrap();
+            store.append(i as u64, &row).unwrap();
+        }
+
+        assert!(store.parse_row(&["heavy".to_string(), "enamine".to_string()]).is_err());
+        assert!(store.append(7, &[AttributeValue::Missing, AttributeValue::Missing]).is_err());
+        assert!(store.append(4, &[AttributeValue::Category(0), AttributeValue::Missing]).is_err());
+
+        store.flush().unwrap();
+
+        let store = AttributeStore::open(filename, categories_filename, &columns).unwrap();
+        assert_eq!(store.len(), 4);
+        assert_eq!(store.get(0).unwrap(), vec![AttributeValue::Number(300.0), AttributeValue::Category(0)]);
+        assert_eq!(store.get(2).unwrap(), vec![AttributeValue::Missing, AttributeValue::Category(0)]);
+        assert_eq!(store.get(3).unwrap(), vec![AttributeValue::Number(520.5), AttributeValue::Missing]);
+        assert_eq!(store.display(1, &store.get(1).unwrap()[1]), "mcule");
+        assert!(store.get(4).is_err());
+
+        let matching = |filter: &str| -> Vec<u64> {
+            let filter = store.parse_filter(filter).unwrap();
+            (0..4).filter(|i| filter.matches(&store.get(*i).unwrap())).collect()
+        };
+
+        assert_eq!(matching("mw<450"), vec![0]);
+        assert_eq!(matching("mw<=450"), vec![0, 1]);
+        assert_eq!(matching("mw >= 450"), vec![1, 3]);
+        assert_eq!(matching("vendor=enamine"), vec![0, 2]);
+        assert_eq!(matching("vendor=enamine|mcule,mw<1000"), vec![0, 1]);
+        assert_eq!(matching("vendor!=enamine"), vec![1]);
+        assert_eq!(matching("vendor=zinc"), Vec::<u64>::new());
+        assert_eq!(matching(""), vec![0, 1, 2, 3]);
+
+        assert!(store.parse_filter("logp<3").is_err());
+        assert!(store.parse_filter("mw<heavy").is_err());
+        assert!(store.parse_filter("vendor<enamine").is_err());
+        assert!(store.parse_filter("mw").is_err());
+    }
+}
@@ -22,6 +22,8 @@ pub struct CompoundRecord {
     pub compound_identifier: CompoundIdentifier,
     pub descriptor: Descriptor,
     pub length: usize,
+    ///Id of the source dataset (vendor, catalog) this record came from, if the build names them
+    pub dataset: Option<u8>,
 }
 
 impl CompoundRecord {
@@ -34,6 +36,7 @@ impl CompoundRecord {
             compound_identifier,
             descriptor,
             length,
+            dataset: Some(dataset_identifier),
         }
     }
 
@@ -61,10 +64,98 @@ impl CompoundRecord {
             compound_identifier,
             descriptor,
             length,
+            dataset: None,
         }
     }
 }
 
+///Number of independent accumulators in the distance kernels, so the inner loop vectorizes
+const LANES: usize = 8;
+
+fn sum_lanes(acc: &[f32; LANES]) -> f32 {
+
+    let mut sum: f32 = 0.0;
+    for value in acc.iter() {
+        sum += value;
+    }
+
+    return sum;
+}
+
+///Descriptor lengths with their own fully unrolled distance kernels. Other lengths use the
+///general loops.
+pub const KERNEL_LENGTHS: [usize; 3] = [8, 16, 32];
+
+///`Descriptor::squared_distance` for a length known at compile time. Accumulates into the same
+///lanes in the same order as the general loop, so the two agree exactly.
+pub fn squared_distance_fixed<const N: usize>(a: &[f32; N], b: &[f32; N]) -> f32 {
+
+    let mut acc = [0.0f32; LANES];
+
+    for i in 0..N {
+        let d = a[i] - b[i];
+        acc[i % LANES] += d * d;
+    }
+
+    return sum_lanes(&acc);
+}
+
+///`squared_distance_to_bytes` for a length known at compile time
+pub fn squared_distance_to_bytes_fixed<const N: usize>(query: &[f32; N], bytes: &[u8]) -> f32 {
+
+    let mut acc = [0.0f32; LANES];
+
+    let bytes: &[u8] = &bytes[..N * 4];
+
+    for (i, (q, value)) in query.iter().zip(bytes.chunks_exact(4)).enumerate() {
+        let d = q - f32::from_be_bytes(value.try_into().unwrap());
+        acc[i % LANES] += d * d;
+    }
+
+    return sum_lanes(&acc);
+}
+
+///Squared distance from `query` to a descriptor stored as big endian f32s in `bytes`, without
+///decoding it first. Sums in the same order as `Descriptor::squared_distance`, so the two agree exactly.
+pub fn squared_distance_to_bytes(query: &[f32], bytes: &[u8]) -> f32 {
+
+    if bytes.len() != query.len() * 4 {
+        return squared_distance_to_bytes_dynamic(query, bytes);
+    }
+
+    return match query.len() {
+        8 => squared_distance_to_bytes_fixed::<8>(query.try_into().unwrap(), bytes),
+        16 => squared_distance_to_bytes_fixed::<16>(query.try_into().unwrap(), bytes),
+        32 => squared_distance_to_bytes_fixed::<32>(query.try_into().unwrap(), bytes),
+        _ => squared_distance_to_bytes_dynamic(query, bytes),
+    }
+}
+
+fn squared_distance_to_bytes_dynamic(query: &[f32], bytes: &[u8]) -> f32 {
+
+    let mut acc = [0.0f32; LANES];
+
+    let q = query.chunks_exact(LANES);
+    let v = bytes.chunks_exact(LANES * 4);
+    let (q_rem, v_rem) = (q.remainder(), v.remainder());
+
+    for (x, y) in q.zip(v) {
+        for j in 0..LANES {
+            let value = f32::from_be_bytes(y[j * 4..j * 4 + 4].try_into().unwrap());
+            let d = x[j] - value;
+            acc[j] += d * d;
+        }
+    }
+
+    for j in 0..q_rem.len().min(v_rem.len() / 4) {
+        let value = f32::from_be_bytes(v_rem[j * 4..j * 4 + 4].try_into().unwrap());
+        let d = q_rem[j] - value;
+        acc[j] += d * d;
+    }
+
+    return sum_lanes(&acc);
+}
+
 #[derive(Debug, PartialEq, Clone, Serialize)]
 pub struct Descriptor {
     pub data: Vec<f32>,
@@ -76,15 +167,47 @@ impl Descriptor {
 
     pub fn distance(&self, other: &Descriptor) -> f32 {
 
-        let mut sum: f32 = 0.0;
-        for i in 0..self.length {
-            sum += f32::powf(self.data[i] - other.data[i], 2.0);
+        return self.squared_distance(other).sqrt();
+    }
+
+    ///Squared euclidean distance, for comparisons where the square root isn't needed
+    pub fn squared_distance(&self, other: &Descriptor) -> f32 {
+
+        if self.data.len() != other.data.len() {
+            return self.squared_distance_dynamic(other);
+        }
+
+        let (a, b) = (self.data.as_slice(), other.data.as_slice());
+
+        return match a.len() {
+            8 => squared_distance_fixed::<8>(a.try_into().unwrap(), b.try_into().unwrap()),
+            16 => squared_distance_fixed::<16>(a.try_into().unwrap(), b.try_into().unwrap()),
+            32 => squared_distance_fixed::<32>(a.try_into().unwrap(), b.try_into().unwrap()),
+            _ => self.squared_distance_dynamic(other),
+        }
+    }
+
+    fn squared_distance_dynamic(&self, other: &Descriptor) -> f32 {
 
+        let mut acc = [0.0f32; LANES];
+
+        let a = self.data.chunks_exact(LANES);
+        let b = other.data.chunks_exact(LANES);
+        let (a_rem, b_rem) = (a.remainder(), b.remainder());
+
+        for (x, y) in a.zip(b) {
+            for j in 0..LANES {
+                let d = x[j] - y[j];
+                acc[j] += d * d;
+            }
         }
 
-        let result = f32::powf(sum, 0.5);
-        return result;
-    
+        for j in 0..a_rem.len().min(b_rem.len()) {
+            let d = a_rem[j] - b_rem[j];
+            acc[j] += d * d;
+        }
+
+        return sum_lanes(&acc);
     }
 
     pub fn random(length: usize) -> Self {
@@ -119,19 +242,68 @@ impl Descriptor {
 
         return s;
     }
+}
+
+///A descriptor whose length is known at compile time, kept on the stack
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct FixedDescriptor<const N: usize>(pub [f32; N]);
+
+impl<const N: usize> FixedDescriptor<N> {
+
+    ///None if `descriptor` isn't `N` long
+    pub fn from_descriptor(descriptor: &Descriptor) -> Option<Self> {
+
+        return descriptor.data.as_slice().try_into().ok().map(Self);
+    }
 
-    pub fn add_small_noise(&mut self) {
+    pub fn to_descriptor(&self) -> Descriptor {
 
-        let mut rng = rand::thread_rng();
-        let noise: Vec<f32> = (0..self.length).map(|_| rng.gen_range(0.0..0.0001)).collect();
+        return Descriptor { data: self.0.to_vec(), length: N };
+    }
+
+    pub fn squared_distance(&self, other: &Self) -> f32 {
+
+        return squared_distance_fixed(&self.0, &other.0);
+    }
+
+    ///Squared distance to a descriptor stored as `N` big endian f32s
+    pub fn squared_distance_to_bytes(&self, bytes: &[u8]) -> f32 {
+
+        return squared_distance_to_bytes_fixed(&self.0, bytes);
+    }
+}
+
+///A query descriptor in the form its distance loop wants: a `FixedDescriptor` for the lengths
+///in `KERNEL_LENGTHS`, so the length is matched once per query instead of once per record
+#[derive(Debug, Clone)]
+pub enum QueryKernel {
+    Fixed8(FixedDescriptor<8>),
+    Fixed16(FixedDescriptor<16>),
+    Fixed32(FixedDescriptor<32>),
+    Dynamic(Vec<f32>),
+}
+
+impl QueryKernel {
 
-        let mut result: Vec<f32> = vec![0.0; self.length];
+    pub fn new(query: &Descriptor) -> Self {
 
-        for i in 0..self.length {
-            result[i] = self.data[i] + noise[i];
+        return match query.data.len() {
+            8 => QueryKernel::Fixed8(FixedDescriptor::from_descriptor(query).unwrap()),
+            16 => QueryKernel::Fixed16(FixedDescriptor::from_descriptor(query).unwrap()),
+            32 => QueryKernel::Fixed32(FixedDescriptor::from_descriptor(query).unwrap()),
+            _ => QueryKernel::Dynamic(query.data.clone()),
         }
+    }
+
+    ///Squared distance to a descriptor stored as big endian f32s
+    pub fn squared_distance_to_bytes(&self, bytes: &[u8]) -> f32 {
 
-        self.data = result;
+        return match self {
+            QueryKernel::Fixed8(x) => x.squared_distance_to_bytes(bytes),
+            QueryKernel::Fixed16(x) => x.squared_distance_to_bytes(bytes),
+            QueryKernel::Fixed32(x) => x.squared_distance_to_bytes(bytes),
+            QueryKernel::Dynamic(x) => squared_distance_to_bytes_dynamic(x, bytes),
+        }
     }
 }
 
@@ -143,24 +315,16 @@ impl CompoundIdentifier {
 
     pub fn from_string(s: String) -> Self {
 
-        assert!(s.len() <= IDENTIFIER_SIZE);
-
         return Self::from_str(&s);
     }
 
+    ///Any length of ASCII, ignoring NUL padding
     pub fn from_str(data: &str) -> Self {
 
-        let mut fill_arr = [0u8; IDENTIFIER_SIZE];
-
-        let bytes = data.as_bytes();
-
-        fill_arr[..bytes.len()].copy_from_slice(bytes);
-
-        let s: [u8;IDENTIFIER_SIZE] = fill_arr.try_into().expect("slice with incorrect length");
-
-        return Self::_from_arr(&s);
-
+        let identifier_str: AsciiString = AsciiString::from_ascii(data.as_bytes()).unwrap();
+        let cleaned_string: String = String::from(identifier_str).chars().filter(|x| *x != '\0').collect();
 
+        return Self(cleaned_string);
     }
 
     fn _from_arr(data: &[u8]) -> Self {
@@ -325,6 +489,35 @@ mod test {
 
     }
 
+    #[test]
+    fn quick_distance_from_bytes_matches() {
+
+        for length in [1, 7, 8, 12, 16, 32, 33] {
+
+            let a = Descriptor::random(length);
+            let b = Descriptor::random(length);
+
+            let mut bytes: Vec<u8> = Vec::new();
+            for value in b.data.iter() {
+                bytes.extend_from_slice(&value.to_be_bytes());
+            }
+
+            assert_eq!(squared_distance_to_bytes(&a.data, &bytes), a.squared_distance(&b));
+            assert_eq!(QueryKernel::new(&a).squared_distance_to_bytes(&bytes), a.squared_distance(&b));
+            assert_eq!(squared_distance_to_bytes_dynamic(&a.data, &bytes), a.squared_distance_dynamic(&b));
+            assert_eq!(a.squared_distance_dynamic(&b), a.squared_distance(&b));
+
+            if let (Some(x), Some(y)) = (FixedDescriptor::<16>::from_descriptor(&a), FixedDescriptor::<16>::from_descriptor(&b)) {
+                assert_eq!(x.squared_distance(&y), a.squared_distance(&b));
+                assert_eq!(x.squared_distance_to_bytes(&bytes), a.squared_distance(&b));
+                assert_eq!(x.to_descriptor(), a);
+            }
+
+            let naive: f32 = a.data.iter().zip(b.data.iter()).map(|(x, y)| (x - y) * (x - y)).sum();
+            assert!((a.distance(&b) - naive.sqrt()).abs() < 1e-5);
+        }
+    }
+
 
 
 }
@@ -1,8 +1,17 @@
+//! SMILES and identifiers of every compound, looked up by compound index
+//!
+//! Trees built before the heap format keep each compound in a fixed 258 byte slot, which caps
+//! SMILES at 200 and identifiers at 50 bytes. New databases append variable length entries to a
+//! heap after a 16 byte header, with the end of every entry in an `offsets` file next to it. The
+//! compressed heap packs `COMPRESSED_BLOCK_ENTRIES` entries per zstd frame and keeps the end of
+//! every frame instead. `query` reads all three, telling them apart by the header.
+use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
 use std::io::Write;
-use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
 use std::path::Path;
 use kdam::tqdm;
+use serde::{Serialize, Deserialize};
 
 use rand::Rng;
 
@@ -20,25 +29,128 @@ pub const ID_SIZE: usize = MAX_IDENTIFIER_LENGTH;
 
 pub const DATABASE_ENTRY_SIZE: usize = ENTRIES_SIZE + SMILES_SIZE + ID_SIZE;
 
+const HEAP_MAGIC: &[u8; 8] = b"SSDBHEAP";
+const COMPRESSED_HEAP_MAGIC: &[u8; 8] = b"SSDBZSTD";
+
+///Magic, then the number of entries
+pub const HEAP_HEADER_SIZE: usize = 16;
+
+///Entries per zstd frame in a compressed heap
+pub const COMPRESSED_BLOCK_ENTRIES: usize = 256;
+
+const COMPRESSION_LEVEL: i32 = 3;
+
+const OFFSET_SIZE: usize = 8;
+
+///How a new database lays out its entries. Existing ones are read in whatever format they were
+///written in.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DatabaseFormat {
+    ///Fixed size slots, limited to `MAX_SMILES_LENGTH` and `MAX_IDENTIFIER_LENGTH`
+    Fixed,
+    ///Variable length entries with an offset per entry
+    #[default]
+    Heap,
+    ///Variable length entries compressed in blocks, with an offset per block
+    CompressedHeap,
+}
+
+///Which source datasets (vendors, catalogs) offer a compound: bit `i` is dataset id `i` in the
+///database's dataset list. Zero if the build didn't name any.
+pub type SourceMask = u64;
+
+pub const MAX_DATASETS: usize = 64;
+
+pub const SOURCE_MASK_SIZE: usize = 8;
+
+//Sources, aliases and dataset names live in files next to the entries, so databases written
+//before they existed still open. Their names swap the database file's extension.
+const SOURCES_EXTENSION: &str = "sources";
+const ALIASES_EXTENSION: &str = "aliases";
+const DATASETS_EXTENSION: &str = "datasets";
+const OFFSETS_EXTENSION: &str = "offsets";
+
+fn sidecar_filename(filename: &str, extension: &str) -> String {
+    return Path::new(filename).with_extension(extension).to_string_lossy().to_string();
+}
+
+///Dataset names in id order, or none if the build didn't name any
+fn read_datasets(filename: &str) -> Result<Vec<String>, String> {
+
+    let datasets_filename = sidecar_filename(filename, DATASETS_EXTENSION);
+
+    return match std::fs::read_to_string(&datasets_filename) {
+        Ok(x) => serde_yaml::from_str(&x).map_err(|e| format!("Could not read dataset names from {}: {}", datasets_filename, e)),
+        Err(_) => Ok(Vec::new()),
+    }
+}
+
+///Extra identifiers for merged duplicates, keyed by the index of the record they were merged into.
+///One `index<TAB>dataset<TAB>identifier` line each.
+fn read_aliases(filename: &str) -> HashMap<u64, Vec<CompoundIdentifier>> {
+
+    let mut aliases: HashMap<u64, Vec<CompoundIdentifier>> = HashMap::new();
+
+    let contents = match std::fs::read_to_string(sidecar_filename(filename, ALIASES_EXTENSION)) {
+        Ok(x) => x,
+        Err(_) => return aliases,
+    };
+
+    for line in contents.lines() {
+        let mut fields = line.splitn(3, "\t");
+        let index = fields.next().and_then(|x| x.parse::<u64>().ok());
+        let identifier = fields.nth(1);
+
+        if let (Some(index), Some(identifier)) = (index, identifier) {
+            aliases.entry(index).or_default().push(CompoundIdentifier::from_str(identifier));
+        }
+    }
+
+    return aliases;
+}
+
+///Names of the datasets set in `mask`. Ids without a name are left out.
+pub fn dataset_names(datasets: &[String], mask: SourceMask) -> Vec<String> {
+
+    return datasets.iter()
+        .enumerate()
+        .filter(|(i, _)| mask & (1 << i) != 0)
+        .map(|(_, x)| x.clone())
+        .collect();
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct DatabaseRecord {
     pub smiles: String,
     pub identifier: CompoundIdentifier,
+    pub sources: SourceMask,
+    ///Identifiers of duplicates merged into this record
+    pub aliases: Vec<CompoundIdentifier>,
 }
 
 impl From<CompoundRecord> for DatabaseRecord {
 
     fn from(record: CompoundRecord) -> Self {
 
+        let sources = match record.dataset {
+            Some(x) => 1 << x,
+            None => 0,
+        };
+
         DatabaseRecord {
             smiles: record.smiles,
             identifier: record.compound_identifier,
+            sources,
+            aliases: Vec::new(),
         }
     }
 }
 
 impl DatabaseRecord {
 
+    ///Parses a `smiles,identifier` line. Lengths are only limited by the format it's written to,
+    ///see `to_arr`.
     fn from_line(line: &str) -> Result<Self, String> {
 
         let mut s = line.split(",");
@@ -48,10 +160,6 @@ impl DatabaseRecord {
             Some(s) => s,
             None => panic!("No smiles string found"),
         };
-    
-        if smiles.len() > SMILES_SIZE {
-            return Err("Smiles string too long".to_string());
-        }
 
         let identifier = match s.next() {
 
@@ -59,19 +167,25 @@ impl DatabaseRecord {
             None => panic!("No identifier found"),
         };
 
-        if identifier.len() > ID_SIZE {
-            return Err("Identifier string too long".to_string());
-        }
-
         return Ok(DatabaseRecord {
             smiles: smiles.to_string(),
             identifier: CompoundIdentifier::from_str(identifier),
+            sources: 0,
+            aliases: Vec::new(),
         });
     }
 
 
     fn to_arr(&self) -> Result<[u8; DATABASE_ENTRY_SIZE], String> {
 
+        if self.smiles.len() > SMILES_SIZE {
+            return Err(format!("SMILES longer than {} bytes don't fit a fixed size database", SMILES_SIZE));
+        }
+
+        if self.identifier.to_string().len() > ID_SIZE {
+            return Err(format!("Identifiers longer than {} bytes don't fit a fixed size database", ID_SIZE));
+        }
+
         let mut arr = [0u8; DATABASE_ENTRY_SIZE];
 
         let mut fill_arr = [0u8; SMILES_SIZE];
@@ -113,9 +227,37 @@ impl DatabaseRecord {
         DatabaseRecord {
             smiles: smiles.to_string(),
             identifier: CompoundIdentifier::from_str(identifier),
+            sources: 0,
+            aliases: Vec::new(),
         }
     }
 
+    ///Appends the heap encoding: SMILES length and identifier length as u32, then both strings
+    fn to_heap_bytes(&self, out: &mut Vec<u8>) {
+
+        let identifier = self.identifier.to_string();
+
+        out.extend_from_slice(&(self.smiles.len() as u32).to_le_bytes());
+        out.extend_from_slice(&(identifier.len() as u32).to_le_bytes());
+        out.extend_from_slice(self.smiles.as_bytes());
+        out.extend_from_slice(identifier.as_bytes());
+    }
+
+    fn from_heap_bytes(bytes: &[u8]) -> Result<Self, String> {
+
+        let (smiles_length, identifier_length) = heap_entry_lengths(bytes)?;
+
+        let smiles = &bytes[8..8 + smiles_length];
+        let identifier = &bytes[8 + smiles_length..8 + smiles_length + identifier_length];
+
+        return Ok(DatabaseRecord {
+            smiles: String::from_utf8_lossy(smiles).to_string(),
+            identifier: CompoundIdentifier::from_str(&String::from_utf8_lossy(identifier)),
+            sources: 0,
+            aliases: Vec::new(),
+        });
+    }
+
     fn random() -> Self {
 
         use rand::{distributions::Alphanumeric, Rng};
@@ -133,61 +275,375 @@ impl DatabaseRecord {
         DatabaseRecord {
             smiles: smiles,
             identifier: identifier,
+            sources: 1 << rng.gen_range(0..MAX_DATASETS),
+            aliases: Vec::new(),
         }
     }
 
 }
 
+///SMILES and identifier lengths from the start of a heap entry, checked against what's there
+fn heap_entry_lengths(bytes: &[u8]) -> Result<(usize, usize), String> {
+
+    if bytes.len() < 8 {
+        return Err("Truncated database entry".to_string());
+    }
+
+    let smiles_length = u32::from_le_bytes(bytes[0..4].try_into().unwrap()) as usize;
+    let identifier_length = u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize;
+
+    if bytes.len() < 8 + smiles_length + identifier_length {
+        return Err("Truncated database entry".to_string());
+    }
+
+    return Ok((smiles_length, identifier_length));
+}
+
+///The `n`th entry of a decompressed block
+fn nth_heap_entry(bytes: &[u8], n: usize) -> Result<DatabaseRecord, String> {
+
+    let mut position = 0;
+
+    for _ in 0..n {
+        let (smiles_length, identifier_length) = heap_entry_lengths(&bytes[position..])?;
+        position += 8 + smiles_length + identifier_length;
+    }
+
+    return DatabaseRecord::from_heap_bytes(&bytes[position..]);
+}
+
+///The entries file in any of the formats, read with positioned reads so queries don't need `&mut`
+#[derive(Debug)]
+struct EntryFile {
+    format: DatabaseFormat,
+    fd: File,
+    ///End of each entry (heap) or block (compressed heap)
+    offsets: Option<File>,
+    ///Entries readable from disk. For a compressed heap, only the ones in full blocks.
+    num_entries: u64,
+}
+
+impl EntryFile {
+
+    fn open(filename: &str, writable: bool) -> Result<Self, String> {
+
+        let fd = OpenOptions::new()
+                    .read(true)
+                    .write(writable)
+                    .open(filename)
+                    .map_err(|e| format!("Could not open database {}: {}", filename, e))?;
+
+        //a fixed size database stays empty until its first entry
+        let mut header = [0u8; HEAP_HEADER_SIZE];
+        let header_length = fd.read_at(&mut header, 0).map_err(|e| e.to_string())?;
+
+        let count_at = |start: usize| u64::from_le_bytes(header[start..start + 8].try_into().unwrap());
+
+        let (format, num_entries) = match &header[..8] {
+            x if x == HEAP_MAGIC => (DatabaseFormat::Heap, count_at(8)),
+            x if x == COMPRESSED_HEAP_MAGIC => (DatabaseFormat::CompressedHeap, count_at(8)),
+            _ if header_length < ENTRIES_SIZE => (DatabaseFormat::Fixed, 0),
+            _ => (DatabaseFormat::Fixed, count_at(ENTRIES_START)),
+        };
+
+        let offsets = match format {
+            DatabaseFormat::Fixed => None,
+            _ => {
+                let offsets_filename = sidecar_filename(filename, OFFSETS_EXTENSION);
+                let file = OpenOptions::new()
+                    .read(true)
+                    .write(writable)
+                    .open(&offsets_filename)
+                    .map_err(|e| format!("Could not open database offsets {}: {}", offsets_filename, e))?;
+                Some(file)
+            },
+        };
+
+        return Ok(Self {
+            format,
+            fd,
+            offsets,
+            num_entries,
+        });
+    }
+
+    ///Where entry or block `i` ends
+    fn end_of(&self, i: u64) -> Result<u64, String> {
+
+        let mut buf = [0u8; OFFSET_SIZE];
+
+        let offsets = self.offsets.as_ref().unwrap();
+        offsets.read_exact_at(&mut buf, i * OFFSET_SIZE as u64).map_err(|e| format!("No offset for {}: {}", i, e))?;
+
+        return Ok(u64::from_le_bytes(buf));
+    }
+
+    ///Where entry or block `first` starts, and where each of the `count` from there ends, from
+    ///one read of the offsets
+    fn bounds(&self, first: u64, count: u64) -> Result<(u64, Vec<u64>), String> {
+
+        let read_from = first.saturating_sub(1);
+        let mut buf = vec![0u8; (first + count - read_from) as usize * OFFSET_SIZE];
+
+        let offsets = self.offsets.as_ref().unwrap();
+        offsets.read_exact_at(&mut buf, read_from * OFFSET_SIZE as u64).map_err(|e| format!("No offsets for {}..{}: {}", first, first + count, e))?;
+
+        let mut ends: Vec<u64> = buf.chunks_exact(OFFSET_SIZE).map(|x| u64::from_le_bytes(x.try_into().unwrap())).collect();
+
+        let start = match first {
+            0 => HEAP_HEADER_SIZE as u64,
+            _ => ends.remove(0),
+        };
+
+        return Ok((start, ends));
+    }
+
+    ///Byte range of entry or block `i`
+    fn range(&self, i: u64) -> Result<(u64, u64), String> {
+
+        let (start, ends) = self.bounds(i, 1)?;
+
+        return Ok((start, ends[0]));
+    }
+
+    fn read_range(&self, start: u64, end: u64) -> Result<Vec<u8>, String> {
+
+        let mut buf = vec![0u8; (end - start) as usize];
+        self.fd.read_exact_at(&mut buf, start).map_err(|e| e.to_string())?;
+
+        return Ok(buf);
+    }
+
+    fn read_block(&self, block: u64) -> Result<Vec<u8>, String> {
+
+        let (start, end) = self.range(block)?;
+        let compressed = self.read_range(start, end)?;
+
+        return zstd::stream::decode_all(&compressed[..]).map_err(|e| format!("Could not decompress database block {}: {}", block, e));
+    }
+
+    fn read(&self, id: u64) -> Result<DatabaseRecord, String> {
+
+        if id >= self.num_entries {
+            return Err(format!("No database entry {}", id));
+        }
+
+        return self.read_run(&[id]).map(|mut x| x.pop().unwrap());
+    }
+
+    ///Splits sorted, distinct ids into groups that `read_run` fetches with one read: consecutive
+    ///entries, or entries sharing a compressed block
+    fn runs<'a>(&self, ids: &'a [u64]) -> Vec<&'a [u64]> {
+
+        return match self.format {
+            DatabaseFormat::CompressedHeap => {
+                let block_entries = COMPRESSED_BLOCK_ENTRIES as u64;
+                ids.chunk_by(|a, b| a / block_entries == b / block_entries).collect()
+            },
+            _ => ids.chunk_by(|a, b| *b == a + 1).collect(),
+        }
+    }
+
+    ///Entries for one group from `runs`, in order
+    fn read_run(&self, run: &[u64]) -> Result<Vec<DatabaseRecord>, String> {
+
+        let first = run[0];
+        let count = run.len() as u64;
+
+        return match self.format {
+            DatabaseFormat::Fixed => {
+                let size = DATABASE_ENTRY_SIZE as u64;
+                let bytes = self.read_range(first * size, (first + count) * size)?;
+                Ok(bytes.chunks_exact(DATABASE_ENTRY_SIZE).map(|x| DatabaseRecord::from_arr(x.try_into().unwrap())).collect())
+            },
+            DatabaseFormat::Heap => {
+                let (start, ends) = self.bounds(first, count)?;
+                let bytes = self.read_range(start, *ends.last().unwrap())?;
+
+                let mut position = start;
+                let mut records: Vec<DatabaseRecord> = Vec::with_capacity(run.len());
+                for end in ends.iter() {
+                    records.push(DatabaseRecord::from_heap_bytes(&bytes[(position - start) as usize..(end - start) as usize])?);
+                    position = *end;
+                }
+
+                Ok(records)
+            },
+            DatabaseFormat::CompressedHeap => {
+                let block_entries = COMPRESSED_BLOCK_ENTRIES as u64;
+                let block = self.read_block(first / block_entries)?;
+
+                //ids are sorted, so one pass over the block finds them all
+                let mut position = 0;
+                let mut current = 0;
+                let mut records: Vec<DatabaseRecord> = Vec::with_capacity(run.len());
+                for id in run.iter() {
+                    while current < id % block_entries {
+                        let (smiles_length, identifier_length) = heap_entry_lengths(&block[position..])?;
+                        position += 8 + smiles_length + identifier_length;
+                        current += 1;
+                    }
+                    records.push(DatabaseRecord::from_heap_bytes(&block[position..])?);
+                }
+
+                Ok(records)
+            },
+        }
+    }
+
+    ///Stores the entry count in the header
+    fn write_count(&self, count: u64) -> Result<(), String> {
+
+        let position = match self.format {
+            DatabaseFormat::Fixed => ENTRIES_START,
+            _ => 8,
+        };
+
+        return self.fd.write_all_at(&count.to_le_bytes(), position as u64).map_err(|e| e.to_string());
+    }
+
+    fn write_end(&self, i: u64, end: u64) -> Result<(), String> {
+
+        let offsets = self.offsets.as_ref().unwrap();
+
+        return offsets.write_all_at(&end.to_le_bytes(), i * OFFSET_SIZE as u64).map_err(|e| e.to_string());
+    }
+}
+
 #[derive(Debug)]
 
 pub struct ImmutDatabase {
-    filename: String,
+    entries: EntryFile,
     num_entries: u64,
+    sources: Option<File>,
+    aliases: HashMap<u64, Vec<CompoundIdentifier>>,
+    pub datasets: Vec<String>,
 }
 
 impl ImmutDatabase {
 
     pub fn open(filename: &str) -> Self {
 
-        let path = Path::new(filename);
+        let entries = EntryFile::open(filename, false).unwrap();
 
-        let mut fd = OpenOptions::new()
-                    .create(false)
-                    .read(true)
-                    .write(false)
-                    .truncate(false)
-                    .open(path).unwrap();
+        let sources = File::open(sidecar_filename(filename, SOURCES_EXTENSION)).ok();
+
+        Self {
+            num_entries: entries.num_entries,
+            entries,
+            sources,
+            aliases: read_aliases(filename),
+            datasets: read_datasets(filename).unwrap(),
+        }
+    }
 
-        let mut buf = [0u8; ENTRIES_SIZE];
-        fd.seek(SeekFrom::Start(ENTRIES_START as u64)).unwrap();
-        fd.read(&mut buf).unwrap();
+    pub fn len(&self) -> u64 {
+        return self.num_entries;
+    }
 
-        let num_entries = u64::from_le_bytes(buf);
+    pub fn is_empty(&self) -> bool {
+        return self.num_entries == 0;
+    }
 
-        Self {
-            filename: filename.to_string(),
-            num_entries: num_entries,
+    pub fn format(&self) -> DatabaseFormat {
+        return self.entries.format;
+    }
+
+    ///Datasets offering the compound at `id`
+    pub fn sources(&self, id: &u64) -> SourceMask {
+
+        let file = match &self.sources {
+            Some(x) => x,
+            None => return 0,
+        };
+
+        let mut buf = [0u8; SOURCE_MASK_SIZE];
+
+        return match file.read_exact_at(&mut buf, id * SOURCE_MASK_SIZE as u64) {
+            Ok(_) => u64::from_le_bytes(buf),
+            Err(_) => 0,
+        }
+    }
+
+    ///Mask of the named datasets, for filtering queries by source
+    pub fn dataset_mask(&self, names: &[String]) -> Result<SourceMask, String> {
+
+        let mut mask: SourceMask = 0;
+
+        for name in names.iter() {
+            match self.datasets.iter().position(|x| x == name) {
+                Some(i) => mask |= 1 << i,
+                None => return Err(format!("Unknown dataset {:?}", name)),
+            }
         }
+
+        return Ok(mask);
     }
 
+    ///The entry at `id`, or None if there isn't one
     pub fn query(&self, id: &u64) -> Option<DatabaseRecord> {
 
-        let mut buf = [0u8; DATABASE_ENTRY_SIZE];
+        return self.query_many(&[*id]).pop().unwrap().ok();
+    }
 
-        let mut fd = OpenOptions::new()
-                    .create(false)
-                    .read(true)
-                    .write(false)
-                    .truncate(false)
-                    .open(&self.filename).unwrap();
+    ///Entries for each of `ids`, in the same order. The ids are sorted and fetched in runs of
+    ///consecutive entries, with each compressed block decompressed once. Ids past the end and
+    ///failed reads give an error for just those ids.
+    pub fn query_many(&self, ids: &[u64]) -> Vec<Result<DatabaseRecord, String>> {
 
-            
-        fd.seek(SeekFrom::Start((id) * (DATABASE_ENTRY_SIZE as u64))).unwrap();
-        fd.read(&mut buf).unwrap();
+        let mut sorted: Vec<u64> = ids.iter().filter(|x| **x < self.num_entries).cloned().collect();
+        sorted.sort_unstable();
+        sorted.dedup();
 
-        let entry = DatabaseRecord::from_arr(buf);
+        let mut found: HashMap<u64, Result<DatabaseRecord, String>> = HashMap::with_capacity(sorted.len());
 
-        return Some(entry);
+        for run in self.entries.runs(&sorted) {
+            match self.entries.read_run(run) {
+                Ok(records) => found.extend(run.iter().cloned().zip(records.into_iter().map(Ok))),
+                Err(e) => found.extend(run.iter().map(|id| (*id, Err(format!("Could not read database entry {}: {}", id, e))))),
+            }
+        }
+
+        let sources = self.sources_many(&sorted);
+
+        return ids.iter().map(|id| {
+            let mut entry = match found.get(id) {
+                Some(x) => x.clone()?,
+                None => return Err(format!("No database entry {}, database has {}", id, self.num_entries)),
+            };
+
+            entry.sources = sources.get(id).cloned().unwrap_or(0);
+            entry.aliases = self.aliases.get(id).cloned().unwrap_or_default();
+
+            Ok(entry)
+        }).collect();
+    }
+
+    ///`sources` for sorted, distinct ids, reading runs of consecutive ids at once
+    fn sources_many(&self, ids: &[u64]) -> HashMap<u64, SourceMask> {
+
+        let mut sources: HashMap<u64, SourceMask> = HashMap::with_capacity(ids.len());
+
+        let file = match &self.sources {
+            Some(x) => x,
+            None => return sources,
+        };
+
+        for run in ids.chunk_by(|a, b| *b == a + 1) {
+            let mut buf = vec![0u8; run.len() * SOURCE_MASK_SIZE];
+
+            //a run past the end of a short sources file falls back to one read per id
+            if file.read_exact_at(&mut buf, run[0] * SOURCE_MASK_SIZE as u64).is_err() {
+                sources.extend(run.iter().map(|id| (*id, self.sources(id))));
+                continue
+            }
+
+            for (id, x) in run.iter().zip(buf.chunks_exact(SOURCE_MASK_SIZE)) {
+                sources.insert(*id, u64::from_le_bytes(x.try_into().unwrap()));
+            }
+        }
+
+        return sources;
     }
 }
 
@@ -196,14 +652,30 @@ impl ImmutDatabase {
 #[derive(Debug)]
 pub struct Database {
     filename: String,
-    fd: File,
+    entries: EntryFile,
+    ///Where the next heap entry or block is written
+    end: u64,
+    ///Encoded entries of the compressed heap block being filled. Written out when it's full, and
+    ///rewritten in place on `flush` until then.
+    pending: Vec<u8>,
+    num_pending: u64,
     num_entries: u64,
+    sources: File,
+    aliases_file: File,
+    aliases: HashMap<u64, Vec<CompoundIdentifier>>,
+    datasets: Vec<String>,
 }
 
 impl Database {
 
+    ///A new database in the default format
     pub fn new(filename: &str) -> Self {
 
+        return Self::create(filename, DatabaseFormat::default()).unwrap();
+    }
+
+    pub fn create(filename: &str, format: DatabaseFormat) -> Result<Self, String> {
+
         let path = Path::new(filename);
 
         let mut fd = OpenOptions::new()
@@ -211,38 +683,121 @@ impl Database {
                     .read(true)
                     .write(true)
                     .truncate(true)
-                    .open(path).unwrap();
+                    .open(path)
+                    .map_err(|e| format!("Could not create database {}: {}", filename, e))?;
 
-        Database {
+        let magic = match format {
+            DatabaseFormat::Fixed => None,
+            DatabaseFormat::Heap => Some(HEAP_MAGIC),
+            DatabaseFormat::CompressedHeap => Some(COMPRESSED_HEAP_MAGIC),
+        };
+
+        if let Some(magic) = magic {
+            fd.write_all(magic).map_err(|e| e.to_string())?;
+            fd.write_all(&0u64.to_le_bytes()).map_err(|e| e.to_string())?;
+
+            File::create(sidecar_filename(filename, OFFSETS_EXTENSION)).map_err(|e| e.to_string())?;
+        }
+
+        let sources = OpenOptions::new()
+                    .create(true)
+                    .read(true)
+                    .write(true)
+                    .truncate(true)
+                    .open(sidecar_filename(filename, SOURCES_EXTENSION)).unwrap();
+
+        let aliases_file = OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .truncate(true)
+                    .open(sidecar_filename(filename, ALIASES_EXTENSION)).unwrap();
+
+        return Ok(Database {
             filename: filename.to_string(),
-            fd: fd,
+            entries: EntryFile::open(filename, true)?,
+            end: HEAP_HEADER_SIZE as u64,
+            pending: Vec::new(),
+            num_pending: 0,
             num_entries: 0,
-        }
+            sources,
+            aliases_file,
+            aliases: HashMap::new(),
+            datasets: Vec::new(),
+        });
     }
 
+    ///Opens an existing database to add more entries, in the format it was written in
     pub fn open(filename: &str) -> Self {
 
-        let path = Path::new(filename);
+        let mut entries = EntryFile::open(filename, true).unwrap();
+
+        let num_entries = entries.num_entries;
+
+        let mut end = HEAP_HEADER_SIZE as u64;
+        let mut pending: Vec<u8> = Vec::new();
+        let mut num_pending: u64 = 0;
+
+        match entries.format {
+            DatabaseFormat::Fixed => {},
+            DatabaseFormat::Heap => if num_entries > 0 {
+                end = entries.end_of(num_entries - 1).unwrap();
+            },
+            DatabaseFormat::CompressedHeap => {
+                let block_entries = COMPRESSED_BLOCK_ENTRIES as u64;
+                let num_blocks = num_entries.div_ceil(block_entries);
+
+                if num_blocks > 0 {
+                    end = entries.end_of(num_blocks - 1).unwrap();
+                }
+
+                //keep filling a partial last block rather than leaving a short one in the middle
+                if !num_entries.is_multiple_of(block_entries) {
+                    pending = entries.read_block(num_blocks - 1).unwrap();
+                    num_pending = num_entries % block_entries;
+                    end = entries.range(num_blocks - 1).unwrap().0;
+                    entries.num_entries -= num_pending;
+                }
+            },
+        }
 
-        let mut fd = OpenOptions::new()
-                    .create(false)
+        let sources = OpenOptions::new()
+                    .create(true)
                     .read(true)
-                    .write(false)
+                    .write(true)
                     .truncate(false)
-                    .open(path).unwrap();
-
-        let mut buf = [0u8; ENTRIES_SIZE];
-        fd.seek(SeekFrom::Start(ENTRIES_START as u64)).unwrap();
-        fd.read(&mut buf).unwrap();
+                    .open(sidecar_filename(filename, SOURCES_EXTENSION)).unwrap();
 
-        let num_entries = u64::from_le_bytes(buf);
+        let aliases_file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(sidecar_filename(filename, ALIASES_EXTENSION)).unwrap();
 
         Database {
             filename: filename.to_string(),
-            fd: fd,
-            num_entries: num_entries,
+            entries,
+            end,
+            pending,
+            num_pending,
+            num_entries,
+            sources,
+            aliases_file,
+            aliases: read_aliases(filename),
+            datasets: read_datasets(filename).unwrap(),
         }
     }
+    ///Number of entries written so far, which is also the index the next one gets
+    pub fn len(&self) -> u64 {
+        return self.num_entries;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.num_entries == 0;
+    }
+
+    pub fn format(&self) -> DatabaseFormat {
+        return self.entries.format;
+    }
+
     pub fn add_compound_record(&mut self, entry: &CompoundRecord) -> Result<u64, String> {
 
         let database_record = DatabaseRecord::from(entry.clone());
@@ -250,36 +805,155 @@ impl Database {
         return self.add_entry(&database_record);
     }
 
-    fn add_entry(&mut self, entry: &DatabaseRecord) -> Result<u64, String> {
+    pub fn datasets(&self) -> &[String] {
+        return &self.datasets;
+    }
 
-        let arr = entry.to_arr();
+    ///Id of the dataset called `name`, adding it to the list if it's new
+    pub fn dataset_id(&mut self, name: &str) -> Result<u8, String> {
 
-        let arr = match arr {
-            Ok(arr) => arr,
-            Err(e) => return Err(e),
+        if let Some(i) = self.datasets.iter().position(|x| x == name) {
+            return Ok(i as u8);
+        }
+
+        if self.datasets.len() >= MAX_DATASETS {
+            return Err(format!("Can't add dataset {:?}, already have {}", name, MAX_DATASETS));
+        }
+
+        self.datasets.push(name.to_string());
+
+        let serialized = serde_yaml::to_string(&self.datasets).unwrap();
+        std::fs::write(sidecar_filename(&self.filename, DATASETS_EXTENSION), serialized).map_err(|e| e.to_string())?;
+
+        return Ok((self.datasets.len() - 1) as u8);
+    }
+
+    ///Records `identifier` from `dataset` as another name for the compound at `id`, e.g. when a
+    ///duplicate is merged into it
+    pub fn add_alias(&mut self, id: &u64, identifier: &CompoundIdentifier, dataset: Option<u8>) -> Result<(), String> {
+
+        if *id >= self.num_entries {
+            return Err(format!("No entry {} to alias", id));
+        }
+
+        let dataset_field = match dataset {
+            Some(x) => x.to_string(),
+            None => "".to_string(),
         };
 
-        self.fd.seek(SeekFrom::Start((self.num_entries as u64) * (DATABASE_ENTRY_SIZE as u64))).unwrap();
-        self.fd.write(&arr).unwrap();
+        let line = format!("{}\t{}\t{}\n", id, dataset_field, identifier.to_string());
+        self.aliases_file.write_all(line.as_bytes()).map_err(|e| e.to_string())?;
+        self.aliases.entry(*id).or_default().push(identifier.clone());
+
+        if let Some(x) = dataset {
+            let sources = self.sources(id) | (1 << x);
+            self.write_sources(id, sources)?;
+        }
+
+        Ok(())
+    }
+
+    ///Datasets offering the compound at `id`
+    pub fn sources(&self, id: &u64) -> SourceMask {
+
+        let mut buf = [0u8; SOURCE_MASK_SIZE];
+
+        return match self.sources.read_exact_at(&mut buf, id * SOURCE_MASK_SIZE as u64) {
+            Ok(_) => u64::from_le_bytes(buf),
+            Err(_) => 0,
+        }
+    }
+
+    fn write_sources(&mut self, id: &u64, sources: SourceMask) -> Result<(), String> {
+
+        return self.sources.write_all_at(&sources.to_le_bytes(), id * SOURCE_MASK_SIZE as u64).map_err(|e| e.to_string());
+    }
+
+    fn add_entry(&mut self, entry: &DatabaseRecord) -> Result<u64, String> {
 
         let return_idx = self.num_entries;
 
+        match self.entries.format {
+            DatabaseFormat::Fixed => {
+                let arr = entry.to_arr()?;
+                self.entries.fd.write_all_at(&arr, return_idx * DATABASE_ENTRY_SIZE as u64).map_err(|e| e.to_string())?;
+            },
+            DatabaseFormat::Heap => {
+                let mut bytes: Vec<u8> = Vec::new();
+                entry.to_heap_bytes(&mut bytes);
+
+                self.entries.fd.write_all_at(&bytes, self.end).map_err(|e| e.to_string())?;
+                self.end += bytes.len() as u64;
+                self.entries.write_end(return_idx, self.end)?;
+            },
+            DatabaseFormat::CompressedHeap => {
+                entry.to_heap_bytes(&mut self.pending);
+                self.num_pending += 1;
+            },
+        }
+
+        self.write_sources(&return_idx, entry.sources)?;
+
         self.num_entries += 1;
 
-        self.fd.seek(SeekFrom::Start(ENTRIES_START as u64)).unwrap();
-        self.fd.write(&(self.num_entries as u64).to_le_bytes()).unwrap();
+        match self.entries.format {
+            DatabaseFormat::CompressedHeap => if self.num_pending == COMPRESSED_BLOCK_ENTRIES as u64 {
+                self.write_pending_block()?;
+                self.end = self.entries.end_of(self.num_entries / COMPRESSED_BLOCK_ENTRIES as u64 - 1)?;
+                self.entries.num_entries = self.num_entries;
+                self.pending.clear();
+                self.num_pending = 0;
+            },
+            _ => {
+                self.entries.num_entries = self.num_entries;
+                self.entries.write_count(self.num_entries)?;
+            },
+        }
 
         return Ok(return_idx);
     }
 
+    ///Compresses the block being filled and writes it at `end`, replacing any earlier copy
+    fn write_pending_block(&mut self) -> Result<(), String> {
+
+        let compressed = zstd::stream::encode_all(&self.pending[..], COMPRESSION_LEVEL).map_err(|e| e.to_string())?;
+
+        let block = self.entries.num_entries / COMPRESSED_BLOCK_ENTRIES as u64;
+        let block_end = self.end + compressed.len() as u64;
+
+        self.entries.fd.write_all_at(&compressed, self.end).map_err(|e| e.to_string())?;
+        self.entries.fd.set_len(block_end).map_err(|e| e.to_string())?;
+        self.entries.write_end(block, block_end)?;
+
+        self.entries.offsets.as_ref().unwrap().set_len((block + 1) * OFFSET_SIZE as u64).map_err(|e| e.to_string())?;
+
+        return self.entries.write_count(self.num_entries);
+    }
+
+    ///Writes out a partly filled block of a compressed heap, so every entry so far can be read
+    pub fn flush(&mut self) -> Result<(), String> {
+
+        if self.num_pending > 0 {
+            self.write_pending_block()?;
+        }
+
+        Ok(())
+    }
+
     pub fn query(&mut self, id: &u64) -> Option<DatabaseRecord> {
 
-        let mut buf = [0u8; DATABASE_ENTRY_SIZE];
-            
-        self.fd.seek(SeekFrom::Start((id) * (DATABASE_ENTRY_SIZE as u64))).unwrap();
-        self.fd.read(&mut buf).unwrap();
+        if *id >= self.num_entries {
+            return None;
+        }
+
+        let entry = match *id >= self.entries.num_entries {
+            true => nth_heap_entry(&self.pending, (*id - self.entries.num_entries) as usize),
+            false => self.entries.read(*id),
+        };
 
-        let entry = DatabaseRecord::from_arr(buf);
+        let mut entry = entry.ok()?;
+        entry.sources = self.sources(id);
+        entry.aliases = self.aliases.get(id).cloned().unwrap_or_default();
 
         return Some(entry);
     }
@@ -387,5 +1061,137 @@ mod tests {
         let mut database = Database::open(filename);
     }
 
+    #[test]
+    fn quick_variable_length_entries() {
+
+        let long_entry = |i: usize| DatabaseRecord {
+            smiles: "C".repeat(40 + (i * 7) % 400),
+            identifier: CompoundIdentifier::from_string(format!("{}-{}", "ID".repeat(i % 40), i)),
+            sources: 0,
+            aliases: Vec::new(),
+        };
+
+        for format in [DatabaseFormat::Heap, DatabaseFormat::CompressedHeap] {
+
+            let filename = format!("/tmp/quick_variable_length_{:?}.db", format);
+
+            let mut database = Database::create(&filename, format).unwrap();
+            let entries: Vec<DatabaseRecord> = (0..1000).map(long_entry).collect();
+
+            for entry in entries[..600].iter() {
+                database.add_entry(entry).unwrap();
+            }
+
+            //readable before anything is flushed, including a compressed block still being filled
+            assert_eq!(database.query(&599).unwrap(), entries[599]);
+            assert_eq!(database.query(&0).unwrap(), entries[0]);
+            database.flush().unwrap();
+
+            //reopen and keep appending into the partly filled block
+            let mut database = Database::open(&filename);
+            assert_eq!(database.len(), 600);
+            assert_eq!(database.format(), format);
+            for entry in entries[600..].iter() {
+                database.add_entry(entry).unwrap();
+            }
+            assert!(database.query(&1000).is_none());
+            database.flush().unwrap();
+
+            let database = ImmutDatabase::open(&filename);
+            assert_eq!(database.len(), 1000);
+            for (i, entry) in entries.iter().enumerate() {
+                assert_eq!(database.query(&(i as u64)).unwrap(), *entry);
+            }
+            assert!(database.query(&1000).is_none());
+        }
+
+        //old fixed size databases still read, and turn away what doesn't fit
+        let filename = "/tmp/quick_variable_length_fixed.db";
+        let mut database = Database::create(filename, DatabaseFormat::Fixed).unwrap();
+        database.add_entry(&long_entry(0)).unwrap();
+        assert!(database.add_entry(&long_entry(30)).is_err());
+        assert!(database.add_entry(&long_entry(1)).is_ok());
+
+        let database = ImmutDatabase::open(filename);
+        assert_eq!(database.format(), DatabaseFormat::Fixed);
+        assert_eq!(database.len(), 2);
+        assert_eq!(database.query(&1).unwrap(), long_entry(1));
+
+        //parsing doesn't limit lengths, only writing a fixed size entry does
+        let line = format!("{},{}", "C".repeat(300), "ID".repeat(40));
+        let entry = DatabaseRecord::from_line(&line).unwrap();
+        assert_eq!(entry.smiles.len(), 300);
+        assert!(entry.to_arr().is_err());
+
+        let mut database = Database::create("/tmp/quick_variable_length_parsed.db", DatabaseFormat::Heap).unwrap();
+        database.add_entry(&entry).unwrap();
+        assert_eq!(database.query(&0).unwrap(), entry);
+    }
+
+
+
+    #[test]
+    fn quick_query_many() {
+
+        for format in [DatabaseFormat::Fixed, DatabaseFormat::Heap, DatabaseFormat::CompressedHeap] {
+
+            let filename = format!("/tmp/quick_query_many_{:?}.db", format);
+
+            let mut database = Database::create(&filename, format).unwrap();
+            let entries: Vec<DatabaseRecord> = (0..2000).map(|_| DatabaseRecord::random()).collect();
+            for entry in entries.iter() {
+                database.add_entry(entry).unwrap();
+            }
+            database.add_alias(&5, &CompoundIdentifier::from_str("ALIAS"), None).unwrap();
+            database.flush().unwrap();
+            assert_eq!(database.query(&5).unwrap().aliases, vec![CompoundIdentifier::from_str("ALIAS")]);
+
+            //aliases from before a reopen are still returned by the write handle
+            let mut database = Database::open(&filename);
+            assert_eq!(database.query(&5).unwrap().aliases, vec![CompoundIdentifier::from_str("ALIAS")]);
+            assert!(database.query(&6).unwrap().aliases.is_empty());
+
+            let database = ImmutDatabase::open(&filename);
+
+            //unsorted, with repeats, runs of neighbors, a block boundary and ids past the end
+            let mut ids: Vec<u64> = vec![1999, 5, 6, 7, 5, 255, 256, 2000, 0, 1234, 99999, 1233];
+            ids.extend((0..200).map(|_| rand::thread_rng().gen_range(0..2000)));
+
+            let results = database.query_many(&ids);
+            assert_eq!(results.len(), ids.len());
+
+            for (id, result) in ids.iter().zip(results.iter()) {
+                match *id < 2000 {
+                    true => {
+                        let entry = result.as_ref().unwrap();
+                        assert_eq!(entry.smiles, entries[*id as usize].smiles);
+                        assert_eq!(entry.identifier, entries[*id as usize].identifier);
+                        assert_eq!(entry.sources, entries[*id as usize].sources);
+                        assert_eq!(Some(entry.clone()), database.query(id));
+                    },
+                    false => assert!(result.is_err()),
+                }
+            }
+
+            assert_eq!(results[1].as_ref().unwrap().aliases, vec![CompoundIdentifier::from_str("ALIAS")]);
+            assert!(database.query_many(&[]).is_empty());
+        }
+    }
+
+    #[test]
+    fn quick_corrupt_datasets() {
+
+        let filename = "/tmp/quick_corrupt_datasets.db";
+        let mut database = Database::create(filename, DatabaseFormat::Heap).unwrap();
+        database.add_entry(&DatabaseRecord::random()).unwrap();
+
+        std::fs::write(sidecar_filename(filename, DATASETS_EXTENSION), "{ not: [a list").unwrap();
+
+        let err = read_datasets(filename).unwrap_err();
+        assert!(err.contains("quick_corrupt_datasets.datasets"));
+
+        std::fs::remove_file(sidecar_filename(filename, DATASETS_EXTENSION)).unwrap();
+        assert!(read_datasets(filename).unwrap().is_empty());
+    }
 
 }
@@ -0,0 +1,607 @@
+//! How descriptor components are stored in record pages
+//!
+//! `F32` is the original layout. `F16` and `Int8` shrink records 2-4x so more of them fit on a
+//! page, at the cost of precision. `Pq` stores one byte per group of dimensions and approximates
+//! distances with per-query lookup tables. The lossy encodings can keep the original vectors in a
+//! side file so query candidates can be re-ranked with exact distances.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+
+use half::f16;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use serde::{Serialize, Deserialize};
+
+use crate::data::{Descriptor, QueryKernel, squared_distance_to_bytes};
+use crate::layout;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DescriptorEncoding {
+    ///4 byte big endian floats
+    #[default]
+    F32,
+    ///2 byte big endian half precision floats
+    F16,
+    ///1 byte per component, linearly mapped onto each dimension's [min, max]
+    Int8,
+    ///Product quantization, 1 byte per subspace indexing a trained codebook
+    Pq,
+}
+
+impl DescriptorEncoding {
+
+    pub fn is_lossy(&self) -> bool {
+        return *self != DescriptorEncoding::F32;
+    }
+}
+
+///Per-dimension bounds for `Int8` storage. Values outside the bounds are clamped.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScalarQuantizer {
+    pub mins: Vec<f32>,
+    pub maxs: Vec<f32>,
+}
+
+impl ScalarQuantizer {
+
+    ///Bounds that contain nothing yet, to be widened with `update`
+    pub fn empty(length: usize) -> Self {
+        return Self {
+            mins: vec![f32::MAX; length],
+            maxs: vec![f32::MIN; length],
+        }
+    }
+
+    pub fn update(&mut self, descriptor: &Descriptor) {
+
+        for (i, value) in descriptor.data.iter().enumerate() {
+            self.mins[i] = self.mins[i].min(*value);
+            self.maxs[i] = self.maxs[i].max(*value);
+        }
+    }
+
+    fn scale(&self, i: usize) -> f32 {
+
+        let range = self.maxs[i] - self.mins[i];
+
+        return match range > 0.0 {
+            true => range / 255.0,
+            false => 1.0,
+        }
+    }
+
+    pub fn encode_value(&self, i: usize, value: f32) -> u8 {
+
+        let code = ((value - self.mins[i]) / self.scale(i)).round();
+
+        return code.clamp(0.0, 255.0) as u8;
+    }
+
+    pub fn decode_value(&self, i: usize, code: u8) -> f32 {
+
+        return self.mins[i] + (code as f32) * self.scale(i);
+    }
+}
+
+///Number of centroids per subspace, so a code fits in a byte
+pub const PQ_CENTROIDS: usize = 256;
+
+///Codebook for `Pq` storage
+///
+///Descriptors are cut into `subspaces` equal slices and each slice is stored as the index of its
+///nearest centroid.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProductQuantizer {
+    pub subspaces: usize,
+    pub subspace_length: usize,
+    ///Per subspace, `PQ_CENTROIDS` or fewer centroids laid out one after another
+    pub centroids: Vec<Vec<f32>>,
+}
+
+impl ProductQuantizer {
+
+    ///k-means on each subspace of `samples`
+    pub fn train(samples: &[Descriptor], subspaces: usize, iterations: usize, seed: u64) -> Result<Self, String> {
+
+        if samples.is_empty() {
+            return Err("No samples to train the product quantizer on".to_string());
+        }
+
+        let length = samples[0].length;
+
+        if subspaces == 0 || !length.is_multiple_of(subspaces) {
+            return Err(format!("desc_length {} is not divisible into {} subspaces", length, subspaces));
+        }
+
+        let subspace_length = length / subspaces;
+        let num_centroids = PQ_CENTROIDS.min(samples.len());
+
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        let mut centroids: Vec<Vec<f32>> = Vec::with_capacity(subspaces);
+
+        for s in 0..subspaces {
+
+            let start = s * subspace_length;
+            let slice = |x: &Descriptor| x.data[start..start + subspace_length].to_vec();
+
+            //start from distinct random samples
+            let mut order: Vec<usize> = (0..samples.len()).collect();
+            for i in 0..num_centroids {
+                let j = rng.gen_range(i..order.len());
+                order.swap(i, j);
+            }
+
+            let mut these: Vec<f32> = Vec::with_capacity(num_centroids * subspace_length);
+            for i in 0..num_centroids {
+                these.extend(slice(&samples[order[i]]));
+            }
+
+            for _ in 0..iterations {
+
+                let mut sums = vec![0.0f64; num_centroids * subspace_length];
+                let mut counts = vec![0usize; num_centroids];
+
+                for sample in samples.iter() {
+                    let sub = &sample.data[start..start + subspace_length];
+                    let c = nearest_centroid(&these, subspace_length, sub);
+
+                    counts[c] += 1;
+                    for j in 0..subspace_length {
+                        sums[c * subspace_length + j] += sub[j] as f64;
+                    }
+                }
+
+                //empty clusters keep their old centroid
+                for c in 0..num_centroids {
+                    if counts[c] == 0 {
+                        continue;
+                    }
+                    for j in 0..subspace_length {
+                        these[c * subspace_length + j] = (sums[c * subspace_length + j] / counts[c] as f64) as f32;
+                    }
+                }
+            }
+
+            centroids.push(these);
+        }
+
+        return Ok(Self {
+            subspaces,
+            subspace_length,
+            centroids,
+        });
+    }
+
+    pub fn from_file(filename: &str) -> Result<Self, String> {
+
+        let serialized = std::fs::read_to_string(filename)
+            .map_err(|e| format!("Could not read codebook {}: {}", filename, e))?;
+
+        return serde_yaml::from_str(&serialized).map_err(|e| e.to_string());
+    }
+
+    pub fn to_file(&self, filename: &str) -> Result<(), String> {
+
+        let serialized = serde_yaml::to_string(&self).map_err(|e| e.to_string())?;
+
+        return std::fs::write(filename, serialized).map_err(|e| e.to_string());
+    }
+
+    fn encode_subspace(&self, s: usize, sub: &[f32]) -> u8 {
+
+        return nearest_centroid(&self.centroids[s], self.subspace_length, sub) as u8;
+    }
+
+    fn decode_value(&self, i: usize, codes: &[u8]) -> f32 {
+
+        let s = i / self.subspace_length;
+
+        return self.centroids[s][codes[s] as usize * self.subspace_length + i % self.subspace_length];
+    }
+
+    ///Squared distance from `query` to every centroid, `PQ_CENTROIDS` entries per subspace
+    fn distance_table(&self, query: &[f32]) -> Vec<f32> {
+
+        let mut table = vec![f32::MAX; self.subspaces * PQ_CENTROIDS];
+
+        for s in 0..self.subspaces {
+            let sub = &query[s * self.subspace_length..(s + 1) * self.subspace_length];
+
+            for (c, centroid) in self.centroids[s].chunks_exact(self.subspace_length).enumerate() {
+                let mut sum: f32 = 0.0;
+                for j in 0..self.subspace_length {
+                    let d = sub[j] - centroid[j];
+                    sum += d * d;
+                }
+                table[s * PQ_CENTROIDS + c] = sum;
+            }
+        }
+
+        return table;
+    }
+}
+
+///Index of the centroid in the flat `centroids` closest to `sub`
+fn nearest_centroid(centroids: &[f32], subspace_length: usize, sub: &[f32]) -> usize {
+
+    let mut best = 0;
+    let mut best_dist = f32::MAX;
+
+    for (c, centroid) in centroids.chunks_exact(subspace_length).enumerate() {
+        let mut sum: f32 = 0.0;
+        for j in 0..subspace_length {
+            let d = sub[j] - centroid[j];
+            sum += d * d;
+        }
+        if sum < best_dist {
+            best_dist = sum;
+            best = c;
+        }
+    }
+
+    return best;
+}
+
+///A query set up for scanning pages of a particular codec
+#[derive(Debug, Clone)]
+pub struct PreparedQuery {
+    pub descriptor: Descriptor,
+    ///Distance loop for `F32` records, picked by the descriptor length
+    kernel: QueryKernel,
+    ///`Pq` distances to every centroid
+    table: Option<Vec<f32>>,
+}
+
+///Everything needed to read and write descriptors in a record page
+#[derive(Debug, Clone)]
+pub struct DescriptorCodec {
+    pub encoding: DescriptorEncoding,
+    pub length: usize,
+    quantizer: Option<Arc<ScalarQuantizer>>,
+    product_quantizer: Option<Arc<ProductQuantizer>>,
+}
+
+impl DescriptorCodec {
+
+    ///The original full precision layout
+    pub fn f32(length: usize) -> Self {
+        return Self {
+            encoding: DescriptorEncoding::F32,
+            length,
+            quantizer: None,
+            product_quantizer: None,
+        }
+    }
+
+    pub fn new(encoding: DescriptorEncoding, length: usize, quantizer: Option<ScalarQuantizer>, product_quantizer: Option<ProductQuantizer>) -> Result<Self, String> {
+
+        if encoding == DescriptorEncoding::Int8 {
+            match &quantizer {
+                None => return Err("int8 descriptor encoding requires quantizer bounds".to_string()),
+                Some(q) if q.mins.len() != length || q.maxs.len() != length => {
+                    return Err(format!("quantizer bounds have {} dimensions, descriptors have {}", q.mins.len(), length));
+                },
+                Some(_) => {},
+            }
+        }
+
+        if encoding == DescriptorEncoding::Pq {
+            match &product_quantizer {
+                None => return Err("pq descriptor encoding requires a trained codebook".to_string()),
+                Some(q) if q.subspaces * q.subspace_length != length => {
+                    return Err(format!("codebook covers {} dimensions, descriptors have {}", q.subspaces * q.subspace_length, length));
+                },
+                Some(_) => {},
+            }
+        }
+
+        return Ok(Self {
+            encoding,
+            length,
+            quantizer: quantizer.map(Arc::new),
+            product_quantizer: product_quantizer.map(Arc::new),
+        });
+    }
+
+    pub fn descriptor_size(&self) -> usize {
+        return match self.encoding {
+            DescriptorEncoding::F32 => self.length * 4,
+            DescriptorEncoding::F16 => self.length * 2,
+            DescriptorEncoding::Int8 => self.length,
+            DescriptorEncoding::Pq => self.product_quantizer.as_ref().unwrap().subspaces,
+        }
+    }
+
+    ///Size of a whole `TreeRecord` in a page, index included
+    pub fn record_size(&self) -> usize {
+        return layout::INDEX_SIZE + self.descriptor_size();
+    }
+
+    pub fn encode_into(&self, descriptor: &Descriptor, out: &mut [u8]) {
+
+        if self.encoding == DescriptorEncoding::Pq {
+            let pq = self.product_quantizer.as_ref().unwrap();
+            for (s, code) in out.iter_mut().take(pq.subspaces).enumerate() {
+                *code = pq.encode_subspace(s, &descriptor.data[s * pq.subspace_length..(s + 1) * pq.subspace_length]);
+            }
+            return;
+        }
+
+        for (i, value) in descriptor.data.iter().take(self.length).enumerate() {
+            match self.encoding {
+                DescriptorEncoding::F32 => out[i * 4..i * 4 + 4].copy_from_slice(&value.to_be_bytes()),
+                DescriptorEncoding::F16 => out[i * 2..i * 2 + 2].copy_from_slice(&f16::from_f32(*value).to_be_bytes()),
+                DescriptorEncoding::Int8 => out[i] = self.quantizer.as_ref().unwrap().encode_value(i, *value),
+                DescriptorEncoding::Pq => unreachable!(),
+            }
+        }
+    }
+
+    pub fn decode(&self, bytes: &[u8]) -> Descriptor {
+
+        let mut descriptor = Descriptor { data: Vec::with_capacity(self.length), length: self.length };
+        self.decode_into(bytes, &mut descriptor);
+
+        return descriptor;
+    }
+
+    ///`decode`, reusing `out`'s allocation
+    pub fn decode_into(&self, bytes: &[u8], out: &mut Descriptor) {
+
+        out.data.clear();
+
+        for i in 0..self.length {
+            out.data.push(self.decode_value(bytes, i));
+        }
+
+        out.length = self.length;
+    }
+
+    fn decode_value(&self, bytes: &[u8], i: usize) -> f32 {
+
+        return match self.encoding {
+            DescriptorEncoding::F32 => f32::from_be_bytes(bytes[i * 4..i * 4 + 4].try_into().unwrap()),
+            DescriptorEncoding::F16 => f16::from_be_bytes(bytes[i * 2..i * 2 + 2].try_into().unwrap()).to_f32(),
+            DescriptorEncoding::Int8 => self.quantizer.as_ref().unwrap().decode_value(i, bytes[i]),
+            DescriptorEncoding::Pq => self.product_quantizer.as_ref().unwrap().decode_value(i, bytes),
+        }
+    }
+
+    ///The descriptor as it will read back after being stored
+    ///
+    ///Records are rounded before they are inserted, so splits are always made on the stored values.
+    pub fn round_trip(&self, descriptor: &Descriptor) -> Descriptor {
+
+        if !self.encoding.is_lossy() {
+            return descriptor.clone();
+        }
+
+        let mut bytes = vec![0u8; self.descriptor_size()];
+        self.encode_into(descriptor, &mut bytes);
+
+        return self.decode(&bytes);
+    }
+
+    ///Does the per-query work for `prepared_squared_distance`, once per query rather than once per record
+    pub fn prepare(&self, query: &Descriptor) -> PreparedQuery {
+
+        let table = match self.encoding {
+            DescriptorEncoding::Pq => Some(self.product_quantizer.as_ref().unwrap().distance_table(&query.data)),
+            _ => None,
+        };
+
+        return PreparedQuery {
+            descriptor: query.clone(),
+            kernel: QueryKernel::new(query),
+            table,
+        };
+    }
+
+    ///Same as `squared_distance_to_bytes`, but `Pq` distances are table lookups
+    pub fn prepared_squared_distance(&self, query: &PreparedQuery, bytes: &[u8]) -> f32 {
+
+        return match &query.table {
+            Some(table) => {
+                let mut sum: f32 = 0.0;
+                for (s, code) in bytes.iter().enumerate() {
+                    sum += table[s * PQ_CENTROIDS + *code as usize];
+                }
+                sum
+            },
+            None if self.encoding == DescriptorEncoding::F32 => query.kernel.squared_distance_to_bytes(bytes),
+            None => self.squared_distance_to_bytes(&query.descriptor.data, bytes),
+        }
+    }
+
+    ///Squared distance from `query` to an encoded descriptor, without building a `Descriptor`
+    pub fn squared_distance_to_bytes(&self, query: &[f32], bytes: &[u8]) -> f32 {
+
+        if self.encoding == DescriptorEncoding::F32 {
+            return squared_distance_to_bytes(query, bytes);
+        }
+
+        let mut sum: f32 = 0.0;
+        for (i, q) in query.iter().take(self.length).enumerate() {
+            let d = q - self.decode_value(bytes, i);
+            sum += d * d;
+        }
+
+        return sum;
+    }
+}
+
+///Full precision descriptors for lossy trees, stored as big endian f32s in compound index order
+#[derive(Debug)]
+pub struct VectorStore {
+    file: File,
+    writer: Option<BufWriter<File>>,
+    length: usize,
+    num_vectors: u64,
+}
+
+impl VectorStore {
+
+    pub fn create(filename: &str, length: usize) -> Result<Self, String> {
+
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(filename)
+            .map_err(|e| format!("Could not create vector file {}: {}", filename, e))?;
+
+        let writer = BufWriter::new(file.try_clone().map_err(|e| e.to_string())?);
+
+        return Ok(Self {
+            file,
+            writer: Some(writer),
+            length,
+            num_vectors: 0,
+        });
+    }
+
+    pub fn open(filename: &str, length: usize) -> Result<Self, String> {
+
+        let file = File::open(filename)
+            .map_err(|e| format!("Could not open vector file {}: {}", filename, e))?;
+
+        let file_length = file.metadata().map_err(|e| e.to_string())?.len();
+
+        return Ok(Self {
+            file,
+            writer: None,
+            length,
+            num_vectors: file_length / (length * 4) as u64,
+        });
+    }
+
+    ///Opens an existing file to keep adding vectors to it
+    pub fn open_append(filename: &str, length: usize) -> Result<Self, String> {
+
+        let mut store = Self::open(filename, length)?;
+
+        let file = OpenOptions::new()
+            .append(true)
+            .open(filename)
+            .map_err(|e| format!("Could not open vector file {}: {}", filename, e))?;
+
+        store.writer = Some(BufWriter::new(file));
+
+        return Ok(store);
+    }
+
+    pub fn len(&self) -> u64 {
+        return self.num_vectors;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.num_vectors == 0;
+    }
+
+    ///Vectors have to be appended in compound index order
+    pub fn append(&mut self, index: u64, descriptor: &Descriptor) -> Result<(), String> {
+
+        if index != self.num_vectors {
+            return Err(format!("Vector for compound {} written out of order, expected {}", index, self.num_vectors));
+        }
+
+        let writer = match &mut self.writer {
+            Some(x) => x,
+            None => return Err("Vector file was opened read only".to_string()),
+        };
+
+        for value in descriptor.data.iter().take(self.length) {
+            writer.write_all(&value.to_be_bytes()).map_err(|e| e.to_string())?;
+        }
+
+        self.num_vectors += 1;
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), String> {
+
+        if let Some(writer) = &mut self.writer {
+            writer.flush().map_err(|e| e.to_string())?;
+        }
+
+        Ok(())
+    }
+
+    pub fn get(&self, index: u64) -> Result<Descriptor, String> {
+
+        if index >= self.num_vectors {
+            return Err(format!("No stored vector for compound {}", index));
+        }
+
+        let mut bytes = vec![0u8; self.length * 4];
+        let offset = index * bytes.len() as u64;
+        self.file.read_exact_at(&mut bytes, offset).map_err(|e| e.to_string())?;
+
+        let data: Vec<f32> = bytes.chunks_exact(4).map(|x| f32::from_be_bytes(x.try_into().unwrap())).collect();
+
+        return Ok(Descriptor { data, length: self.length });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn quick_codecs_round_trip() {
+
+        let length = 16;
+        let descriptors: Vec<Descriptor> = (0..100).map(|_| Descriptor::random(length)).collect();
+
+        let mut quantizer = ScalarQuantizer::empty(length);
+        for descriptor in descriptors.iter() {
+            quantizer.update(descriptor);
+        }
+
+        let product_quantizer = ProductQuantizer::train(&descriptors, 4, 10, 0).unwrap();
+
+        let cases = [
+            (DescriptorEncoding::F32, 0.0, length * 4),
+            (DescriptorEncoding::F16, 1e-3, length * 2),
+            (DescriptorEncoding::Int8, 1e-2, length),
+            (DescriptorEncoding::Pq, 2.0, 4),
+        ];
+
+        for (encoding, tolerance, size) in cases {
+
+            let codec = DescriptorCodec::new(encoding, length, Some(quantizer.clone()), Some(product_quantizer.clone())).unwrap();
+            assert_eq!(codec.descriptor_size(), size);
+
+            for descriptor in descriptors.iter() {
+
+                let mut bytes = vec![0u8; codec.descriptor_size()];
+                codec.encode_into(descriptor, &mut bytes);
+
+                let decoded = codec.decode(&bytes);
+                for i in 0..length {
+                    assert!((decoded.data[i] - descriptor.data[i]).abs() <= tolerance);
+                }
+
+                //stored values are stable once rounded
+                assert_eq!(codec.round_trip(&decoded), decoded);
+
+                let query = Descriptor::random(length);
+                let expected = query.squared_distance(&decoded);
+                assert!((codec.squared_distance_to_bytes(&query.data, &bytes) - expected).abs() < 1e-4);
+                assert!((codec.prepared_squared_distance(&codec.prepare(&query), &bytes) - expected).abs() < 1e-4);
+            }
+        }
+
+        assert!(DescriptorCodec::new(DescriptorEncoding::Int8, length, None, None).is_err());
+        assert!(DescriptorCodec::new(DescriptorEncoding::Pq, length, None, None).is_err());
+        assert!(ProductQuantizer::train(&descriptors, 5, 10, 0).is_err());
+    }
+}
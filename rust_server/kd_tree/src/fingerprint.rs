@@ -0,0 +1,788 @@
+//! Bit vector fingerprints searched by Tanimoto similarity
+//!
+//! The kd tree stops beating brute force somewhere past 20 dimensions, so 1024-2048 bit Morgan/ECFP
+//! fingerprints get their own index. Fingerprints are stored flat, grouped by popcount. Since
+//! Tanimoto(a, b) <= min(|a|, |b|) / max(|a|, |b|), a query scans the popcount groups closest to
+//! its own first and stops once that bound can't beat the k-th best hit.
+//!
+//! Selected with `index_type: fingerprint` in the `TreeConfig`, where `desc_length` is the number
+//! of bits. Compound SMILES and identifiers go in the same `Database` as a kd tree, and results
+//! come back as `NearestNeighbors` with Tanimoto distances (1 - similarity).
+//!
+//! A kd tree can also keep a `FingerprintStore` next to it, so its descriptor neighbors can be
+//! re-ranked by exact Tanimoto similarity.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use rand::Rng;
+
+use crate::data::{CompoundRecord, CompoundIndex, Descriptor};
+use crate::database::{Database, ImmutDatabase, SourceMask};
+use crate::layout;
+use crate::node::PagePointer;
+use crate::tree::{IndexType, NearestNeighbors, SearchParams, TopHits, TreeConfig, TreeRecord};
+
+///Fingerprints read per disk read while scanning a popcount group
+const SCAN_CHUNK_RECORDS: usize = 4096;
+
+///Bytes buffered per popcount group while sorting, before they are written out
+const SORT_BUFFER_SIZE: usize = 1 << 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fingerprint {
+    pub words: Vec<u64>,
+    pub num_bits: usize,
+}
+
+impl Fingerprint {
+
+    pub fn num_words(num_bits: usize) -> usize {
+        return num_bits.div_ceil(64);
+    }
+
+    pub fn empty(num_bits: usize) -> Self {
+        return Self {
+            words: vec![0; Self::num_words(num_bits)],
+            num_bits,
+        }
+    }
+
+    ///Sets every bit whose value is nonzero, e.g. a row of a 0/1 fingerprint matrix
+    pub fn from_dense(values: &[f32]) -> Self {
+
+        let mut fingerprint = Self::empty(values.len());
+        for (i, value) in values.iter().enumerate() {
+            if *value != 0.0 {
+                fingerprint.set(i);
+            }
+        }
+
+        return fingerprint;
+    }
+
+    pub fn from_on_bits(bits: &[usize], num_bits: usize) -> Result<Self, String> {
+
+        let mut fingerprint = Self::empty(num_bits);
+        for bit in bits.iter() {
+            if *bit >= num_bits {
+                return Err(format!("Bit {} out of range for a {} bit fingerprint", bit, num_bits));
+            }
+            fingerprint.set(*bit);
+        }
+
+        return Ok(fingerprint);
+    }
+
+    ///Each bit set with probability `density`
+    pub fn random(num_bits: usize, density: f64) -> Self {
+
+        let mut rng = rand::thread_rng();
+
+        let mut fingerprint = Self::empty(num_bits);
+        for i in 0..num_bits {
+            if rng.gen_bool(density) {
+                fingerprint.set(i);
+            }
+        }
+
+        return fingerprint;
+    }
+
+    pub fn set(&mut self, bit: usize) {
+        self.words[bit / 64] |= 1 << (bit % 64);
+    }
+
+    pub fn get(&self, bit: usize) -> bool {
+        return self.words[bit / 64] & (1 << (bit % 64)) != 0;
+    }
+
+    pub fn popcount(&self) -> usize {
+        return self.words.iter().map(|x| x.count_ones() as usize).sum();
+    }
+
+    pub fn intersection_count(&self, other: &Fingerprint) -> usize {
+        return self.words.iter().zip(other.words.iter()).map(|(a, b)| (a & b).count_ones() as usize).sum();
+    }
+
+    ///Two empty fingerprints have similarity 0
+    pub fn tanimoto(&self, other: &Fingerprint) -> f32 {
+        return tanimoto_from_counts(self.popcount(), other.popcount(), self.intersection_count(other));
+    }
+
+    pub fn write_bytes(&self, out: &mut Vec<u8>) {
+        for word in self.words.iter() {
+            out.extend_from_slice(&word.to_be_bytes());
+        }
+    }
+
+    ///Parses FPS style hex, as written by RDKit's `BitVectToFPSText`: two characters per byte,
+    ///bytes in order, bit 0 the lowest bit of the first byte
+    pub fn from_hex(hex: &str, num_bits: usize) -> Result<Self, String> {
+
+        let hex = hex.trim();
+
+        if hex.len() != num_bits.div_ceil(8) * 2 {
+            return Err(format!("Hex fingerprint has {} characters, expected {} for {} bits", hex.len(), num_bits.div_ceil(8) * 2, num_bits));
+        }
+
+        let mut fingerprint = Self::empty(num_bits);
+        for i in 0..hex.len() / 2 {
+
+            let byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2).unwrap_or("?"), 16)
+                .map_err(|_| format!("Invalid hex fingerprint {:?}", hex))?;
+
+            for j in 0..8 {
+                let bit = i * 8 + j;
+                if byte & (1 << j) != 0 {
+                    if bit >= num_bits {
+                        return Err(format!("Bit {} out of range for a {} bit fingerprint", bit, num_bits));
+                    }
+                    fingerprint.set(bit);
+                }
+            }
+        }
+
+        return Ok(fingerprint);
+    }
+
+    pub fn to_hex(&self) -> String {
+
+        let mut s = String::with_capacity(self.num_bits.div_ceil(8) * 2);
+        for i in 0..self.num_bits.div_ceil(8) {
+            let byte = (self.words[i / 8] >> ((i % 8) * 8)) as u8;
+            s += &format!("{:02x}", byte);
+        }
+
+        return s;
+    }
+
+    pub fn from_bytes(bytes: &[u8], num_bits: usize) -> Self {
+
+        let words = bytes.chunks_exact(8)
+            .take(Self::num_words(num_bits))
+            .map(|x| u64::from_be_bytes(x.try_into().unwrap()))
+            .collect();
+
+        return Self {
+            words,
+            num_bits,
+        }
+    }
+}
+
+fn tanimoto_from_counts(a: usize, b: usize, intersection: usize) -> f32 {
+
+    let union = a + b - intersection;
+
+    return match union {
+        0 => 0.0,
+        _ => intersection as f32 / union as f32,
+    }
+}
+
+///Highest Tanimoto similarity possible between fingerprints with `a` and `b` bits set
+fn tanimoto_upper_bound(a: usize, b: usize) -> f32 {
+
+    let (low, high) = (a.min(b), a.max(b));
+
+    return match high {
+        0 => 0.0,
+        _ => low as f32 / high as f32,
+    }
+}
+
+///Intersection popcount of a query with a fingerprint stored as big endian words
+fn intersection_with_bytes(query: &Fingerprint, bytes: &[u8]) -> usize {
+
+    let mut count: usize = 0;
+    for (word, chunk) in query.words.iter().zip(bytes.chunks_exact(8)) {
+        count += (word & u64::from_be_bytes(chunk.try_into().unwrap())).count_ones() as usize;
+    }
+
+    return count;
+}
+
+///Fingerprints stored next to a kd tree, as big endian words in compound index order
+#[derive(Debug)]
+pub struct FingerprintStore {
+    file: File,
+    writer: Option<BufWriter<File>>,
+    num_bits: usize,
+    num_fingerprints: u64,
+}
+
+impl FingerprintStore {
+
+    pub fn create(filename: &str, num_bits: usize) -> Result<Self, String> {
+
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(filename)
+            .map_err(|e| format!("Could not create fingerprint file {}: {}", filename, e))?;
+
+        let writer = BufWriter::new(file.try_clone().map_err(|e| e.to_string())?);
+
+        return Ok(Self {
+            file,
+            writer: Some(writer),
+            num_bits,
+            num_fingerprints: 0,
+        });
+    }
+
+    pub fn open(filename: &str, num_bits: usize) -> Result<Self, String> {
+
+        let file = File::open(filename)
+            .map_err(|e| format!("Could not open fingerprint file {}: {}", filename, e))?;
+
+        let file_length = file.metadata().map_err(|e| e.to_string())?.len();
+
+        return Ok(Self {
+            file,
+            writer: None,
+            num_bits,
+            num_fingerprints: file_length / Self::fingerprint_size(num_bits) as u64,
+        });
+    }
+
+    ///Opens an existing file to keep adding fingerprints to it
+    pub fn open_append(filename: &str, num_bits: usize) -> Result<Self, String> {
+
+        let mut store = Self::open(filename, num_bits)?;
+
+        let file = OpenOptions::new()
+            .append(true)
+            .open(filename)
+            .map_err(|e| format!("Could not open fingerprint file {}: {}", filename, e))?;
+
+        store.writer = Some(BufWriter::new(file));
+
+        return Ok(store);
+    }
+
+    fn fingerprint_size(num_bits: usize) -> usize {
+        return Fingerprint::num_words(num_bits) * 8;
+    }
+
+    pub fn num_bits(&self) -> usize {
+        return self.num_bits;
+    }
+
+    pub fn len(&self) -> u64 {
+        return self.num_fingerprints;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.num_fingerprints == 0;
+    }
+
+    ///Fingerprints have to be appended in compound index order
+    pub fn append(&mut self, index: CompoundIndex, fingerprint: &Fingerprint) -> Result<(), String> {
+
+        if index != self.num_fingerprints {
+            return Err(format!("Fingerprint for compound {} written out of order, expected {}", index, self.num_fingerprints));
+        }
+
+        if fingerprint.num_bits != self.num_bits {
+            return Err(format!("Fingerprint has {} bits, expected {}", fingerprint.num_bits, self.num_bits));
+        }
+
+        let writer = match &mut self.writer {
+            Some(x) => x,
+            None => return Err("Fingerprint file was opened read only".to_string()),
+        };
+
+        let mut bytes: Vec<u8> = Vec::with_capacity(Self::fingerprint_size(self.num_bits));
+        fingerprint.write_bytes(&mut bytes);
+        writer.write_all(&bytes).map_err(|e| e.to_string())?;
+
+        self.num_fingerprints += 1;
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), String> {
+
+        if let Some(writer) = &mut self.writer {
+            writer.flush().map_err(|e| e.to_string())?;
+        }
+
+        Ok(())
+    }
+
+    pub fn get(&self, index: CompoundIndex) -> Result<Fingerprint, String> {
+
+        if index >= self.num_fingerprints {
+            return Err(format!("No stored fingerprint for compound {}", index));
+        }
+
+        let mut bytes = vec![0u8; Self::fingerprint_size(self.num_bits)];
+        let offset = index * bytes.len() as u64;
+        self.file.read_exact_at(&mut bytes, offset).map_err(|e| e.to_string())?;
+
+        return Ok(Fingerprint::from_bytes(&bytes, self.num_bits));
+    }
+}
+
+///Index followed by the fingerprint words
+fn record_size(num_bits: usize) -> usize {
+    return layout::INDEX_SIZE + Fingerprint::num_words(num_bits) * 8;
+}
+
+fn get_fingerprint_filename(config: &TreeConfig) -> String {
+    return config.directory.clone() + "/fingerprints";
+}
+
+fn get_unsorted_filename(config: &TreeConfig) -> String {
+    return config.directory.clone() + "/fingerprints.unsorted";
+}
+
+fn get_groups_filename(config: &TreeConfig) -> String {
+    return config.directory.clone() + "/fingerprint_groups";
+}
+
+///Builds a fingerprint index. Records are staged in arrival order, then grouped by popcount
+///in `finish`.
+#[derive(Debug)]
+pub struct FingerprintIndex {
+    pub database: Database,
+    pub config: TreeConfig,
+    writer: BufWriter<File>,
+    group_counts: Vec<u64>,
+}
+
+impl FingerprintIndex {
+
+    pub fn create_with_config(config: TreeConfig) -> Self {
+
+        if config.index_type != IndexType::Fingerprint {
+            panic!("Config is for a {:?} index, not a fingerprint index", config.index_type);
+        }
+
+        let dir_path = Path::new(&config.directory);
+
+        match dir_path.is_dir() {
+            true => {panic!("Directory already exists: {}", config.directory)},
+            false => {},
+        }
+
+        std::fs::create_dir(dir_path).expect("could not create directory for fingerprint index");
+
+        config.to_file(config.get_config_filename());
+
+        let database = Database::create(&config.get_database_filename(), config.database_format).unwrap();
+
+        let file = File::create(get_unsorted_filename(&config)).unwrap();
+
+        return Self {
+            database,
+            writer: BufWriter::new(file),
+            group_counts: vec![0; config.desc_length + 1],
+            config,
+        };
+    }
+
+    ///Takes the fingerprint from the record's descriptor, where every nonzero value is a set bit
+    pub fn add_record(&mut self, record: &CompoundRecord) -> Result<(), String> {
+
+        if record.descriptor.length != self.config.desc_length {
+            return Err(format!("Fingerprint has {} bits, expected {}", record.descriptor.length, self.config.desc_length));
+        }
+
+        let fingerprint = Fingerprint::from_dense(&record.descriptor.data);
+
+        let index = self.database.add_compound_record(record)?;
+
+        return self.add_fingerprint(index, &fingerprint);
+    }
+
+    fn add_fingerprint(&mut self, index: CompoundIndex, fingerprint: &Fingerprint) -> Result<(), String> {
+
+        let mut bytes: Vec<u8> = Vec::with_capacity(record_size(self.config.desc_length));
+        bytes.extend_from_slice(&index.to_be_bytes());
+        fingerprint.write_bytes(&mut bytes);
+
+        self.writer.write_all(&bytes).map_err(|e| e.to_string())?;
+        self.group_counts[fingerprint.popcount()] += 1;
+
+        Ok(())
+    }
+
+    ///Sorts the staged records into popcount groups and writes the group offsets. Call once,
+    ///after the last record.
+    pub fn finish(mut self) -> Result<(), String> {
+
+        self.writer.flush().map_err(|e| e.to_string())?;
+        self.database.flush()?;
+
+        let size = record_size(self.config.desc_length);
+
+        //group i holds records [starts[i], starts[i + 1])
+        let mut starts: Vec<u64> = Vec::with_capacity(self.group_counts.len() + 1);
+        let mut total: u64 = 0;
+        for count in self.group_counts.iter() {
+            starts.push(total);
+            total += count;
+        }
+        starts.push(total);
+
+        let out = File::create(get_fingerprint_filename(&self.config)).map_err(|e| e.to_string())?;
+        out.set_len(total * size as u64).map_err(|e| e.to_string())?;
+
+        let mut cursors: Vec<u64> = starts[..starts.len() - 1].to_vec();
+        let mut buffers: Vec<Vec<u8>> = vec![Vec::new(); self.group_counts.len()];
+
+        let unsorted_filename = get_unsorted_filename(&self.config);
+        let mut reader = BufReader::new(File::open(&unsorted_filename).map_err(|e| e.to_string())?);
+        let mut record = vec![0u8; size];
+
+        for _ in 0..total {
+
+            reader.read_exact(&mut record).map_err(|e| e.to_string())?;
+
+            let popcount = Fingerprint::from_bytes(&record[layout::INDEX_SIZE..], self.config.desc_length).popcount();
+            buffers[popcount].extend_from_slice(&record);
+
+            if buffers[popcount].len() >= SORT_BUFFER_SIZE {
+                flush_group(&out, &mut buffers[popcount], &mut cursors[popcount], size)?;
+            }
+        }
+
+        for (buffer, cursor) in buffers.iter_mut().zip(cursors.iter_mut()) {
+            flush_group(&out, buffer, cursor, size)?;
+        }
+
+        let mut group_bytes: Vec<u8> = Vec::with_capacity(starts.len() * 8);
+        for start in starts.iter() {
+            group_bytes.extend_from_slice(&start.to_be_bytes());
+        }
+        std::fs::write(get_groups_filename(&self.config), group_bytes).map_err(|e| e.to_string())?;
+
+        std::fs::remove_file(&unsorted_filename).map_err(|e| e.to_string())?;
+
+        Ok(())
+    }
+}
+
+fn flush_group(out: &File, buffer: &mut Vec<u8>, cursor: &mut u64, size: usize) -> Result<(), String> {
+
+    out.write_all_at(buffer, *cursor * size as u64).map_err(|e| e.to_string())?;
+    *cursor += (buffer.len() / size) as u64;
+    buffer.clear();
+
+    Ok(())
+}
+
+///A finished fingerprint index, opened for querying
+pub struct ImmutFingerprintIndex {
+    pub database: ImmutDatabase,
+    pub config: TreeConfig,
+    file: File,
+    group_starts: Vec<u64>,
+}
+
+impl ImmutFingerprintIndex {
+
+    pub fn read_from_directory(directory_name: String) -> Self {
+
+        let config_filename = directory_name.clone() + "/config.yaml";
+        let config = TreeConfig::from_file(config_filename);
+
+        if config.index_type != IndexType::Fingerprint {
+            panic!("{} holds a {:?} index, not a fingerprint index", directory_name, config.index_type);
+        }
+
+        let file = File::open(get_fingerprint_filename(&config)).unwrap();
+
+        let group_bytes = std::fs::read(get_groups_filename(&config)).unwrap();
+        let group_starts: Vec<u64> = group_bytes.chunks_exact(8)
+            .map(|x| u64::from_be_bytes(x.try_into().unwrap()))
+            .collect();
+
+        assert_eq!(group_starts.len(), config.desc_length + 2);
+
+        let database = ImmutDatabase::open(&config.get_database_filename());
+
+        return Self {
+            database,
+            config,
+            file,
+            group_starts,
+        };
+    }
+
+    pub fn len(&self) -> u64 {
+        return self.group_starts[self.group_starts.len() - 1];
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+    pub fn get_nearest_neighbors(&self, query: &Fingerprint, n: usize) -> NearestNeighbors {
+
+        return self.get_nearest_neighbors_with_params(query, n, &SearchParams::exact()).unwrap();
+    }
+
+    ///`get_nearest_neighbors`, restricted to the sources in `params`. Fingerprint indexes keep no
+    ///attributes, so a filter is an error.
+    pub fn get_nearest_neighbors_with_params(&self, query: &Fingerprint, n: usize, params: &SearchParams) -> Result<NearestNeighbors, String> {
+
+        let mut hits = TopHits::new(n);
+        self.search(query, &mut hits, params)?;
+
+        return NearestNeighbors::from_top_hits(hits, &self.database, true);
+    }
+
+    ///Every fingerprint within Tanimoto distance `radius`, i.e. similarity above `1 - radius`
+    pub fn get_neighbors_within_radius(&self, query: &Fingerprint, radius: f32) -> NearestNeighbors {
+
+        let mut hits = TopHits::within_radius(radius);
+        self.search(query, &mut hits, &SearchParams::exact()).unwrap();
+
+        return NearestNeighbors::from_top_hits(hits, &self.database, true).unwrap();
+    }
+
+    ///Scans popcount groups from the highest similarity bound down, until the bound can't get a
+    ///hit into `hits`. Only the sources in `params` apply; the scan is always exact.
+    pub fn search(&self, query: &Fingerprint, hits: &mut TopHits, params: &SearchParams) -> Result<(), String> {
+
+        if params.filter.is_some() {
+            return Err("Fingerprint indexes don't keep attributes to filter on".to_string());
+        }
+
+        if query.num_bits != self.config.desc_length {
+            return Err(format!("Query has {} bits, index has {}", query.num_bits, self.config.desc_length));
+        }
+
+        let query_popcount = query.popcount();
+        let num_groups = self.config.desc_length + 1;
+
+        //walk outwards from the query's own popcount, taking whichever side has the higher bound
+        let mut below: Option<usize> = Some(query_popcount);
+        let mut above = query_popcount + 1;
+
+        loop {
+
+            let below_bound = below.map(|x| tanimoto_upper_bound(x, query_popcount));
+            let above_bound = match above < num_groups {
+                true => Some(tanimoto_upper_bound(above, query_popcount)),
+                false => None,
+            };
+
+            let group = match (below_bound, above_bound) {
+                (None, None) => break,
+                (Some(b), Some(a)) if a > b => above,
+                (None, Some(_)) => above,
+                _ => below.unwrap(),
+            };
+
+            let bound = tanimoto_upper_bound(group, query_popcount);
+            if 1.0 - bound >= hits.get_highest_dist() {
+                break;
+            }
+
+            self.scan_group(query, query_popcount, group, hits, params.sources)?;
+
+            match group == above {
+                true => above += 1,
+                false => below = group.checked_sub(1),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn scan_group(&self, query: &Fingerprint, query_popcount: usize, group: usize, hits: &mut TopHits, sources: Option<SourceMask>) -> Result<(), String> {
+
+        let size = record_size(self.config.desc_length);
+        let start = self.group_starts[group];
+        let end = self.group_starts[group + 1];
+
+        let pointer = PagePointer::Leaf(group);
+        let mut buffer: Vec<u8> = Vec::new();
+
+        let mut offset = start;
+        while offset < end {
+
+            let num_records = ((end - offset) as usize).min(SCAN_CHUNK_RECORDS);
+            buffer.resize(num_records * size, 0);
+            self.file.read_exact_at(&mut buffer, offset * size as u64).map_err(|e| e.to_string())?;
+
+            for record in buffer.chunks_exact(size) {
+
+                let intersection = intersection_with_bytes(query, &record[layout::INDEX_SIZE..]);
+                let distance = 1.0 - tanimoto_from_counts(query_popcount, group, intersection);
+
+                if distance < hits.get_highest_dist() {
+                    let index = u64::from_be_bytes(record[..layout::INDEX_SIZE].try_into().unwrap());
+
+                    if let Some(mask) = sources {
+                        if self.database.sources(&index) & mask == 0 {
+                            continue;
+                        }
+                    }
+
+                    let tree_record = TreeRecord {
+                        index,
+                        descriptor: Descriptor { data: Vec::new(), length: 0 },
+                        length: 0,
+                    };
+                    hits.try_add(distance, &tree_record, &pointer)?;
+                }
+            }
+
+            offset += num_records as u64;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::attributes::Filter;
+    use crate::data::CompoundIdentifier;
+
+    #[test]
+    fn quick_fingerprint_tanimoto() {
+
+        let a = Fingerprint::from_on_bits(&[0, 5, 64, 100], 128).unwrap();
+        let b = Fingerprint::from_on_bits(&[0, 5, 70], 128).unwrap();
+
+        assert_eq!(a.popcount(), 4);
+        assert_eq!(a.intersection_count(&b), 2);
+        assert_eq!(a.tanimoto(&b), 2.0 / 5.0);
+        assert_eq!(a.tanimoto(&a), 1.0);
+        assert_eq!(Fingerprint::empty(128).tanimoto(&Fingerprint::empty(128)), 0.0);
+        assert!(Fingerprint::from_on_bits(&[128], 128).is_err());
+
+        let mut bytes: Vec<u8> = Vec::new();
+        a.write_bytes(&mut bytes);
+        assert_eq!(Fingerprint::from_bytes(&bytes, 128), a);
+
+        let dense: Vec<f32> = (0..128).map(|i| if a.get(i) { 1.0 } else { 0.0 }).collect();
+        assert_eq!(Fingerprint::from_dense(&dense), a);
+
+        assert!(tanimoto_upper_bound(4, 3) >= a.tanimoto(&b));
+
+        //bits 0 and 5 in the first byte, 64 and 70 in the ninth, 100 in the thirteenth
+        let hex = a.to_hex();
+        assert_eq!(&hex[..2], "21");
+        assert_eq!(Fingerprint::from_hex(&hex, 128).unwrap(), a);
+        assert!(Fingerprint::from_hex("21", 128).is_err());
+        assert!(Fingerprint::from_hex(&hex.replace("21", "zz"), 128).is_err());
+    }
+
+    #[test]
+    fn quick_fingerprint_store() {
+
+        let filename = "/tmp/quick_fingerprint_store";
+        let num_bits = 200;
+
+        let fingerprints: Vec<Fingerprint> = (0..100).map(|_| Fingerprint::random(num_bits, 0.1)).collect();
+
+        let mut store = FingerprintStore::create(filename, num_bits).unwrap();
+        for (i, fingerprint) in fingerprints.iter().enumerate() {
+            store.append(i as u64, fingerprint).unwrap();
+        }
+        assert!(store.append(200, &fingerprints[0]).is_err());
+        store.flush().unwrap();
+
+        let store = FingerprintStore::open(filename, num_bits).unwrap();
+        assert_eq!(store.len(), 100);
+        for (i, fingerprint) in fingerprints.iter().enumerate() {
+            assert_eq!(&store.get(i as u64).unwrap(), fingerprint);
+        }
+        assert!(store.get(100).is_err());
+    }
+
+    #[test]
+    fn quick_fingerprint_index_matches_brute_force() {
+
+        let num_bits = 1024;
+
+        let mut config = TreeConfig::default();
+        config.directory = "/tmp/quick_fingerprint_index".to_string();
+        config.desc_length = num_bits;
+        config.index_type = IndexType::Fingerprint;
+
+        //there's no default model for fingerprints to fall back on
+        assert!(config.embedding_model().is_err());
+        config.embedding_model = Some("morgan1024".to_string());
+        assert_eq!(config.embedding_model().unwrap(), "morgan1024");
+
+        let _ = std::fs::remove_dir_all(&config.directory);
+
+        let mut index = FingerprintIndex::create_with_config(config.clone());
+        index.database.dataset_id("enamine").unwrap();
+        index.database.dataset_id("mcule").unwrap();
+
+        let mut fingerprints: Vec<Fingerprint> = Vec::new();
+        for i in 0..5000 {
+            let fingerprint = Fingerprint::random(num_bits, 0.01 + 0.1 * (i % 7) as f64 / 7.0);
+            let descriptor: Vec<f32> = (0..num_bits).map(|x| if fingerprint.get(x) { 1.0 } else { 0.0 }).collect();
+
+            let record = CompoundRecord {
+                smiles: format!("C{}", i),
+                compound_identifier: CompoundIdentifier::from_string(format!("fp{}", i)),
+                descriptor: Descriptor::from_vec(descriptor, num_bits),
+                length: num_bits,
+                dataset: Some((i % 2) as u8),
+            };
+
+            index.add_record(&record).unwrap();
+            fingerprints.push(fingerprint);
+        }
+        index.finish().unwrap();
+
+        let index = ImmutFingerprintIndex::read_from_directory(config.directory.clone());
+        assert_eq!(index.len(), 5000);
+
+        for q in 0..20 {
+            let query = match q % 2 {
+                0 => fingerprints[q * 100].clone(),
+                _ => Fingerprint::random(num_bits, 0.05),
+            };
+
+            let mut brute: Vec<f32> = fingerprints.iter().map(|x| 1.0 - query.tanimoto(x)).collect();
+            brute.sort_by(|a, b| a.total_cmp(b));
+
+            let nn = index.get_nearest_neighbors(&query, 10);
+            assert_eq!(nn.distances, brute[..10].to_vec());
+
+            if q % 2 == 0 {
+                assert_eq!(nn.distances[0], 0.0);
+                assert_eq!(nn.records[0].as_ref().unwrap().smiles, format!("C{}", q * 100));
+            }
+
+            let radius = brute[25];
+            let within = index.get_neighbors_within_radius(&query, radius);
+            assert_eq!(within.distances.len(), brute.iter().filter(|x| **x < radius).count());
+
+            //only the odd records are from mcule
+            let mut params = SearchParams::exact();
+            params.sources = Some(index.database.dataset_mask(&["mcule".to_string()]).unwrap());
+
+            let mut expected: Vec<f32> = fingerprints.iter().skip(1).step_by(2).map(|x| 1.0 - query.tanimoto(x)).collect();
+            expected.sort_by(|a, b| a.total_cmp(b));
+
+            let nn = index.get_nearest_neighbors_with_params(&query, 10, &params).unwrap();
+            assert_eq!(nn.distances, expected[..10].to_vec());
+            assert!(nn.catalogs.unwrap().iter().all(|x| x == &vec!["mcule".to_string()]));
+        }
+
+        //there are no attributes to filter on
+        let mut params = SearchParams::exact();
+        params.filter = Some(Filter::default());
+        assert!(index.get_nearest_neighbors_with_params(&fingerprints[0], 10, &params).is_err());
+    }
+}
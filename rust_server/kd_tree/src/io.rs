@@ -4,8 +4,9 @@
 
 use crate::error::Error;
 use crate::data::{CompoundIdentifier};
-use crate::node::{InternalNode, PagePointer};
+use crate::node::{InternalNode, PackedNode, PagePointer};
 use crate::page::RecordPage;
+use crate::encoding::DescriptorCodec;
 use byteorder::{ByteOrder, BigEndian};
 use crate::layout;
 use std::fs::{File, OpenOptions};
@@ -13,6 +14,20 @@ use std::io::Write;
 use std::io::{Read, Seek, SeekFrom};
 use std::path::Path;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use memmap2::Mmap;
+use serde::{Serialize, Deserialize};
+
+///How a tree's node file is written. Readers tell the two apart from the file itself.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeFileFormat {
+    ///Big-endian nodes in the tree's `format_version` layout, what trees before packed files use
+    Portable,
+    ///Aligned native-endian `PackedNode`s, mapped into memory instead of parsed when queried
+    #[default]
+    Packed,
+}
 
 #[derive(Debug)]
 pub struct RecordPager {
@@ -21,24 +36,28 @@ pub struct RecordPager {
     pub next_free_index: usize, //this is the next available slot
     pub desc_length: usize,
     pub page_length: usize,
+    pub codec: DescriptorCodec,
     cache: HashMap<usize, RecordPage>,
     cache_limit: Option<f32>,
     cache_check_counter: usize,
+    cache_hits: AtomicUsize,
+    cache_misses: AtomicUsize,
 }
 
 pub struct DiskNodePager {
-    filename: String
+    filename: String,
+    node_layout: layout::NodeLayout,
 }
 
 impl DiskNodePager {
 
-    pub fn from_file(filename: &String) -> Result<Self, Error> {
-        return Ok(Self{filename: filename.clone()});
+    pub fn from_file(filename: &String, node_layout: layout::NodeLayout) -> Result<Self, Error> {
+        return Ok(Self{filename: filename.clone(), node_layout});
     }
 
-    fn calc_offset(index: usize) -> usize {
+    fn calc_offset(&self, index: usize) -> usize {
 
-        return layout::FILE_DATA_START + (index * layout::NODE_SIZE);
+        return layout::FILE_DATA_START + (index * self.node_layout.node_size());
 
     }
 
@@ -51,11 +70,11 @@ impl DiskNodePager {
                     .truncate(false)
                     .open(&self.filename).unwrap();
 
-        let start = Self::calc_offset(*index);
-        let mut node_arr: [u8; layout::NODE_SIZE] = [0x00; layout::NODE_SIZE];
+        let start = self.calc_offset(*index);
+        let mut node_arr = vec![0x00; self.node_layout.node_size()];
         fd.seek(SeekFrom::Start(start as u64)).unwrap();
         fd.read_exact(&mut node_arr).unwrap();
-        let node = InternalNode::from_slice(&node_arr).unwrap();
+        let node = InternalNode::from_slice(&node_arr, &self.node_layout)?;
 
         Ok(node)
 
@@ -63,8 +82,18 @@ impl DiskNodePager {
 
 }
 
+///Nodes of a tree opened for querying. Packed node files are mapped and portable ones read a node
+///at a time, so neither is parsed up front and opening takes the same time however many nodes
+///there are.
 pub struct ImmutNodePager {
-    pub store: Vec<InternalNode>
+    nodes: ImmutNodes,
+}
+
+enum ImmutNodes {
+    ///A packed node file and the number of nodes in it
+    Mapped(Mmap, usize),
+    ///A portable node file and the number of nodes in it
+    OnDisk(DiskNodePager, usize),
 }
 
 pub trait GetNode {
@@ -76,65 +105,62 @@ pub trait GetNode {
 impl ImmutNodePager {
 
     pub fn len(&self) -> usize {
-        return self.store.len();
-    }
 
-    fn calc_offset(index: usize) -> usize {
+        return match &self.nodes {
+            ImmutNodes::Mapped(_, num_nodes) => *num_nodes,
+            ImmutNodes::OnDisk(_, num_nodes) => *num_nodes,
+        }
+    }
 
-        return layout::FILE_DATA_START + (index * layout::NODE_SIZE);
+    pub fn from_file(filename: &String, node_layout: &layout::NodeLayout) -> Result<Self, String> {
 
-    }
+        let mut file = File::open(Path::new(filename)).map_err(|e| format!("Could not open nodes at {}: {}", filename, e))?;
+        let file_length = file.metadata().map_err(|e| e.to_string())?.len();
 
-    pub fn from_file(filename: &String) -> Result<Self, Error> {
+        let mut header = vec![0u8; layout::PACKED_DATA_START.min(file_length as usize)];
+        file.read_exact(&mut header).map_err(|e| format!("Could not read nodes at {}: {}", filename, e))?;
 
-        let path = Path::new(filename);
+        let nodes = match is_packed_node_file(&header) {
+            true => {
+                let num_nodes = read_packed_header(&header, file_length)?;
 
-        let mut fd = OpenOptions::new()
-                    .create(false)
-                    .read(true)
-                    .write(false)
-                    .truncate(false)
-                    .open(path)?;
+                //trees aren't modified once built, which is what makes mapping their node file sound
+                let map = unsafe { Mmap::map(&file) }.map_err(|e| format!("Could not map nodes at {}: {}", filename, e))?;
+                packed_nodes(&map, num_nodes)?;
 
-        let mut next_free_index_arr: [u8; layout::HEADER_CURSOR_SIZE] = [0x00; layout::HEADER_CURSOR_SIZE];
+                ImmutNodes::Mapped(map, num_nodes)
+            },
+            false => {
+                let num_nodes = read_portable_header(&header, file_length, node_layout)?;
+                let pager = DiskNodePager::from_file(filename, *node_layout).map_err(|e| format!("{:?}", e))?;
 
-        fd.seek(SeekFrom::Start(layout::HEADER_CURSOR_START as u64))?;
-        fd.read_exact(&mut next_free_index_arr)?;
+                ImmutNodes::OnDisk(pager, num_nodes)
+            },
+        };
 
-        //let mut pager = Self::new();
-        let mut store: Vec<InternalNode> = Vec::new();
+        Ok(Self{nodes})
 
-        let attempted_usize = layout::Value::try_from(next_free_index_arr);
-        let layout::Value(value) = attempted_usize.unwrap();
-        
-        for i in 0..(value + 1) {
-            let mut node_arr: [u8; layout::NODE_SIZE] = [0x00; layout::NODE_SIZE];
+    }
 
-            let start = Self::calc_offset(i);
-            fd.seek(SeekFrom::Start(start as u64))?;
-            fd.read_exact(&mut node_arr)?;
-            let node = InternalNode::from_slice(&node_arr).unwrap();
-            store.push(node);
+    pub fn get_node(&self, index: &usize) -> Result<InternalNode, String> {
 
+        if *index >= self.len() {
+            return Err(format!("Node not found at address: {:?}", index));
         }
-        
-        Ok(Self{store})
 
+        return match &self.nodes {
+            ImmutNodes::Mapped(map, num_nodes) => Ok(packed_nodes(map, *num_nodes)?[*index].to_node()),
+            ImmutNodes::OnDisk(pager, _) => pager.get_node(index),
+        }
     }
-
-
 }
-impl GetNode for ImmutNodePager {
 
-    fn get_node(&self, index: &usize) -> Result<&InternalNode, String> {
+///The `num_nodes` nodes of a mapped packed node file
+fn packed_nodes(map: &Mmap, num_nodes: usize) -> Result<&[PackedNode], String> {
 
-        let ret = match self.store.get(index.clone()) {
-            Some(node) => Ok(node),
-            None => Err(format!("Node not found at address: {:?}", index)),
-        };
+    let end = layout::PACKED_DATA_START + num_nodes * std::mem::size_of::<PackedNode>();
 
-        return ret;
-    }
+    return PackedNode::slice_from_bytes(&map[layout::PACKED_DATA_START..end]);
 }
 
 
@@ -174,80 +200,57 @@ impl FastNodePager {
         return self.store.len();
     }
 
-    fn calc_offset(index: usize) -> usize {
+    ///Errors if a node can't be represented in the format, before anything is written
+    pub fn to_file(&self, filename: &String, node_layout: &layout::NodeLayout, format: NodeFileFormat) -> Result<(), String> {
 
-        return layout::FILE_DATA_START + (index * layout::NODE_SIZE);
-
-    }
+        let data = match format {
+            NodeFileFormat::Portable => self.to_portable_bytes(node_layout)?,
+            NodeFileFormat::Packed => self.to_packed_bytes()?,
+        };
 
-    pub fn to_file(&self, filename: &String) -> Result<(), Error> {
+        std::fs::write(Path::new(filename), &data).map_err(|e| format!("Could not write nodes to {}: {}", filename, e))?;
 
-        let path = Path::new(filename);
+        Ok(())
 
-        let mut fd = OpenOptions::new()
-                    .create(true)
-                    .read(true)
-                    .write(true)
-                    .truncate(true)
-                    .open(path).unwrap();
-
-        for i in 0..self.store.len() {
-            let node = self.store.get(i).unwrap();
-            let start = Self::calc_offset(i);
-            let slice = node.to_arr();
-
-            fd.seek(SeekFrom::Start(start as u64))?;
-            fd.write(&slice).unwrap();
-            //println!("W {:?} {:?}", i, start);
-            //println!("\t{:?}", node);
-        }
+    }
 
-        let next_free_index = self.store.len() - 1;
-        //update next_free_index on disk
-        let mut next_free_index_arr: [u8; layout::HEADER_CURSOR_SIZE] = [0x00; layout::HEADER_CURSOR_SIZE];
+    fn to_portable_bytes(&self, node_layout: &layout::NodeLayout) -> Result<Vec<u8>, String> {
 
-        BigEndian::write_u64(&mut next_free_index_arr, next_free_index as u64);
+        let node_size = node_layout.node_size();
+        let mut data = vec![0u8; layout::FILE_DATA_START + self.store.len() * node_size];
 
-        fd.seek(SeekFrom::Start(layout::HEADER_CURSOR_START as u64))?;
-        fd.write(&next_free_index_arr)?;
+        let next_free_index = self.store.len() - 1;
+        BigEndian::write_u64(&mut data[layout::HEADER_CURSOR_START..layout::HEADER_CURSOR_START + layout::HEADER_CURSOR_SIZE], next_free_index as u64);
 
-        Ok(())
+        for (i, node) in self.store.iter().enumerate() {
+            let start = layout::FILE_DATA_START + i * node_size;
+            data[start..start + node_size].copy_from_slice(&node.to_arr(node_layout)?);
+        }
 
+        return Ok(data);
     }
 
-    pub fn from_file(filename: &String) -> Result<FastNodePager, Error> {
-
-        let path = Path::new(filename);
-
-        let mut fd = OpenOptions::new()
-                    .create(false)
-                    .read(true)
-                    .write(false)
-                    .truncate(false)
-                    .open(path)?;
+    fn to_packed_bytes(&self) -> Result<Vec<u8>, String> {
 
-        let mut next_free_index_arr: [u8; layout::HEADER_CURSOR_SIZE] = [0x00; layout::HEADER_CURSOR_SIZE];
-        fd.seek(SeekFrom::Start(layout::HEADER_CURSOR_START as u64))?;
-        fd.read_exact(&mut next_free_index_arr)?;
+        let packed = self.store.iter().map(PackedNode::from_node).collect::<Result<Vec<_>, String>>()?;
+        let node_bytes = PackedNode::slice_as_bytes(&packed);
 
-        let mut pager = Self::new();
+        let mut data = Vec::with_capacity(layout::PACKED_DATA_START + node_bytes.len());
+        data.extend_from_slice(layout::PACKED_NODE_MAGIC);
+        data.extend_from_slice(&layout::PACKED_ENDIAN_TAG.to_ne_bytes());
+        data.extend_from_slice(&(std::mem::size_of::<PackedNode>() as u32).to_ne_bytes());
+        data.extend_from_slice(&(packed.len() as u64).to_ne_bytes());
+        data.extend_from_slice(node_bytes);
 
-        let attempted_usize = layout::Value::try_from(next_free_index_arr);
-        let layout::Value(value) = attempted_usize.unwrap();
-        
-        for i in 0..(value + 1) {
-            let mut node_arr: [u8; layout::NODE_SIZE] = [0x00; layout::NODE_SIZE];
-            let start = Self::calc_offset(i);
-            //println!("R {:?} {:?}", i, start);
-            fd.seek(SeekFrom::Start(start as u64))?;
-            fd.read_exact(&mut node_arr)?;
-            let node = InternalNode::from_slice(&node_arr).unwrap();
-            pager.store.push(node);
+        return Ok(data);
+    }
 
-        }
+    ///Reads either node file format, `node_layout` being the one portable files are written in
+    pub fn from_file(filename: &String, node_layout: &layout::NodeLayout) -> Result<FastNodePager, String> {
 
+        let mut pager = Self::new();
+        pager.store = read_nodes(filename, node_layout)?;
 
-        
         Ok(pager)
 
     }
@@ -266,6 +269,96 @@ impl FastNodePager {
         Ok(PagePointer::Node(added_index))
     }
 
+    ///Renumbers nodes so the top `block_height` levels of each subtree are stored together,
+    ///breadth first, followed by the subtrees hanging off that block in the same layout, left to
+    ///right. A root to leaf path then touches one block every `block_height` levels instead of
+    ///nodes spread over the whole file. The root stays at 0 and every `PagePointer::Node` is
+    ///rewritten. Errors, leaving the nodes as they were, if they don't form a tree under node 0.
+    pub fn reorder_blocked(&mut self, block_height: usize) -> Result<(), String> {
+
+        let num_nodes = self.store.len();
+
+        if num_nodes == 0 {
+            return Ok(());
+        }
+
+        if block_height == 0 {
+            return Err("Node blocks need at least one level".to_string());
+        }
+
+        //old index of the node at each new position
+        let mut order: Vec<usize> = Vec::with_capacity(num_nodes);
+        let mut placed = vec![false; num_nodes];
+
+        //roots of subtrees still to lay out, the next one last
+        let mut subtree_roots: Vec<usize> = vec![0];
+
+        while let Some(subtree_root) = subtree_roots.pop() {
+
+            let mut level: Vec<usize> = vec![subtree_root];
+            let mut below: Vec<usize> = Vec::new();
+
+            for depth in 0..block_height {
+
+                let mut next_level: Vec<usize> = Vec::new();
+
+                for index in level {
+
+                    if placed[index] {
+                        return Err(format!("Node {} has more than one parent", index));
+                    }
+                    placed[index] = true;
+                    order.push(index);
+
+                    let node = &self.store[index];
+                    for child in [&node.left_child_pointer, &node.right_child_pointer] {
+                        if let PagePointer::Node(x) = child {
+
+                            if *x >= num_nodes {
+                                return Err(format!("Node {} points to missing node {}", index, x));
+                            }
+
+                            match depth + 1 < block_height {
+                                true => next_level.push(*x),
+                                false => below.push(*x),
+                            }
+                        }
+                    }
+                }
+
+                level = next_level;
+            }
+
+            subtree_roots.extend(below.into_iter().rev());
+        }
+
+        if order.len() != num_nodes {
+            return Err(format!("{} nodes are unreachable from the root", num_nodes - order.len()));
+        }
+
+        let mut new_index = vec![0usize; num_nodes];
+        for (position, old) in order.iter().enumerate() {
+            new_index[*old] = position;
+        }
+
+        let renumber = |pointer: &PagePointer| match pointer {
+            PagePointer::Node(x) => PagePointer::Node(new_index[*x]),
+            PagePointer::Leaf(x) => PagePointer::Leaf(*x),
+        };
+
+        self.store = order.iter().map(|old| {
+            let node = &self.store[*old];
+            InternalNode {
+                left_child_pointer: renumber(&node.left_child_pointer),
+                right_child_pointer: renumber(&node.right_child_pointer),
+                split_axis: node.split_axis,
+                split_value: node.split_value,
+            }
+        }).collect();
+
+        Ok(())
+    }
+
     pub fn update_node(&mut self, index: &usize, new_node: &InternalNode) -> Result<(), Error> {
 
         //self.map.insert(pointer.to_tuple(), new_node.clone());
@@ -277,9 +370,89 @@ impl FastNodePager {
 }
 
 
+fn is_packed_node_file(data: &[u8]) -> bool {
+
+    return data.starts_with(layout::PACKED_NODE_MAGIC);
+}
+
+///Checks the header of a packed node file `file_length` bytes long and returns how many nodes it holds
+fn read_packed_header(header: &[u8], file_length: u64) -> Result<usize, String> {
+
+    if header.len() < layout::PACKED_DATA_START || !is_packed_node_file(header) {
+        return Err("Not a packed node file".to_string());
+    }
+
+    let read_u32 = |offset: usize| u32::from_ne_bytes(header[offset..offset + 4].try_into().unwrap());
+
+    if read_u32(layout::PACKED_ENDIAN_TAG_OFFSET) != layout::PACKED_ENDIAN_TAG {
+        return Err("Packed node file was written on a machine with a different byte order".to_string());
+    }
+
+    let node_size = read_u32(layout::PACKED_NODE_SIZE_OFFSET) as usize;
+    if node_size != std::mem::size_of::<PackedNode>() {
+        return Err(format!("Packed node file holds {} byte nodes, expected {}", node_size, std::mem::size_of::<PackedNode>()));
+    }
+
+    let num_nodes = u64::from_ne_bytes(header[layout::PACKED_COUNT_OFFSET..layout::PACKED_COUNT_OFFSET + layout::PACKED_COUNT_SIZE].try_into().unwrap()) as usize;
+
+    if file_length < (layout::PACKED_DATA_START + num_nodes * node_size) as u64 {
+        return Err(format!("Packed node file holds fewer than the {} nodes its header claims", num_nodes));
+    }
+
+    return Ok(num_nodes);
+}
+
+///Checks the header of a portable node file `file_length` bytes long and returns how many nodes it holds
+fn read_portable_header(header: &[u8], file_length: u64, node_layout: &layout::NodeLayout) -> Result<usize, String> {
+
+    if header.len() < layout::FILE_DATA_START {
+        return Err("Node file is missing its header".to_string());
+    }
+
+    let next_free_index = BigEndian::read_u64(&header[layout::HEADER_CURSOR_START..layout::HEADER_CURSOR_START + layout::HEADER_CURSOR_SIZE]) as usize;
+    let num_nodes = next_free_index + 1;
+
+    if file_length < (layout::FILE_DATA_START + num_nodes * node_layout.node_size()) as u64 {
+        return Err(format!("Node file holds fewer than the {} nodes its header claims", num_nodes));
+    }
+
+    return Ok(num_nodes);
+}
+
+///Reads every node of a node file, packed or written with `node_layout`
+fn read_nodes(filename: &String, node_layout: &layout::NodeLayout) -> Result<Vec<InternalNode>, String> {
+
+    let data = std::fs::read(Path::new(filename)).map_err(|e| format!("Could not read nodes from {}: {}", filename, e))?;
+
+    if is_packed_node_file(&data) {
+        let num_nodes = read_packed_header(&data, data.len() as u64)?;
+
+        let mut packed = vec![PackedNode{left_child: 0, right_child: 0, split_axis: 0, split_value: 0.0}; num_nodes];
+        let node_bytes = PackedNode::slice_as_bytes_mut(&mut packed);
+        node_bytes.copy_from_slice(&data[layout::PACKED_DATA_START..layout::PACKED_DATA_START + node_bytes.len()]);
+
+        return Ok(packed.iter().map(PackedNode::to_node).collect());
+    }
+
+    let num_nodes = read_portable_header(&data, data.len() as u64, node_layout).map_err(|e| format!("{}: {}", filename, e))?;
+    let node_size = node_layout.node_size();
+
+    let mut store: Vec<InternalNode> = Vec::with_capacity(num_nodes);
+
+    for i in 0..num_nodes {
+        let start = layout::FILE_DATA_START + i * node_size;
+        store.push(InternalNode::from_slice(&data[start..start + node_size], node_layout)?);
+    }
+
+    return Ok(store);
+}
+
+
 impl RecordPager {
 
-    pub fn new(path: String, page_length: usize, desc_length: usize, create: bool, cache_limit: Option<f32>) -> Result<Self, Error> {
+    pub fn new(path: String, page_length: usize, codec: DescriptorCodec, create: bool, cache_limit: Option<f32>) -> Result<Self, Error> {
+
+        let desc_length = codec.length;
 
         match create {
             true => {
@@ -297,9 +470,12 @@ impl RecordPager {
                     next_free_index: 0,
                     desc_length,
                     page_length,
+                    codec,
                     cache: HashMap::new(),
                     cache_limit: cache_limit,
                     cache_check_counter: 0,
+                    cache_hits: AtomicUsize::new(0),
+                    cache_misses: AtomicUsize::new(0),
                 })
             },
             false => {
@@ -316,15 +492,24 @@ impl RecordPager {
 
                     let attempted_usize = layout::Value::try_from(next_free_index_from_disk);
                     let layout::Value(value) = attempted_usize.unwrap();
+
+                    //files written before the header was kept up to date still hold the "empty"
+                    //placeholder, so never trust it past the number of pages actually on disk
+                    let file_length = fd.metadata()?.len() as usize;
+                    let pages_on_disk = file_length.saturating_sub(layout::FILE_DATA_START) / page_length;
+                    let value = value.min(pages_on_disk);
                     
                     return Ok(Self {
                         path: path,
                         next_free_index: value,
                         desc_length,
                         page_length,
+                        codec,
                         cache: HashMap::new(),
                         cache_limit: cache_limit,
                         cache_check_counter: 0,
+                        cache_hits: AtomicUsize::new(0),
+                        cache_misses: AtomicUsize::new(0),
                 })
 
             }
@@ -347,10 +532,12 @@ impl RecordPager {
         let retval = match self.cache.get(address) {
             Some(x) => {
                 //dbg!("CACHE HIT");
+                self.cache_hits.fetch_add(1, Ordering::Relaxed);
                 Ok(x.clone())
             }
             None => {
                 //dbg!("CACHE MISS");
+                self.cache_misses.fetch_add(1, Ordering::Relaxed);
 
                 
                 let page = self._read_record_page(address)?;
@@ -384,7 +571,7 @@ impl RecordPager {
 
         file.read_exact(&mut page)?;
 
-        let page = RecordPage::from_arr(&page, self.page_length, self.desc_length);
+        let page = RecordPage::from_arr(&page, self.page_length, &self.codec);
 
         return Ok(page);
     }
@@ -422,6 +609,11 @@ impl RecordPager {
 
     }
 
+    ///Returns (hits, misses) of `get_record_page` against the page cache since opening
+    pub fn get_cache_stats(&self) -> (usize, usize) {
+        return (self.cache_hits.load(Ordering::Relaxed), self.cache_misses.load(Ordering::Relaxed));
+    }
+
     pub fn get_cache_len(&self) -> usize {
         return self.cache.len();
     }
@@ -438,6 +630,24 @@ impl RecordPager {
 
         }
 
+        self._write_header().unwrap();
+
+    }
+
+    ///Writes the number of pages to the start of the file so readers know where the data ends
+    fn _write_header(&mut self) -> Result<(), Error> {
+
+        let mut file = OpenOptions::new()
+                    .write(true)
+                    .open(self.path.clone())?;
+
+        let mut next_free_index_arr: [u8; layout::HEADER_CURSOR_SIZE] = [0x00; layout::HEADER_CURSOR_SIZE];
+        BigEndian::write_u64(&mut next_free_index_arr, self.next_free_index as u64);
+
+        file.seek(SeekFrom::Start(layout::HEADER_CURSOR_START as u64))?;
+        file.write_all(&next_free_index_arr)?;
+
+        Ok(())
     }
 
     pub fn flush_keys(&mut self, keys: Vec<usize>) {
@@ -564,14 +774,87 @@ mod tests {
                 }
                 
                 let filename = "test_data/node".to_string();
-                pager.to_file(&filename).unwrap();
-                pager = FastNodePager::from_file(&filename).unwrap();
+                pager.to_file(&filename, &layout::NodeLayout::current(), NodeFileFormat::Portable).unwrap();
+                pager = FastNodePager::from_file(&filename, &layout::NodeLayout::current()).unwrap();
 
                 assert_eq!(pager.store.len(), num_nodes);
             }
         }
 
     }
-}
 
+    #[test]
+    fn quick_node_layouts() {
+
+        let mut node = InternalNode::default();
+        node.left_child_pointer = PagePointer::Leaf(7);
+        node.right_child_pointer = PagePointer::Node(3);
+        node.split_axis = 300;
+        node.split_value = 0.25;
 
+        let current = layout::NodeLayout::current();
+        let arr = node.to_arr(&current).unwrap();
+        assert_eq!(arr.len(), current.node_size());
+        assert_eq!(InternalNode::from_slice(&arr, &current).unwrap(), node);
+
+        //a legacy node has one byte for the axis, so wide axes are refused instead of wrapping
+        let legacy = layout::NodeLayout::for_version(layout::LEGACY_FORMAT_VERSION).unwrap();
+        assert!(node.to_arr(&legacy).is_err());
+
+        node.split_axis = 255;
+        let arr = node.to_arr(&legacy).unwrap();
+        assert_eq!(arr.len(), 23);
+        assert_eq!(InternalNode::from_slice(&arr, &legacy).unwrap(), node);
+
+        //unknown child types are an error, not a panic
+        let mut arr = node.to_arr(&current).unwrap();
+        arr[layout::LEFT_CHILD_TYPE_START] = 9;
+        assert!(InternalNode::from_slice(&arr, &current).is_err());
+
+        assert!(layout::NodeLayout::for_version(layout::FORMAT_VERSION + 1).is_err());
+    }
+
+    #[test]
+    fn quick_packed_nodes() {
+
+        let mut pager = FastNodePager::new();
+
+        for i in 0..1000 {
+            let mut node = InternalNode::default();
+            node.left_child_pointer = PagePointer::Leaf(2 * i);
+            node.right_child_pointer = PagePointer::Node(2 * i + 1);
+            node.split_axis = i % 700;
+            node.split_value = i as f32 / 3.0;
+            pager.add_node(&node).unwrap();
+        }
+
+        let node_layout = layout::NodeLayout::current();
+
+        for format in [NodeFileFormat::Portable, NodeFileFormat::Packed] {
+
+            let filename = "test_data/packed_node".to_string();
+            pager.to_file(&filename, &node_layout, format).unwrap();
+
+            //both readers take either format
+            let read = FastNodePager::from_file(&filename, &node_layout).unwrap();
+            assert_eq!(read.store, pager.store);
+
+            let immut = ImmutNodePager::from_file(&filename, &node_layout).unwrap();
+            assert_eq!(immut.len(), pager.len());
+            for (i, node) in pager.store.iter().enumerate() {
+                assert_eq!(&immut.get_node(&i).unwrap(), node);
+            }
+            assert!(immut.get_node(&pager.len()).is_err());
+        }
+
+        //a file from a machine with the other byte order is refused rather than misread
+        let filename = "test_data/packed_node".to_string();
+        pager.to_file(&filename, &node_layout, NodeFileFormat::Packed).unwrap();
+        let mut data = std::fs::read(&filename).unwrap();
+        data[layout::PACKED_ENDIAN_TAG_OFFSET..layout::PACKED_ENDIAN_TAG_OFFSET + layout::PACKED_ENDIAN_TAG_SIZE].reverse();
+        std::fs::write(&filename, &data).unwrap();
+
+        assert!(FastNodePager::from_file(&filename, &node_layout).is_err());
+        assert!(ImmutNodePager::from_file(&filename, &node_layout).is_err());
+    }
+}
@@ -45,12 +45,55 @@ pub const RIGHT_CHILD_TYPE_START: usize = RIGHT_CHILD_INDEX_START + RIGHT_CHILD_
 pub const RIGHT_CHILD_TYPE_SIZE: usize = 1;
 
 pub const SPLIT_AXIS_OFFSET: usize = RIGHT_CHILD_TYPE_START + RIGHT_CHILD_TYPE_SIZE;
-pub const SPLIT_AXIS_SIZE: usize = 1;
+pub const SPLIT_AXIS_SIZE: usize = 2;
+pub const LEGACY_SPLIT_AXIS_SIZE: usize = 1;
 
-pub const SPLIT_VALUE_OFFSET: usize = SPLIT_AXIS_OFFSET + SPLIT_AXIS_SIZE;
 pub const SPLIT_VALUE_SIZE: usize = 4;
 
-pub const NODE_SIZE: usize = SPLIT_VALUE_OFFSET + SPLIT_VALUE_SIZE;
+///Node format of trees built before split axes got two bytes. Still read, never written.
+pub const LEGACY_FORMAT_VERSION: u32 = 1;
+///Node format new trees are written in
+pub const FORMAT_VERSION: u32 = 2;
+
+///Where the split axis and value sit in a node, which depends on the tree's format version.
+///Everything before the split axis is the same in every version.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NodeLayout {
+    pub split_axis_size: usize,
+}
+
+impl NodeLayout {
+
+    pub fn for_version(version: u32) -> Result<Self, String> {
+
+        return match version {
+            LEGACY_FORMAT_VERSION => Ok(Self{split_axis_size: LEGACY_SPLIT_AXIS_SIZE}),
+            FORMAT_VERSION => Ok(Self{split_axis_size: SPLIT_AXIS_SIZE}),
+            x => Err(format!("Unsupported tree format version {}, this build reads versions {} to {}", x, LEGACY_FORMAT_VERSION, FORMAT_VERSION)),
+        }
+    }
+
+    pub fn current() -> Self {
+
+        return Self{split_axis_size: SPLIT_AXIS_SIZE};
+    }
+
+    pub fn split_value_offset(&self) -> usize {
+
+        return SPLIT_AXIS_OFFSET + self.split_axis_size;
+    }
+
+    pub fn node_size(&self) -> usize {
+
+        return self.split_value_offset() + SPLIT_VALUE_SIZE;
+    }
+
+    ///Number of distinct split axes, and so descriptor dimensions, a node can address
+    pub fn max_split_axes(&self) -> usize {
+
+        return 1 << (8 * self.split_axis_size);
+    }
+}
 
 
 //for TreeRecord
@@ -67,6 +110,8 @@ pub const PAGE_TYPE_SIZE: usize = 1;
 
 pub const TAIL_OFFSET: usize = PAGE_TYPE_OFFSET + PAGE_TYPE_SIZE;
 pub const TAIL_SIZE: usize = 4; //2 bytes supports up to 65535 records, but 4 is easier to implement lol
+///Most records a page can count in its tail
+pub const MAX_PAGE_RECORDS: usize = u32::MAX as usize;
 
 pub const IS_EMPTY_OFFSET: usize = TAIL_OFFSET + TAIL_SIZE;
 pub const IS_EMPTY_SIZE: usize = 1;
@@ -79,6 +124,23 @@ pub const HEADER_CURSOR_SIZE: usize = PTR_SIZE;
 
 pub const FILE_DATA_START: usize = HEADER_CURSOR_START + HEADER_CURSOR_SIZE;
 
+//for packed node files, which hold `PackedNode`s in native byte order so they can be mapped as is
+pub const PACKED_NODE_MAGIC: &[u8; 8] = b"SSNODEPK";
+
+pub const PACKED_ENDIAN_TAG_OFFSET: usize = PACKED_NODE_MAGIC.len();
+pub const PACKED_ENDIAN_TAG_SIZE: usize = 4;
+///Written in native byte order, so a reader with the other byte order sees it reversed
+pub const PACKED_ENDIAN_TAG: u32 = 0x01020304;
+
+pub const PACKED_NODE_SIZE_OFFSET: usize = PACKED_ENDIAN_TAG_OFFSET + PACKED_ENDIAN_TAG_SIZE;
+pub const PACKED_NODE_SIZE_SIZE: usize = 4;
+
+pub const PACKED_COUNT_OFFSET: usize = PACKED_NODE_SIZE_OFFSET + PACKED_NODE_SIZE_SIZE;
+pub const PACKED_COUNT_SIZE: usize = 8;
+
+///A multiple of the node alignment, so nodes in a mapped file are aligned
+pub const PACKED_DATA_START: usize = PACKED_COUNT_OFFSET + PACKED_COUNT_SIZE;
+
 
 
 
@@ -10,11 +10,15 @@
 //! Collections of internal nodes and collections of compound records are organized into 4kb disk
 //! pages, which can be parsed into Rust structs, updated, and written back to disk.
 //!
+//! Bit vector fingerprints (Morgan/ECFP) are too high dimensional for the kd tree, and are indexed
+//! separately by popcount in `fingerprint`, selected with `index_type` in the `TreeConfig`.
+//!
 //! TODO
 //! - [x] prototype tree construction and querying with tests
-//! - [ ] explore alternate construction algorithms
+//! - [x] explore alternate construction algorithms
 //! - [ ] implement parallel querying
 //! - [x] implement server with whole tree in memory
+//! - [x] specialize distance kernels for common descriptor lengths
 //! - [ ] make descriptor size generic
 //!
 //!
@@ -28,4 +32,8 @@ pub mod page;
 pub mod tree;
 pub mod decision_tree;
 pub mod database;
-pub mod data;
\ No newline at end of file
+pub mod data;
+pub mod encoding;
+pub mod fingerprint;
+pub mod split;
+pub mod attributes;
\ No newline at end of file
@@ -6,14 +6,12 @@
 use rand::prelude::*;
 
 use crate::error::Error;
-use crate::data::{Parser};
 use crate::page::PageType;
 use crate::layout;
 
 use byteorder::{ByteOrder, BigEndian};
 use std::fmt;
 
-use std::convert::TryInto;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum PagePointer {
@@ -41,6 +39,25 @@ impl fmt::Display for PagePointer {
 }
 
 
+impl PagePointer {
+
+    pub fn new(page_type: PageType, index: usize) -> Self {
+
+        return match page_type {
+            PageType::Node => PagePointer::Node(index),
+            PageType::Leaf => PagePointer::Leaf(index),
+        }
+    }
+
+    pub fn to_parts(&self) -> (PageType, usize) {
+
+        return match self {
+            PagePointer::Node(x) => (PageType::Node, *x),
+            PagePointer::Leaf(x) => (PageType::Leaf, *x),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum NodeType{
     Internal,
@@ -82,69 +99,161 @@ impl InternalNode {
                         self.right_child_pointer)
     }
 
-    pub fn from_slice(node_slice: &[u8]) -> Result<InternalNode, String> {
+    pub fn from_slice(node_slice: &[u8], node_layout: &layout::NodeLayout) -> Result<InternalNode, String> {
 
-        let left_child_index = Parser::get_usize_from_array(node_slice, layout::LEFT_CHILD_INDEX_START, layout::LEFT_CHILD_INDEX_SIZE);
-        let left_child_type = Parser::get_usize_from_array(node_slice, layout::LEFT_CHILD_TYPE_START, layout::LEFT_CHILD_TYPE_SIZE);
-
-        let right_child_index = Parser::get_usize_from_array(node_slice, layout::RIGHT_CHILD_INDEX_START, layout::RIGHT_CHILD_INDEX_SIZE);
-        let right_child_type = Parser::get_usize_from_array(node_slice, layout::RIGHT_CHILD_TYPE_START, layout::RIGHT_CHILD_TYPE_SIZE);
+        if node_slice.len() < node_layout.node_size() {
+            return Err(format!("Node needs {} bytes, got {}", node_layout.node_size(), node_slice.len()));
+        }
 
-        let split_axis = Parser::get_usize_from_array(node_slice, layout::SPLIT_AXIS_OFFSET, layout::SPLIT_AXIS_SIZE);
-        let split_value = Parser::get_f32_from_array(node_slice, layout::SPLIT_VALUE_OFFSET);
+        let left_child_index = BigEndian::read_u64(&node_slice[layout::LEFT_CHILD_INDEX_START..layout::LEFT_CHILD_INDEX_START + layout::LEFT_CHILD_INDEX_SIZE]) as usize;
+        let left_child_type = PageType::try_from(node_slice[layout::LEFT_CHILD_TYPE_START])?;
 
-        let mut node = InternalNode::default();
+        let right_child_index = BigEndian::read_u64(&node_slice[layout::RIGHT_CHILD_INDEX_START..layout::RIGHT_CHILD_INDEX_START + layout::RIGHT_CHILD_INDEX_SIZE]) as usize;
+        let right_child_type = PageType::try_from(node_slice[layout::RIGHT_CHILD_TYPE_START])?;
 
-        node.left_child_pointer = match left_child_type.unwrap() {
-            1 => {PagePointer::Node(left_child_index.unwrap())},
-            2 => {PagePointer::Leaf(left_child_index.unwrap())},
-            _ => {panic!()},
+        let split_axis_slice = &node_slice[layout::SPLIT_AXIS_OFFSET..layout::SPLIT_AXIS_OFFSET + node_layout.split_axis_size];
+        let split_axis = match node_layout.split_axis_size {
+            1 => split_axis_slice[0] as usize,
+            2 => BigEndian::read_u16(split_axis_slice) as usize,
+            x => {return Err(format!("Unsupported split axis width: {} bytes", x))},
         };
 
-        node.right_child_pointer = match right_child_type.unwrap() {
-            1 => {PagePointer::Node(right_child_index.unwrap())},
-            2 => {PagePointer::Leaf(right_child_index.unwrap())},
-            _ => {panic!()},
-        };
+        let split_value = BigEndian::read_f32(&node_slice[node_layout.split_value_offset()..node_layout.split_value_offset() + layout::SPLIT_VALUE_SIZE]);
 
-        node.split_axis = split_axis.unwrap();
-        node.split_value = split_value.unwrap();
+        let node = InternalNode {
+            left_child_pointer: PagePointer::new(left_child_type, left_child_index),
+            right_child_pointer: PagePointer::new(right_child_type, right_child_index),
+            split_axis,
+            split_value,
+        };
 
         Ok(node)
     }
 
-    pub fn to_arr(&self) -> [u8; layout::NODE_SIZE] {
+    ///Errors rather than truncating if the split axis is too wide for `node_layout`
+    pub fn to_arr(&self, node_layout: &layout::NodeLayout) -> Result<Vec<u8>, String> {
+
+        if self.split_axis >= node_layout.max_split_axes() {
+            return Err(format!("Split axis {} does not fit in {} bytes", self.split_axis, node_layout.split_axis_size));
+        }
 
-        let mut arr: [u8; layout::NODE_SIZE] = [0; layout::NODE_SIZE];
+        let mut arr = vec![0u8; node_layout.node_size()];
 
-        let slice = &mut arr[layout::LEFT_CHILD_INDEX_START..layout::LEFT_CHILD_INDEX_START + layout::LEFT_CHILD_INDEX_SIZE];
+        let (node_type, value) = self.left_child_pointer.to_parts();
+        BigEndian::write_u64(&mut arr[layout::LEFT_CHILD_INDEX_START..layout::LEFT_CHILD_INDEX_START + layout::LEFT_CHILD_INDEX_SIZE], value as u64);
+        arr[layout::LEFT_CHILD_TYPE_START] = node_type as u8;
 
-        let (node_type, value) = match self.left_child_pointer {
-            PagePointer::Node(x) => (1, x.try_into().unwrap()),
-            PagePointer::Leaf(x) => (2, x.try_into().unwrap()),
-        };
-        BigEndian::write_u64(slice, value);
+        let (node_type, value) = self.right_child_pointer.to_parts();
+        BigEndian::write_u64(&mut arr[layout::RIGHT_CHILD_INDEX_START..layout::RIGHT_CHILD_INDEX_START + layout::RIGHT_CHILD_INDEX_SIZE], value as u64);
+        arr[layout::RIGHT_CHILD_TYPE_START] = node_type as u8;
 
+        let split_axis_slice = &mut arr[layout::SPLIT_AXIS_OFFSET..layout::SPLIT_AXIS_OFFSET + node_layout.split_axis_size];
+        match node_layout.split_axis_size {
+            1 => split_axis_slice[0] = self.split_axis as u8,
+            2 => BigEndian::write_u16(split_axis_slice, self.split_axis as u16),
+            x => {return Err(format!("Unsupported split axis width: {} bytes", x))},
+        }
 
-        arr[layout::LEFT_CHILD_TYPE_START] = node_type as u8;
+        let split_value_offset = node_layout.split_value_offset();
+        BigEndian::write_f32(&mut arr[split_value_offset..split_value_offset + layout::SPLIT_VALUE_SIZE], self.split_value);
 
-        let slice = &mut arr[layout::RIGHT_CHILD_INDEX_START..layout::RIGHT_CHILD_INDEX_START + layout::RIGHT_CHILD_INDEX_SIZE];
+        return Ok(arr)
+    }
+}
 
-        let (node_type, value) = match self.right_child_pointer {
-            PagePointer::Node(x) => (1, x.try_into().unwrap()),
-            PagePointer::Leaf(x) => (2, x.try_into().unwrap()),
-        };
-        BigEndian::write_u64(slice, value);
 
-        arr[layout::RIGHT_CHILD_TYPE_START] = node_type as u8;
+///Top bit of a packed child index, set when the child is a record page
+const PACKED_LEAF_BIT: u64 = 1 << 63;
 
+///An `InternalNode` as it sits in a packed node file: fixed size, aligned, no padding and in
+///native byte order, so a file of them can be viewed as a slice without parsing.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PackedNode {
+    pub left_child: u64,
+    pub right_child: u64,
+    pub split_axis: u32,
+    pub split_value: f32,
+}
 
-        arr[layout::SPLIT_AXIS_OFFSET] = self.split_axis as u8;
+const _: () = assert!(std::mem::size_of::<PackedNode>() == 24);
+const _: () = assert!(layout::PACKED_DATA_START.is_multiple_of(std::mem::align_of::<PackedNode>()));
 
-        let slice = &mut arr[layout::SPLIT_VALUE_OFFSET..layout::SPLIT_VALUE_OFFSET + layout::SPLIT_VALUE_SIZE];
-        BigEndian::write_f32(slice, self.split_value.try_into().unwrap());
+impl PackedNode {
 
-        return arr
+    fn pack_pointer(pointer: &PagePointer) -> Result<u64, String> {
+
+        let (page_type, index) = pointer.to_parts();
+        let index = index as u64;
+
+        if index & PACKED_LEAF_BIT != 0 {
+            return Err(format!("Child index {} is too large to pack", index));
+        }
+
+        return match page_type {
+            PageType::Node => Ok(index),
+            PageType::Leaf => Ok(index | PACKED_LEAF_BIT),
+        }
+    }
+
+    fn unpack_pointer(value: u64) -> PagePointer {
+
+        return match value & PACKED_LEAF_BIT {
+            0 => PagePointer::Node(value as usize),
+            _ => PagePointer::Leaf((value & !PACKED_LEAF_BIT) as usize),
+        }
+    }
+
+    pub fn from_node(node: &InternalNode) -> Result<Self, String> {
+
+        let split_axis: u32 = node.split_axis.try_into().map_err(|_| format!("Split axis {} is too large to pack", node.split_axis))?;
+
+        return Ok(Self {
+            left_child: Self::pack_pointer(&node.left_child_pointer)?,
+            right_child: Self::pack_pointer(&node.right_child_pointer)?,
+            split_axis,
+            split_value: node.split_value,
+        });
+    }
+
+    pub fn to_node(&self) -> InternalNode {
+
+        return InternalNode {
+            left_child_pointer: Self::unpack_pointer(self.left_child),
+            right_child_pointer: Self::unpack_pointer(self.right_child),
+            split_axis: self.split_axis as usize,
+            split_value: self.split_value,
+        }
     }
-}
 
+    ///Views bytes holding whole packed nodes as nodes. Errors if they're misaligned or don't
+    ///hold a whole number of nodes.
+    pub fn slice_from_bytes(bytes: &[u8]) -> Result<&[PackedNode], String> {
+
+        let node_size = std::mem::size_of::<PackedNode>();
+
+        if bytes.as_ptr().align_offset(std::mem::align_of::<PackedNode>()) != 0 {
+            return Err("Packed nodes are misaligned".to_string());
+        }
+
+        if !bytes.len().is_multiple_of(node_size) {
+            return Err(format!("{} bytes is not a whole number of packed nodes", bytes.len()));
+        }
+
+        //aligned, sized, and every bit pattern is a valid PackedNode
+        return Ok(unsafe { std::slice::from_raw_parts(bytes.as_ptr() as *const PackedNode, bytes.len() / node_size) });
+    }
+
+    ///Views nodes as the bytes they're stored as
+    pub fn slice_as_bytes(nodes: &[PackedNode]) -> &[u8] {
+
+        //PackedNode has no padding, so every byte is initialized
+        return unsafe { std::slice::from_raw_parts(nodes.as_ptr() as *const u8, std::mem::size_of_val(nodes)) };
+    }
+
+    ///`slice_as_bytes`, mutably, for reading nodes straight into an allocated buffer
+    pub fn slice_as_bytes_mut(nodes: &mut [PackedNode]) -> &mut [u8] {
+
+        return unsafe { std::slice::from_raw_parts_mut(nodes.as_mut_ptr() as *mut u8, std::mem::size_of_val(nodes)) };
+    }
+}
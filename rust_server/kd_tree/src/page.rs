@@ -3,8 +3,10 @@
 //!
 //!
 
-use crate::tree::{TreeRecord};
-use crate::data::{Descriptor};
+use crate::tree::{TreeRecord, TopHits};
+use crate::data::{CompoundIndex, Descriptor};
+use crate::encoding::{DescriptorCodec, PreparedQuery};
+use crate::node::PagePointer;
 use crate::layout;
 
 #[derive(Debug, Clone, PartialEq)]
@@ -13,6 +15,19 @@ pub enum PageType {
     Leaf = 2,
 }
 
+impl TryFrom<u8> for PageType {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+
+        return match value {
+            1 => Ok(PageType::Node),
+            2 => Ok(PageType::Leaf),
+            x => Err(format!("Unknown page type: {}", x)),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct RecordPage {
 
@@ -20,17 +35,24 @@ pub struct RecordPage {
     pub tail: Option<usize>,
     pub desc_length: usize,
     pub page_length: usize,
+    pub codec: DescriptorCodec,
 }
 
 impl RecordPage {
 
     pub fn new(page_length: usize, desc_length: usize) -> Self {
+
+        return Self::with_codec(page_length, &DescriptorCodec::f32(desc_length));
+    }
+
+    pub fn with_codec(page_length: usize, codec: &DescriptorCodec) -> Self {
         
         let mut s = Self {
             data: vec![0u8; page_length],
             tail: Some(0),
-            desc_length,
+            desc_length: codec.length,
             page_length,
+            codec: codec.clone(),
         };
 
         s.data[layout::PAGE_TYPE_OFFSET] = PageType::Leaf as u8;
@@ -42,7 +64,7 @@ impl RecordPage {
         &self.data
     }
 
-    pub fn from_arr(arr: &[u8], page_length: usize, desc_length: usize) -> Self {
+    pub fn from_arr(arr: &[u8], page_length: usize, codec: &DescriptorCodec) -> Self {
 
         let tail = Some(u32::from_be_bytes(arr[layout::TAIL_OFFSET..layout::TAIL_OFFSET+layout::TAIL_SIZE].try_into().unwrap()) as usize);
 
@@ -52,21 +74,28 @@ impl RecordPage {
         let page = Self {
             data: vec,
             tail,
-            desc_length,
+            desc_length: codec.length,
             page_length,
+            codec: codec.clone(),
         };
         return page;
     }
 
     pub fn descriptor_in_page(&self, query_desc: &Descriptor) -> bool {
 
+        return self.find_descriptor(query_desc).is_some();
+    }
+
+    ///Index of the first record in the page with exactly this descriptor
+    pub fn find_descriptor(&self, query_desc: &Descriptor) -> Option<CompoundIndex> {
+
         for record in self.get_records() {
             if record.descriptor == *query_desc {
-                return true;
+                return Some(record.index);
             }
         }
 
-        return false;
+        return None;
     }
 
 
@@ -74,7 +103,7 @@ impl RecordPage {
 
         let mut v: Vec::<TreeRecord> = Vec::with_capacity(self.get_capacity());
 
-        for offset in 0..10000 {
+        for offset in 0..self.len() {
 
             let record_result = self.get_record_at(offset);
 
@@ -89,6 +118,46 @@ impl RecordPage {
     }
 
 
+    ///Offers every record on the page to `hits`. Distances are computed straight from the page
+    ///bytes and compared squared; only records close enough to be kept are decoded.
+    pub fn scan_into(&self, query: &PreparedQuery, hits: &mut TopHits, page_pointer: &PagePointer) -> Result<(), String> {
+
+        return self.scan_into_filtered(query, hits, page_pointer, &|_| true);
+    }
+
+    ///`scan_into`, skipping records whose index `accepts` turns down. It's only asked about records
+    ///close enough to be kept.
+    pub fn scan_into_filtered(&self, query: &PreparedQuery, hits: &mut TopHits, page_pointer: &PagePointer, accepts: &dyn Fn(CompoundIndex) -> bool) -> Result<(), String> {
+
+        let record_size = self.codec.record_size();
+
+        let highest = hits.get_highest_dist();
+        let mut threshold = highest * highest;
+
+        for offset in 0..self.len() {
+
+            let start = layout::PAGE_DATA_START + (offset * record_size);
+            let descriptor_bytes = &self.data[start + layout::DESCRIPTOR_START..start + record_size];
+
+            let squared_distance = self.codec.prepared_squared_distance(query, descriptor_bytes);
+
+            if squared_distance < threshold {
+                let record_slice = &self.data[start..start + record_size];
+
+                if !accepts(TreeRecord::decode_index(record_slice)) {
+                    continue;
+                }
+
+                hits.try_add_encoded(squared_distance.sqrt(), record_slice, &self.codec, page_pointer)?;
+
+                let highest = hits.get_highest_dist();
+                threshold = highest * highest;
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn add_record(&mut self, record: &TreeRecord) -> Result<(), String> {
 
         //dbg!("ADD CHECK");
@@ -97,12 +166,12 @@ impl RecordPage {
             false => {},
         }
 
-        let start = layout::PAGE_DATA_START + (self.tail.unwrap() * record.get_record_size());
-        let size = record.get_record_size();
+        let size = self.codec.record_size();
+        let start = layout::PAGE_DATA_START + (self.tail.unwrap() * size);
 
         let slice = &mut self.data[start..start + size];
 
-        slice.copy_from_slice(&record.to_vec());
+        record.encode_into(slice, &self.codec);
         self.tail = match self.tail {
             Some(x) => Some(x + 1),
             None => Some(0),
@@ -123,11 +192,11 @@ impl RecordPage {
             return Err("Provided offset greater than number of nodes".to_string());
         }
         else {
-            let start = layout::PAGE_DATA_START + (offset * TreeRecord::compute_record_size(self.desc_length)); 
-            let size = TreeRecord::compute_record_size(self.desc_length);
+            let start = layout::PAGE_DATA_START + (offset * self.codec.record_size()); 
+            let size = self.codec.record_size();
             let slice = &self.data[start..start + size];
 
-            let cr = TreeRecord::from_slice(slice, self.desc_length);
+            let cr = TreeRecord::decode(slice, &self.codec);
             return cr;
         }
 
@@ -143,7 +212,7 @@ impl RecordPage {
 
     pub fn get_capacity(&self) -> usize {
 
-        return (self.page_length - layout::PAGE_DATA_START) / TreeRecord::compute_record_size(self.desc_length);
+        return (self.page_length - layout::PAGE_DATA_START) / self.codec.record_size();
 
     }
 
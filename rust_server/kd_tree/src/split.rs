@@ -0,0 +1,453 @@
+//! How a full record page chooses its split axis and value
+//!
+//! The original tree cycles through the axes and splits at the median, which wastes splits on
+//! near-constant dimensions when descriptor dimensions have very different variances. The other
+//! strategies pick the axis from the page's records instead:
+//!
+//! - `MaxVariance` and `MaxSpread` split at the median of the most variable / widest axis
+//! - `SlidingMidpoint` halves the longest side of the leaf's cell, sliding the split to the nearest
+//!   record if one side would be empty
+//! - `PcaRotated` rotates every descriptor onto the data's principal axes before it goes into the
+//!   tree, then splits like `MaxVariance`. Rotations don't change distances, so queries are rotated
+//!   the same way and results rotated back.
+//!
+//! Selected with `split_strategy` in the `TreeConfig`.
+
+use serde::{Serialize, Deserialize};
+
+use crate::data::Descriptor;
+use crate::tree::TreeRecord;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SplitStrategy {
+    ///Next axis after the parent's, at the median
+    #[default]
+    Cyclic,
+    ///Axis with the highest variance in the page, at the median
+    MaxVariance,
+    ///Axis with the widest range in the page, at the median
+    MaxSpread,
+    ///Longest side of the leaf's cell, at its midpoint
+    SlidingMidpoint,
+    ///`MaxVariance` on descriptors rotated onto their principal axes
+    PcaRotated,
+}
+
+///Bounds of the region of space a leaf covers, narrowed by each split on the way down to it.
+///Sides that no split has bounded yet are infinite.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cell {
+    pub lower: Vec<f32>,
+    pub upper: Vec<f32>,
+}
+
+impl Cell {
+
+    pub fn unbounded(length: usize) -> Self {
+        return Self {
+            lower: vec![f32::NEG_INFINITY; length],
+            upper: vec![f32::INFINITY; length],
+        }
+    }
+
+    ///Narrows the cell to one child of a node. Split values are inside both children.
+    pub fn descend(&mut self, axis: usize, split_value: f32, left: bool) {
+
+        match left {
+            true => self.upper[axis] = self.upper[axis].min(split_value),
+            false => self.lower[axis] = self.lower[axis].max(split_value),
+        }
+    }
+}
+
+///Picks an axis and value that put records on both sides of the split, with records equal to
+///the value going left. `None` if every record is identical. `Tree::split` may still balance ties
+///across both sides.
+pub fn choose_split(strategy: SplitStrategy, records: &[TreeRecord], parent_axis: Option<usize>, cell: &Cell) -> Option<(usize, f32)> {
+
+    if records.is_empty() {
+        return None;
+    }
+
+    let length = records[0].length;
+
+    let columns: Vec<Vec<f32>> = (0..length).map(|axis| {
+        let mut values: Vec<f32> = records.iter().map(|x| x.descriptor.data[axis]).collect();
+        values.sort_by(|a, b| a.total_cmp(b));
+        values
+    }).collect();
+
+    //preferred axis first. If it can't divide the records, e.g. a lossy encoding left them all tied,
+    //fall back to the next one
+    let axes: Vec<usize> = match strategy {
+        SplitStrategy::Cyclic => {
+            let start = match parent_axis {
+                Some(x) => (x + 1) % length,
+                None => 0,
+            };
+            (0..length).map(|i| (start + i) % length).collect()
+        },
+        SplitStrategy::MaxVariance | SplitStrategy::PcaRotated => axes_by_score(&columns, variance),
+        SplitStrategy::MaxSpread => axes_by_score(&columns, spread),
+        SplitStrategy::SlidingMidpoint => axes_by_score(&columns, |axis, values| cell_extent(cell, axis, values)),
+    };
+
+    for axis in axes {
+
+        let value = match strategy {
+            SplitStrategy::SlidingMidpoint => sliding_midpoint(cell, axis, &columns[axis]),
+            _ => split_value_for(&columns[axis]),
+        };
+
+        if let Some(x) = value {
+            return Some((axis, x));
+        }
+    }
+
+    return None;
+}
+
+///Axes sorted by `score` of their sorted values, highest first
+fn axes_by_score(columns: &[Vec<f32>], score: impl Fn(usize, &[f32]) -> f32) -> Vec<usize> {
+
+    let scores: Vec<f32> = columns.iter().enumerate().map(|(axis, values)| score(axis, values)).collect();
+
+    let mut axes: Vec<usize> = (0..columns.len()).collect();
+    axes.sort_by(|a, b| scores[*b].total_cmp(&scores[*a]));
+
+    return axes;
+}
+
+fn variance(_axis: usize, values: &[f32]) -> f32 {
+
+    let mean = values.iter().sum::<f32>() / values.len() as f32;
+
+    return values.iter().map(|x| (x - mean) * (x - mean)).sum::<f32>() / values.len() as f32;
+}
+
+fn spread(_axis: usize, values: &[f32]) -> f32 {
+    return values[values.len() - 1] - values[0];
+}
+
+///Side of the cell along `axis`, with unbounded sides cut off at the outermost record
+fn cell_bounds(cell: &Cell, axis: usize, values: &[f32]) -> (f32, f32) {
+
+    let lower = match cell.lower[axis].is_finite() {
+        true => cell.lower[axis],
+        false => values[0],
+    };
+
+    let upper = match cell.upper[axis].is_finite() {
+        true => cell.upper[axis],
+        false => values[values.len() - 1],
+    };
+
+    return (lower, upper);
+}
+
+fn cell_extent(cell: &Cell, axis: usize, values: &[f32]) -> f32 {
+
+    let (lower, upper) = cell_bounds(cell, axis, values);
+
+    return upper - lower;
+}
+
+fn sliding_midpoint(cell: &Cell, axis: usize, values: &[f32]) -> Option<f32> {
+
+    let (min, max) = (values[0], values[values.len() - 1]);
+
+    if min == max {
+        return None;
+    }
+
+    let (lower, upper) = cell_bounds(cell, axis, values);
+    let midpoint = (lower + upper) / 2.0;
+
+    //slide towards the records until one is on the near side
+    if midpoint < min {
+        return Some(min);
+    }
+
+    if midpoint >= max {
+        return values.iter().rev().find(|x| **x < max).copied();
+    }
+
+    return Some(midpoint);
+}
+
+///The median of sorted `values` if it has values on both sides, otherwise the boundary between
+///distinct values closest to the middle. `None` if all values are equal.
+pub fn split_value_for(values: &[f32]) -> Option<f32> {
+
+    let median = match values.len() % 2 {
+        0 => {
+            let idx_b: usize  = values.len() / 2;
+            let idx_a = idx_b - 1;
+
+            (values[idx_a] + values[idx_b]) / 2.0
+        },
+        _ => {
+            let idx: usize  = values.len() / 2;
+            values[idx]
+        },
+    };
+
+    if values[0] <= median && values[values.len() - 1] > median {
+        return Some(median);
+    }
+
+    //values[i] < values[i + 1], so splitting at values[i] puts records on both sides
+    let middle = values.len() / 2;
+    let boundary = (0..values.len() - 1)
+        .filter(|i| values[*i] < values[*i + 1])
+        .min_by_key(|i| (*i as isize - middle as isize).abs());
+
+    return boundary.map(|i| values[i]);
+}
+
+///Orthonormal rotation onto the principal axes of a dataset, highest variance first
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rotation {
+    ///Unit length, mutually orthogonal rows
+    pub axes: Vec<Vec<f32>>,
+}
+
+impl Rotation {
+
+    pub fn rotate(&self, descriptor: &Descriptor) -> Descriptor {
+
+        let data: Vec<f32> = self.axes.iter()
+            .map(|axis| axis.iter().zip(descriptor.data.iter()).map(|(a, b)| a * b).sum())
+            .collect();
+
+        return Descriptor { data, length: descriptor.length };
+    }
+
+    ///Inverse of `rotate`, up to float rounding
+    pub fn unrotate(&self, descriptor: &Descriptor) -> Descriptor {
+
+        let mut data = vec![0.0f32; descriptor.length];
+        for (axis, value) in self.axes.iter().zip(descriptor.data.iter()) {
+            for (x, a) in data.iter_mut().zip(axis.iter()) {
+                *x += a * value;
+            }
+        }
+
+        return Descriptor { data, length: descriptor.length };
+    }
+}
+
+///Accumulates a covariance matrix in one pass over the data, to fit a `Rotation`
+#[derive(Debug, Clone)]
+pub struct PcaFitter {
+    count: u64,
+    sums: Vec<f64>,
+    products: Vec<Vec<f64>>,
+}
+
+impl PcaFitter {
+
+    pub fn new(length: usize) -> Self {
+        return Self {
+            count: 0,
+            sums: vec![0.0; length],
+            products: vec![vec![0.0; length]; length],
+        }
+    }
+
+    pub fn update(&mut self, descriptor: &Descriptor) {
+
+        self.count += 1;
+
+        for (i, x) in descriptor.data.iter().enumerate() {
+            self.sums[i] += *x as f64;
+            for (j, y) in descriptor.data.iter().enumerate().skip(i) {
+                self.products[i][j] += *x as f64 * *y as f64;
+            }
+        }
+    }
+
+    pub fn fit(&self) -> Result<Rotation, String> {
+
+        if self.count < 2 {
+            return Err("Need at least two descriptors to fit a rotation".to_string());
+        }
+
+        let length = self.sums.len();
+        let n = self.count as f64;
+
+        //only the upper triangle of products is accumulated
+        let covariance: Vec<Vec<f64>> = (0..length).map(|i| (0..length).map(|j| {
+            let (i, j) = (i.min(j), i.max(j));
+            (self.products[i][j] - self.sums[i] * self.sums[j] / n) / (n - 1.0)
+        }).collect()).collect();
+
+        let (eigenvalues, eigenvectors) = symmetric_eigen(covariance);
+
+        let mut order: Vec<usize> = (0..length).collect();
+        order.sort_by(|a, b| eigenvalues[*b].total_cmp(&eigenvalues[*a]));
+
+        let axes: Vec<Vec<f32>> = order.iter()
+            .map(|k| (0..length).map(|i| eigenvectors[i][*k] as f32).collect())
+            .collect();
+
+        return Ok(Rotation { axes });
+    }
+}
+
+///Cyclic Jacobi eigenvalue algorithm. Returns the eigenvalues and a matrix with the matching
+///eigenvectors as columns.
+fn symmetric_eigen(mut a: Vec<Vec<f64>>) -> (Vec<f64>, Vec<Vec<f64>>) {
+
+    let n = a.len();
+
+    let mut v = vec![vec![0.0f64; n]; n];
+    for (i, row) in v.iter_mut().enumerate() {
+        row[i] = 1.0;
+    }
+
+    for _ in 0..100 {
+
+        let off_diagonal: f64 = a.iter().enumerate()
+            .map(|(i, row)| row.iter().enumerate().filter(|(j, _)| *j != i).map(|(_, x)| x * x).sum::<f64>())
+            .sum();
+
+        if off_diagonal < 1e-24 {
+            break;
+        }
+
+        for p in 0..n {
+            for q in p + 1..n {
+
+                if a[p][q].abs() < 1e-300 {
+                    continue;
+                }
+
+                let theta = (a[q][q] - a[p][p]) / (2.0 * a[p][q]);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+                let t = match theta == 0.0 {
+                    true => 1.0,
+                    false => t,
+                };
+                let c = 1.0 / (t * t + 1.0).sqrt();
+                let s = t * c;
+
+                for row in a.iter_mut() {
+                    let (akp, akq) = (row[p], row[q]);
+                    row[p] = c * akp - s * akq;
+                    row[q] = s * akp + c * akq;
+                }
+
+                let (row_p, row_q) = (a[p].clone(), a[q].clone());
+                a[p] = row_p.iter().zip(row_q.iter()).map(|(apk, aqk)| c * apk - s * aqk).collect();
+                a[q] = row_p.iter().zip(row_q.iter()).map(|(apk, aqk)| s * apk + c * aqk).collect();
+
+                for row in v.iter_mut() {
+                    let (vkp, vkq) = (row[p], row[q]);
+                    row[p] = c * vkp - s * vkq;
+                    row[q] = s * vkp + c * vkq;
+                }
+            }
+        }
+    }
+
+    let eigenvalues: Vec<f64> = (0..n).map(|i| a[i][i]).collect();
+
+    return (eigenvalues, v);
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn records_from(rows: Vec<Vec<f32>>) -> Vec<TreeRecord> {
+
+        return rows.into_iter().enumerate().map(|(i, data)| {
+            let length = data.len();
+            TreeRecord { index: i as u64, descriptor: Descriptor::from_vec(data, length), length }
+        }).collect();
+    }
+
+    #[test]
+    fn quick_split_strategies() {
+
+        //axis 1 varies the most, axis 2 has the widest range because of one outlier
+        let records = records_from(vec![
+            vec![0.0, -4.0, 0.0],
+            vec![0.1, -4.0, 0.0],
+            vec![0.2, 4.0, 0.0],
+            vec![0.3, 4.0, 9.0],
+        ]);
+
+        let cell = Cell::unbounded(3);
+
+        assert_eq!(choose_split(SplitStrategy::Cyclic, &records, Some(0), &cell), Some((1, 0.0)));
+        assert_eq!(choose_split(SplitStrategy::Cyclic, &records, None, &cell), Some((0, (0.1 + 0.2) / 2.0)));
+        assert_eq!(choose_split(SplitStrategy::MaxVariance, &records, Some(0), &cell), Some((1, 0.0)));
+        assert_eq!(choose_split(SplitStrategy::MaxSpread, &records, Some(0), &cell), Some((2, 0.0)));
+        assert_eq!(choose_split(SplitStrategy::SlidingMidpoint, &records, None, &cell), Some((2, 4.5)));
+
+        //a cell much wider than the records on axis 0 makes it the longest side, and the midpoint
+        //slides onto the closest record
+        let mut cell = Cell::unbounded(3);
+        cell.descend(0, -100.0, false);
+        cell.descend(0, 50.0, true);
+        assert_eq!(choose_split(SplitStrategy::SlidingMidpoint, &records, None, &cell), Some((0, 0.0)));
+
+        //tied axes are skipped
+        let tied = records_from(vec![vec![1.0, 2.0], vec![1.0, 3.0], vec![1.0, 3.0]]);
+        assert_eq!(choose_split(SplitStrategy::Cyclic, &tied, Some(1), &Cell::unbounded(2)), Some((1, 2.0)));
+
+        let identical = records_from(vec![vec![1.0, 2.0]; 4]);
+        for strategy in [SplitStrategy::Cyclic, SplitStrategy::MaxVariance, SplitStrategy::MaxSpread, SplitStrategy::SlidingMidpoint] {
+            assert_eq!(choose_split(strategy, &identical, None, &Cell::unbounded(2)), None);
+        }
+    }
+
+    #[test]
+    fn quick_pca_rotation() {
+
+        //points along the (1, 1) diagonal with a little noise across it
+        let mut fitter = PcaFitter::new(2);
+        let mut descriptors: Vec<Descriptor> = Vec::new();
+        for i in 0..100 {
+            let t = i as f32 / 10.0 - 5.0;
+            for noise in [0.1, -0.1] {
+                let descriptor = Descriptor::from_vec(vec![t + noise, t - noise], 2);
+                fitter.update(&descriptor);
+                descriptors.push(descriptor);
+            }
+        }
+
+        let rotation = fitter.fit().unwrap();
+
+        let first = &rotation.axes[0];
+        assert!((first[0].abs() - 0.5f32.sqrt()).abs() < 1e-4);
+        assert!((first[0] - first[1]).abs() < 1e-4);
+
+        for a in descriptors.iter() {
+            let rotated = rotation.rotate(a);
+            let back = rotation.unrotate(&rotated);
+            assert!((back.data[0] - a.data[0]).abs() < 1e-4 && (back.data[1] - a.data[1]).abs() < 1e-4);
+
+            let b = &descriptors[7];
+            assert!((rotated.distance(&rotation.rotate(b)) - a.distance(b)).abs() < 1e-4);
+        }
+
+        let random: Vec<Descriptor> = (0..500).map(|_| Descriptor::random(6)).collect();
+        let mut fitter = PcaFitter::new(6);
+        for descriptor in random.iter() {
+            fitter.update(descriptor);
+        }
+        let rotation = fitter.fit().unwrap();
+        for i in 0..6 {
+            for j in 0..6 {
+                let dot: f32 = rotation.axes[i].iter().zip(rotation.axes[j].iter()).map(|(a, b)| a * b).sum();
+                let expected = match i == j { true => 1.0, false => 0.0 };
+                assert!((dot - expected).abs() < 1e-4);
+            }
+        }
+    }
+}
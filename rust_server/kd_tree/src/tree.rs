@@ -1,12 +1,16 @@
 //! Implementation of kd-tree creation and querying
 extern crate test;
 use crate::data::{CompoundIdentifier, Descriptor, CompoundRecord, CompoundIndex};
-use crate::database::{Database, ImmutDatabase};
+use crate::database::{dataset_names, Database, DatabaseFormat, ImmutDatabase, SourceMask};
 use crate::node::{InternalNode, PagePointer};
 use crate::page::RecordPage;
 use crate::layout;
-use crate::io::{DiskNodePager, FastNodePager, RecordPager, GetNode};
+use crate::io::{ImmutNodePager, FastNodePager, NodeFileFormat, RecordPager, GetNode};
 use crate::data::{Parser};
+use crate::encoding::{DescriptorCodec, DescriptorEncoding, ScalarQuantizer, ProductQuantizer, VectorStore};
+use crate::fingerprint::{Fingerprint, FingerprintStore};
+use crate::split::{choose_split, Cell, Rotation, SplitStrategy};
+use crate::attributes::{AttributeColumn, AttributeStore, AttributeValue, Filter};
 use std::collections::HashMap;
 use serde::{Serialize, Deserialize};
 use std::time::Instant;
@@ -20,12 +24,17 @@ use std::io::prelude::*;
 
 use std::path::Path;
 use std::collections::VecDeque;
+use std::collections::BinaryHeap;
+use std::cmp::Ordering;
 
 
 pub struct ImmutTree {
-    pub node_handler: DiskNodePager,
+    pub node_handler: ImmutNodePager,
     pub record_handler: RecordPager,
     pub database: ImmutDatabase,
+    pub vectors: Option<VectorStore>,
+    pub fingerprints: Option<FingerprintStore>,
+    pub attributes: Option<AttributeStore>,
     pub root: PagePointer,
     pub config: TreeConfig,
 }
@@ -35,17 +44,34 @@ impl ImmutTree {
     pub fn read_from_directory(directory_name: String) -> Self {
 
         let config_filename = directory_name.clone() + "/config.yaml";
-        let config = TreeConfig::from_file(config_filename);
+        let mut config = TreeConfig::from_file(config_filename);
+        config.load_codebook();
         dbg!(&config);
 
+        if config.index_type != IndexType::KdTree {
+            panic!("{} holds a {:?} index, not a kd tree", directory_name, config.index_type);
+        }
+
         let node_filename = config.get_node_filename();
         let record_filename = config.get_record_filename();
 
         dbg!(&node_filename);
         dbg!(&record_filename);
 
-        let node_handler = DiskNodePager::from_file(&node_filename).unwrap();
-        let record_handler = RecordPager::new(record_filename, config.record_page_length, config.desc_length, false, config.cache_limit).unwrap();
+        let node_handler = ImmutNodePager::from_file(&node_filename, &config.node_layout().unwrap()).unwrap();
+        let record_handler = RecordPager::new(record_filename, config.record_page_length, config.get_codec(), false, config.cache_limit).unwrap();
+
+        let vectors = match config.reranks() {
+            true => Some(VectorStore::open(&config.get_vectors_filename(), config.desc_length).unwrap()),
+            false => None,
+        };
+
+        let fingerprints = config.fingerprint_bits.map(|x| FingerprintStore::open(&config.get_fingerprints_filename(), x).unwrap());
+
+        let attributes = match config.attributes.is_empty() {
+            true => None,
+            false => Some(AttributeStore::open(&config.get_attributes_filename(), &config.get_attribute_categories_filename(), &config.attributes).unwrap()),
+        };
 
         let database = ImmutDatabase::open(&config.get_database_filename());
 
@@ -53,6 +79,9 @@ impl ImmutTree {
             node_handler,
             record_handler, 
             database,
+            vectors,
+            fingerprints,
+            attributes,
             root: PagePointer::Node(0),
             config,
             };
@@ -114,14 +143,20 @@ impl ImmutTree {
     ///Returns whether or not the exact provided descriptor is in the tree
     pub fn record_in_tree(&mut self, record: &CompoundRecord) -> Result<bool, String> {
 
-        let mut curr_pointer: PagePointer = self.root.clone();
+        //stored descriptors went through the codec, so compare against what this would store as
+        let descriptor = self.record_handler.codec.round_trip(&self.config.to_tree_space(&record.descriptor));
 
-        loop {
+        //a descriptor equal to a split value can be in either child
+        let mut to_visit: Vec<PagePointer> = vec![self.root.clone()];
+
+        while let Some(curr_pointer) = to_visit.pop() {
             match curr_pointer {
                 PagePointer::Leaf(index) => {
 
                     let page: RecordPage = self.record_handler.get_record_page(&index).unwrap();
-                    return Ok(page.descriptor_in_page(&record.descriptor));
+                    if page.descriptor_in_page(&descriptor) {
+                        return Ok(true);
+                    }
 
                 },
                 PagePointer::Node(index) => {
@@ -129,16 +164,21 @@ impl ImmutTree {
                     let node = self.node_handler.get_node(&index).unwrap().clone();
 
                     let axis = node.split_axis;
-                    let this_value = record.descriptor.data[axis];
+                    let this_value = descriptor.data[axis];
                     let split_value = node.split_value;
 
-                    match this_value <= split_value {
-                        true => curr_pointer = node.left_child_pointer,
-                        false => curr_pointer = node.right_child_pointer,
+                    if this_value <= split_value {
+                        to_visit.push(node.left_child_pointer);
+                    }
+
+                    if this_value >= split_value {
+                        to_visit.push(node.right_child_pointer);
                     }
                 }
             }
         }
+
+        return Ok(false);
     }
 
     fn dist_to_axis(&self, split_axis: usize, split_value: f32, descriptor: &Descriptor) -> f32 {
@@ -168,140 +208,439 @@ impl ImmutTree {
     //pub fn get_nearest_neighbors(&mut self, query_descriptor: &Descriptor, n: usize) -> NearestNeighbors {
     pub fn get_nearest_neighbors(&self, query_descriptor: &Descriptor, n: usize) -> NearestNeighbors {
 
-        let top_hits = self.get_top_hits(query_descriptor, n);
-        dbg!("BETWEEN");
-
-        let nearest_neighbors = NearestNeighbors::from_top_hits(top_hits, &self.database);
+        let (nearest_neighbors, _) = self.get_nearest_neighbors_with_stats(query_descriptor, n);
 
         return nearest_neighbors;
     }
 
-    ///Returns the `n` nearest neighbors of the provided `query_descriptor`
+    ///Same as `get_nearest_neighbors`, but also reports how much of the tree was visited
+    pub fn get_nearest_neighbors_with_stats(&self, query_descriptor: &Descriptor, n: usize) -> (NearestNeighbors, QueryStats) {
+
+        let (top_hits, stats) = self.get_top_hits(query_descriptor, n);
+
+        let nearest_neighbors = self.neighbors_from_hits(top_hits.into_sorted_vec(), stats.exact).unwrap();
+
+        return (nearest_neighbors, stats);
+    }
+
+    ///Nearest neighbors that may stop early according to `params`. `exact` on the result says
+    ///whether the search was allowed to finish. Errors if a hit's compound can't be read.
+    pub fn get_nearest_neighbors_with_params(&self, query_descriptor: &Descriptor, n: usize, params: &SearchParams) -> Result<(NearestNeighbors, QueryStats), String> {
+
+        let (top_hits, stats) = self.get_top_hits_best_first(query_descriptor, n, params);
+
+        let nearest_neighbors = self.neighbors_from_hits(top_hits.into_sorted_vec(), stats.exact)?;
+
+        return Ok((nearest_neighbors, stats));
+    }
+
+    ///Every neighbor closer than `radius`, closest first
     ///
-    ///Performance should worsen as `n` grows larger, as fewer branches of the tree can be pruned
-    ///with more distant already-found points
-    //fn get_top_hits(&mut self, query_descriptor: &Descriptor, n: usize) -> TopHits {
-    fn get_top_hits(&self, query_descriptor: &Descriptor, n: usize) -> TopHits {
+    ///On lossy trees the radius is applied to the stored values first, so points right at the
+    ///edge can be missed even when the results are re-ranked.
+    pub fn get_neighbors_within_radius(&self, query_descriptor: &Descriptor, radius: f32) -> NearestNeighbors {
 
-        let mut hits = TopHits::new(n);
+        return self.get_neighbors_within_radius_with_params(query_descriptor, radius, &SearchParams::exact()).unwrap();
+    }
 
-        let mut num_nodes_visited: usize = 0;
-        let mut num_record_pages_visited: usize = 0;
+    ///`get_neighbors_within_radius`, restricted by the sources and filter in `params`
+    pub fn get_neighbors_within_radius_with_params(&self, query_descriptor: &Descriptor, radius: f32, params: &SearchParams) -> Result<NearestNeighbors, String> {
 
-        //direction is the one we go if we pass!!!
-        let mut nodes_to_check: VecDeque<(PagePointer, NodeAction, Option<Direction>)> = VecDeque::new();
+        let mut hits = TopHits::within_radius(radius);
 
-        let root_pointer = self.root.clone();
+        let stats = self.search_best_first(query_descriptor, &mut hits, params);
+
+        let hits = match self.vectors.is_some() {
+            true => self.rerank(query_descriptor, hits.into_sorted_vec(), TopHits::within_radius(radius)),
+            false => hits,
+        };
 
-        nodes_to_check.push_front((root_pointer, NodeAction::Descend, None));
+        return self.neighbors_from_hits(hits.into_sorted_vec(), stats.exact);
+    }
 
+    ///`get_nearest_neighbors` for each of `query_descriptors`, in order
+    pub fn get_nearest_neighbors_batch(&self, query_descriptors: &[Descriptor], n: usize) -> Result<Vec<NearestNeighbors>, String> {
 
-        loop {
+        let mut results: Vec<NearestNeighbors> = Vec::with_capacity(query_descriptors.len());
 
-            //dbg!(&nodes_to_check);
-            let popped_val = nodes_to_check.pop_front();
-            //dbg!(&popped_val);
+        //reuse the heap's allocation between queries
+        let mut hits = TopHits::new(self.num_candidates(n));
 
-            let curr_tup = match popped_val {
-                None => {break;},
-                Some(x) => {x},
+        for query_descriptor in query_descriptors.iter() {
+
+            let stats = self.search_best_first(query_descriptor, &mut hits, &SearchParams::exact());
+
+            let nearest_neighbors = match self.vectors.is_some() {
+                true => {
+                    let reranked = self.rerank(query_descriptor, hits.drain_sorted(), TopHits::new(n));
+                    self.neighbors_from_hits(reranked.into_sorted_vec(), stats.exact)?
+                },
+                false => self.neighbors_from_hits(hits.drain_sorted(), stats.exact)?,
             };
 
-            let (curr_pointer, action, direction) = curr_tup;
+            results.push(nearest_neighbors);
+        }
 
-            match action {
+        return Ok(results);
+    }
 
-                NodeAction::Descend => {
+    ///Nearest neighbors by Tanimoto similarity to `query_fingerprint`, most similar first
+    ///
+    ///The descriptor is a proxy for structural similarity, so this takes the
+    ///`tanimoto_rerank_factor * n` nearest descriptors and keeps the `n` whose stored fingerprints
+    ///are most similar. Results carry both the descriptor distance and the Tanimoto similarity.
+    ///Candidates are searched for with `params`, so its sources and filter apply to the results.
+    pub fn get_nearest_neighbors_by_tanimoto(&self, query_descriptor: &Descriptor, query_fingerprint: &Fingerprint, n: usize, params: &SearchParams) -> Result<NearestNeighbors, String> {
 
-                    match curr_pointer {
-                        PagePointer::Leaf(index) => {
+        let fingerprints = match &self.fingerprints {
+            Some(x) => x,
+            None => return Err("Tree doesn't keep fingerprints".to_string()),
+        };
 
-                            num_record_pages_visited += 1;
+        if query_fingerprint.num_bits != fingerprints.num_bits() {
+            return Err(format!("Query fingerprint has {} bits, tree keeps {}", query_fingerprint.num_bits, fingerprints.num_bits()));
+        }
 
-                            let page: RecordPage = self.record_handler.get_record_page(&index).unwrap();
+        let num_candidates = n.saturating_mul(self.config.tanimoto_rerank_factor.max(1));
+        let (candidates, stats) = self.get_top_hits_best_first(query_descriptor, num_candidates, params);
 
-                            for record in page.get_records() {
-                                let dist = query_descriptor.distance(&record.descriptor);
+        let mut scored: Vec<(f32, Hit)> = Vec::with_capacity(candidates.len());
+        for hit in candidates.into_sorted_vec() {
+            let fingerprint = fingerprints.get(hit.record.index)?;
+            scored.push((query_fingerprint.tanimoto(&fingerprint), hit));
+        }
 
-                                hits.try_add(dist, &record, &curr_pointer).unwrap();
-                            }
+        //stable, so equal similarities stay closest descriptor first
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        scored.truncate(n);
 
+        let tanimoto: Vec<f32> = scored.iter().map(|x| x.0).collect();
+        let hits: Vec<Hit> = scored.into_iter().map(|x| x.1).collect();
 
-                        },
-                        PagePointer::Node(index) => {
+        let mut nearest_neighbors = self.neighbors_from_hits(hits, stats.exact)?;
+        nearest_neighbors.tanimoto = Some(tanimoto);
 
-                            num_nodes_visited += 1;
+        return Ok(nearest_neighbors);
+    }
 
-                            let node = self.node_handler.get_node(&index).unwrap().clone();
+    ///Visits branches in order of their distance from the query, so the closest record pages are
+    ///scanned first. Stops once no remaining branch can beat the current `n`th hit (shrunk by
+    ///`epsilon`) or after `max_record_pages` pages.
+    ///
+    ///Trees that keep full precision vectors fetch extra candidates and re-rank them exactly.
+    pub fn get_top_hits_best_first(&self, query_descriptor: &Descriptor, n: usize, params: &SearchParams) -> (TopHits, QueryStats) {
 
-                            let axis = node.split_axis;
-                            let this_value = &query_descriptor.data[axis];
-                            let split_value = node.split_value;
+        let mut hits = TopHits::new(self.num_candidates(n));
 
-                            match this_value <= &split_value {
+        let stats = self.search_best_first(query_descriptor, &mut hits, params);
 
-                                true => {
+        let hits = match self.vectors.is_some() {
+            true => self.rerank(query_descriptor, hits.into_sorted_vec(), TopHits::new(n)),
+            false => hits,
+        };
 
-                                    let descend_pointer = node.left_child_pointer;
+        return (hits, stats);
+    }
 
-                                    //push the current node and the direction we're going
-                                    nodes_to_check.push_front((descend_pointer.clone(), NodeAction::Descend, None));
+    ///Looks up each hit's compound and rotates its descriptor back out of the tree's space
+    fn neighbors_from_hits(&self, mut hits: Vec<Hit>, exact: bool) -> Result<NearestNeighbors, String> {
 
-                                    //push the current node and the direction we ignored
-                                    nodes_to_check.push_back((curr_pointer.clone(), NodeAction::CheckIgnoredBranch, Some(Direction::Right)));
-                                },
-                                false => {
+        if let Some(rotation) = &self.config.rotation {
+            for hit in hits.iter_mut() {
+                hit.record.descriptor = rotation.unrotate(&hit.record.descriptor);
+            }
+        }
 
-                                    let descend_pointer = node.right_child_pointer;
+        return NearestNeighbors::from_hits(hits, &self.database, exact);
+    }
 
-                                    //push the current node and the direction we're going
-                                    nodes_to_check.push_front((descend_pointer.clone(), NodeAction::Descend, None));
+    ///How many hits to collect from the tree to return `n` results
+    fn num_candidates(&self, n: usize) -> usize {
 
-                                    //push the current node and the direction we ignored
-                                    nodes_to_check.push_back((curr_pointer.clone(), NodeAction::CheckIgnoredBranch, Some(Direction::Left)));
-                                },
-                            }
-                        },
-                    }
-                },
+        return match self.vectors.is_some() {
+            true => n.saturating_mul(self.config.rerank_factor.max(1)),
+            false => n,
+        }
+    }
 
-                NodeAction::CheckIgnoredBranch => {
+    ///Swaps in the full precision descriptor for each candidate and keeps the closest in `into`
+    fn rerank(&self, query_descriptor: &Descriptor, candidates: Vec<Hit>, mut into: TopHits) -> TopHits {
 
-                    match curr_pointer {
-                        PagePointer::Leaf(_) => {panic!();},
-                        PagePointer::Node(index) => {
+        let vectors = self.vectors.as_ref().unwrap();
+        let query_descriptor = &self.config.to_tree_space(query_descriptor);
 
-                            let node = self.node_handler.get_node(&index).unwrap().clone();
+        for hit in candidates {
 
-                            let split_axis = node.split_axis;
-                            let split_value = node.split_value;
+            let mut record = hit.record;
+            record.descriptor = vectors.get(record.index).unwrap();
 
-                            //calc_distance to this axis and check it
-                            let dist = self.dist_to_axis(split_axis, split_value, query_descriptor);
-                            let threshold = hits.get_highest_dist();
-                            //println!("DIST TO AXIS: {:?}", dist);
+            let dist = query_descriptor.distance(&record.descriptor);
 
-                            if dist < threshold { //we have to visit the supplied direction
-                                let descend_pointer = match direction.unwrap() {
-                                    Direction::Left => node.left_child_pointer,
-                                    Direction::Right => node.right_child_pointer,
-                                };
-                                nodes_to_check.push_front((descend_pointer, NodeAction::Descend, None));
-                            }
+            into.try_add(dist, &record, &hit.pointer).unwrap();
+        }
+
+        return into;
+    }
+
+    ///Best-first search collecting into `hits`, which decides whether this is a kNN or radius query
+    pub fn search_best_first(&self, query_descriptor: &Descriptor, hits: &mut TopHits, params: &SearchParams) -> QueryStats {
+
+        //queued branches copy their box offsets, which for the common lengths needn't allocate
+        return match self.config.desc_length {
+            8 => self.search_best_first_with(query_descriptor, hits, params, [0.0f32; 8]),
+            16 => self.search_best_first_with(query_descriptor, hits, params, [0.0f32; 16]),
+            32 => self.search_best_first_with(query_descriptor, hits, params, [0.0f32; 32]),
+            n => self.search_best_first_with(query_descriptor, hits, params, vec![0.0f32; n]),
+        }
+    }
+
+    fn search_best_first_with<O: BoxOffsets>(&self, query_descriptor: &Descriptor, hits: &mut TopHits, params: &SearchParams, zero_offsets: O) -> QueryStats {
+
+        let mut stats = QueryStats::default();
+
+        let query_descriptor = &self.config.to_tree_space(query_descriptor);
+        let prepared_query = self.record_handler.codec.prepare(query_descriptor);
+
+        let mut candidates: BinaryHeap<SearchCandidate<O>> = BinaryHeap::new();
+        candidates.push(SearchCandidate {
+            lower_bound: 0.0,
+            pointer: self.root.clone(),
+            offsets: zero_offsets,
+        });
+
+        //bounds and thresholds are all compared squared
+        let prune_factor = (1.0 + params.epsilon.max(0.0)).powi(2);
+
+        let in_sources = |index: CompoundIndex| match params.sources {
+            Some(mask) => self.database.sources(&index) & mask != 0,
+            None => true,
+        };
+
+        let passes_filter = |index: CompoundIndex| match (&params.filter, &self.attributes) {
+            (None, _) => true,
+            (Some(filter), Some(store)) => store.get(index).map(|x| filter.matches(&x)).unwrap_or(false),
+            (Some(_), None) => false,
+        };
+
+        let accepts = |index: CompoundIndex| in_sources(index) && passes_filter(index);
+
+        loop {
+
+            let candidate = match candidates.pop() {
+                None => {break;},
+                Some(x) => x,
+            };
+
+            let threshold = squared_threshold(hits);
+
+            //everything left in the queue is at least this far away
+            if candidate.lower_bound >= threshold {
+                break;
+            }
+
+            if candidate.lower_bound * prune_factor >= threshold {
+                stats.exact = false;
+                break;
+            }
+
+            //walk down to a leaf, queueing the far side of each split. The near side is always
+            //on the query's side of the split, so its box distance doesn't change on the way down
+            let mut curr_pointer = candidate.pointer;
+            let offsets = candidate.offsets;
+
+            loop {
+                match curr_pointer {
+                    PagePointer::Leaf(index) => {
+
+                        match params.max_record_pages {
+                            Some(max) if stats.record_pages_visited >= max => {
+                                stats.exact = false;
+                                candidates.clear();
+                            },
+                            _ => {
+                                stats.record_pages_visited += 1;
+
+                                let page: RecordPage = self.record_handler.get_record_page(&index).unwrap();
+
+                                page.scan_into_filtered(&prepared_query, hits, &curr_pointer, &accepts).unwrap();
+                            },
                         }
-                    }
 
-                },
+                        break;
+                    },
+                    PagePointer::Node(index) => {
+
+                        stats.nodes_visited += 1;
+
+                        let node = self.node_handler.get_node(&index).unwrap();
+
+                        let dist = self.dist_to_axis(node.split_axis, node.split_value, query_descriptor);
+
+                        let (near, far) = match query_descriptor.data[node.split_axis] <= node.split_value {
+                            true => (node.left_child_pointer, node.right_child_pointer),
+                            false => (node.right_child_pointer, node.left_child_pointer),
+                        };
+
+                        let mut far_offsets = offsets.clone();
+                        let axis_offset = &mut far_offsets.as_mut()[node.split_axis];
+                        *axis_offset = axis_offset.max(dist);
+
+                        let lower_bound = squared_box_distance(far_offsets.as_ref());
+
+                        //the threshold only shrinks, so this would be pruned when popped anyway
+                        if lower_bound < squared_threshold(hits) {
+                            candidates.push(SearchCandidate {
+                                lower_bound,
+                                pointer: far,
+                                offsets: far_offsets,
+                            });
+                        }
+
+                        curr_pointer = near;
+                    },
+                }
             }
         }
 
-        println!("NODES VISITED: {:?}", num_nodes_visited);
-        println!("RECORD PAGES VISITED: {:?}", num_record_pages_visited);
+        return stats;
+    }
+
+    ///Returns the `n` nearest neighbors of the provided `query_descriptor`
+    ///
+    ///Performance should worsen as `n` grows larger, as fewer branches of the tree can be pruned
+    ///with more distant already-found points
+    pub fn get_top_hits(&self, query_descriptor: &Descriptor, n: usize) -> (TopHits, QueryStats) {
+
+        return self.get_top_hits_best_first(query_descriptor, n, &SearchParams::exact());
+    }
 
-        return hits;
+}
+
+///Counts of what a single query touched
+#[derive(Debug, Clone, Serialize)]
+pub struct QueryStats {
+    pub nodes_visited: usize,
+    pub record_pages_visited: usize,
+    ///false if the search stopped before every branch that could hold a closer hit was visited
+    pub exact: bool,
+}
+
+impl QueryStats {
+
+    pub fn new() -> Self {
+        return Self {
+            nodes_visited: 0,
+            record_pages_visited: 0,
+            exact: true,
+        }
+    }
+}
+
+impl Default for QueryStats {
+    fn default() -> Self {
+        return Self::new();
+    }
+}
+
+///What `Tree::check_integrity` walked through
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IntegrityReport {
+    pub num_nodes: usize,
+    pub num_record_pages: usize,
+    pub num_records: usize,
+}
+
+///The (axis, value, went left) of each split on the way down to a page
+type SplitPath = Vec<(usize, f32, bool)>;
+
+///Knobs for trading recall for speed. The default is an exact search.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchParams {
+    ///Stop after scanning this many record pages
+    pub max_record_pages: Option<usize>,
+    ///Skip branches that can't be more than (1 + epsilon) times closer than the current `n`th hit
+    pub epsilon: f32,
+    ///Only return records offered by one of these datasets, see `ImmutDatabase::dataset_mask`
+    #[serde(default)]
+    pub sources: Option<SourceMask>,
+    ///Only return records whose attributes pass this, see `AttributeStore::parse_filter`
+    #[serde(default)]
+    pub filter: Option<Filter>,
+}
+
+impl SearchParams {
+
+    pub fn exact() -> Self {
+        return Self {
+            max_record_pages: None,
+            epsilon: 0.0,
+            sources: None,
+            filter: None,
+        }
+    }
+}
+
+impl Default for SearchParams {
+    fn default() -> Self {
+        return Self::exact();
+    }
+}
+
+///Per-axis offsets of a search candidate: an array for the lengths with their own search, a
+///`Vec` otherwise
+trait BoxOffsets: Clone + AsRef<[f32]> + AsMut<[f32]> {}
+
+impl<T: Clone + AsRef<[f32]> + AsMut<[f32]>> BoxOffsets for T {}
+
+///Queue entry for best-first search, ordered so the closest branch pops first
+#[derive(Debug)]
+struct SearchCandidate<O> {
+    ///Squared distance from the query to the subtree's bounding box
+    lower_bound: f32,
+    pointer: PagePointer,
+    ///Per-axis distance from the query to the subtree's bounding box, zero where the query is
+    ///inside the box's extent on that axis
+    offsets: O,
+}
+
+///Squared minimum distance from the query to a box, given the per-axis offsets to it
+fn squared_box_distance(offsets: &[f32]) -> f32 {
+
+    let mut sum: f32 = 0.0;
+    for offset in offsets.iter() {
+        sum += offset * offset;
+    }
+
+    return sum;
+}
+
+///Squared distance a branch has to beat to be worth visiting
+fn squared_threshold(hits: &TopHits) -> f32 {
+
+    let highest = hits.get_highest_dist();
+
+    return highest * highest;
+}
+
+impl<O> PartialEq for SearchCandidate<O> {
+    fn eq(&self, other: &Self) -> bool {
+        return self.lower_bound == other.lower_bound;
+    }
+}
 
+impl<O> Eq for SearchCandidate<O> {}
+
+impl<O> PartialOrd for SearchCandidate<O> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        return Some(self.cmp(other));
     }
+}
 
+impl<O> Ord for SearchCandidate<O> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        //reversed, BinaryHeap is a max heap
+        return other.lower_bound.total_cmp(&self.lower_bound);
+    }
 }
 
 
@@ -368,6 +707,32 @@ impl TreeRecord {
     }
 
 
+    ///Just the compound index of an encoded record
+    pub fn decode_index(record_slice: &[u8]) -> CompoundIndex {
+
+        return Parser::get_usize_from_array(record_slice, layout::INDEX_START, layout::INDEX_SIZE).unwrap() as u64;
+    }
+
+    ///Reads a record stored with `codec`
+    pub fn decode(record_slice: &[u8], codec: &DescriptorCodec) -> Result<Self, String> {
+
+        let index = Self::decode_index(record_slice);
+        let descriptor = codec.decode(&record_slice[layout::DESCRIPTOR_START..codec.record_size()]);
+
+        return Ok (Self {
+            index,
+            descriptor,
+            length: codec.length,
+        })
+    }
+
+    ///Writes the record into `out`, which must be `codec.record_size()` long
+    pub fn encode_into(&self, out: &mut [u8], codec: &DescriptorCodec) {
+
+        out[layout::INDEX_START..layout::INDEX_START + layout::INDEX_SIZE].copy_from_slice(&self.index.to_be_bytes());
+        codec.encode_into(&self.descriptor, &mut out[layout::DESCRIPTOR_START..codec.record_size()]);
+    }
+
     pub fn to_vec(&self) -> Vec<u8> {
 
         let mut vec: Vec<u8> = Vec::with_capacity(self.get_record_size());
@@ -395,28 +760,206 @@ pub struct BuildConfig {
 
 
 
+///Which kind of index a directory holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexType {
+    ///kd tree over low dimensional float descriptors
+    #[default]
+    KdTree,
+    ///Bit vectors searched by Tanimoto similarity, see `fingerprint`. `desc_length` is the
+    ///number of bits.
+    Fingerprint,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TreeConfig {
     pub directory: String,
+    #[serde(default)]
+    pub index_type: IndexType,
+    ///Embedding service model that computes query descriptors, see `embedding_model`
+    #[serde(default)]
+    pub embedding_model: Option<String>,
     pub desc_length: usize,
     pub record_page_length: usize,
     pub node_page_length: usize,
     pub num_records: Option<usize>,
     pub cache_limit: Option<f32>,
+    ///How descriptor components are stored in record pages
+    #[serde(default)]
+    pub descriptor_encoding: DescriptorEncoding,
+    ///Per-dimension bounds, required for `int8` encoding
+    #[serde(default)]
+    pub quantizer: Option<ScalarQuantizer>,
+    ///Keep full precision descriptors next to a lossy tree and re-rank query results with them
+    #[serde(default)]
+    pub keep_full_vectors: bool,
+    ///When re-ranking, fetch this many times `k` candidates from the tree
+    #[serde(default = "default_rerank_factor")]
+    pub rerank_factor: usize,
+    ///Number of one byte codes per descriptor for `pq` encoding
+    #[serde(default = "default_pq_subspaces")]
+    pub pq_subspaces: usize,
+    ///Trained codebook for `pq` encoding, kept in its own file in the tree directory
+    #[serde(skip)]
+    pub product_quantizer: Option<ProductQuantizer>,
+    ///Keep a fingerprint of this many bits for every compound, for Tanimoto re-ranking
+    #[serde(default)]
+    pub fingerprint_bits: Option<usize>,
+    ///Embedding service model that computes those fingerprints for queries
+    #[serde(default)]
+    pub fingerprint_model: Option<String>,
+    ///When re-ranking by Tanimoto, fetch this many times `k` candidates from the tree
+    #[serde(default = "default_tanimoto_rerank_factor")]
+    pub tanimoto_rerank_factor: usize,
+    ///How full record pages choose their split axis and value
+    #[serde(default)]
+    pub split_strategy: SplitStrategy,
+    ///Principal axes descriptors are rotated onto, required for `pca_rotated` splits
+    #[serde(default)]
+    pub rotation: Option<Rotation>,
+    ///Per-compound values kept next to the tree for filtering searches, see `attributes`
+    #[serde(default)]
+    pub attributes: Vec<AttributeColumn>,
+    ///Layout of new databases. `fixed` is what trees built before the heap format use, and limits
+    ///SMILES to 200 and identifiers to 50 bytes.
+    #[serde(default)]
+    pub database_format: DatabaseFormat,
+    ///Node layout version. Configs without one are from trees built before versioning, which all
+    ///use the legacy layout; new trees are always written in the current one.
+    #[serde(default = "default_format_version")]
+    pub format_version: u32,
+    ///How the node file is written. Either format is read regardless.
+    #[serde(default)]
+    pub node_file_format: NodeFileFormat,
+}
+
+fn default_format_version() -> u32 {
+    return layout::LEGACY_FORMAT_VERSION;
+}
+
+fn default_rerank_factor() -> usize {
+    return 4;
 }
 
+fn default_pq_subspaces() -> usize {
+    return 4;
+}
 
+fn default_tanimoto_rerank_factor() -> usize {
+    return 10;
+}
 
 impl TreeConfig {
 
     pub fn default() -> Self {
         return Self {
             directory: "/tmp/kd_tree".to_string(),
+            index_type: IndexType::KdTree,
+            embedding_model: None,
             desc_length: 8,
             record_page_length: 4096,
             node_page_length: 4096,
             num_records: None,
             cache_limit: None,
+            descriptor_encoding: DescriptorEncoding::F32,
+            quantizer: None,
+            keep_full_vectors: false,
+            rerank_factor: default_rerank_factor(),
+            pq_subspaces: default_pq_subspaces(),
+            product_quantizer: None,
+            fingerprint_bits: None,
+            fingerprint_model: None,
+            tanimoto_rerank_factor: default_tanimoto_rerank_factor(),
+            split_strategy: SplitStrategy::Cyclic,
+            rotation: None,
+            attributes: Vec::new(),
+            database_format: DatabaseFormat::default(),
+            format_version: layout::FORMAT_VERSION,
+            node_file_format: NodeFileFormat::default(),
+        }
+    }
+
+    ///Model to embed query SMILES with. Kd trees built before the model was recorded all use
+    ///`salsa16`; fingerprint indexes have to name theirs.
+    pub fn embedding_model(&self) -> Result<String, String> {
+
+        return match (&self.embedding_model, self.index_type) {
+            (Some(model), _) => Ok(model.clone()),
+            (None, IndexType::KdTree) => Ok("salsa16".to_string()),
+            (None, IndexType::Fingerprint) => Err("Fingerprint index config doesn't name its embedding_model".to_string()),
+        }
+    }
+
+    ///Levels of nodes `reorder_nodes` stores together, as many as fit a node page
+    pub fn node_block_height(&self) -> Result<usize, String> {
+
+        let nodes_per_page = self.node_page_length / self.node_layout()?.node_size();
+
+        return Ok((nodes_per_page + 1).ilog2().max(1) as usize);
+    }
+
+    pub fn node_layout(&self) -> Result<layout::NodeLayout, String> {
+
+        return layout::NodeLayout::for_version(self.format_version);
+    }
+
+    ///Checks the node and page formats can represent trees with this config
+    pub fn validate(&self) -> Result<(), String> {
+
+        let node_layout = self.node_layout()?;
+
+        if self.desc_length == 0 {
+            return Err("desc_length must be at least 1".to_string());
+        }
+
+        if self.desc_length > node_layout.max_split_axes() {
+            return Err(format!("desc_length {} is more than the {} split axes format version {} can address", self.desc_length, node_layout.max_split_axes(), self.format_version));
+        }
+
+        let record_size = self.get_codec().record_size();
+        let capacity = self.record_page_length.saturating_sub(layout::PAGE_DATA_START) / record_size;
+
+        if capacity < 2 {
+            return Err(format!("record_page_length {} fits {} records of {} bytes, a page needs room for at least 2 to split", self.record_page_length, capacity, record_size));
+        }
+
+        if capacity > layout::MAX_PAGE_RECORDS {
+            return Err(format!("record_page_length {} fits {} records, more than a page tail can count ({})", self.record_page_length, capacity, layout::MAX_PAGE_RECORDS));
+        }
+
+        return Ok(());
+    }
+
+    pub fn get_codec(&self) -> DescriptorCodec {
+
+        return DescriptorCodec::new(self.descriptor_encoding, self.desc_length, self.quantizer.clone(), self.product_quantizer.clone()).unwrap();
+    }
+
+    ///Whether query results are re-ranked against a full precision side file. `pq` distances are
+    ///too coarse to return as is, so those trees always are.
+    pub fn reranks(&self) -> bool {
+
+        return match self.descriptor_encoding {
+            DescriptorEncoding::Pq => true,
+            x => self.keep_full_vectors && x.is_lossy(),
+        }
+    }
+
+    ///A descriptor as the tree stores it, rotated if the tree uses `pca_rotated` splits
+    pub fn to_tree_space(&self, descriptor: &Descriptor) -> Descriptor {
+
+        return match &self.rotation {
+            Some(rotation) => rotation.rotate(descriptor),
+            None => descriptor.clone(),
+        }
+    }
+
+    ///Reads the codebook of a `pq` tree from its directory
+    pub fn load_codebook(&mut self) {
+
+        if self.descriptor_encoding == DescriptorEncoding::Pq {
+            self.product_quantizer = Some(ProductQuantizer::from_file(&self.get_codebook_filename()).unwrap());
         }
     }
 
@@ -457,6 +1000,31 @@ impl TreeConfig {
         return self.directory.clone() + "/db.db";
     }
 
+    pub fn get_vectors_filename(&self) -> String {
+
+        return self.directory.clone() + "/vectors";
+    }
+
+    pub fn get_codebook_filename(&self) -> String {
+
+        return self.directory.clone() + "/pq_codebook.yaml";
+    }
+
+    pub fn get_fingerprints_filename(&self) -> String {
+
+        return self.directory.clone() + "/fingerprint_store";
+    }
+
+    pub fn get_attributes_filename(&self) -> String {
+
+        return self.directory.clone() + "/attributes";
+    }
+
+    pub fn get_attribute_categories_filename(&self) -> String {
+
+        return self.directory.clone() + "/attribute_categories.yaml";
+    }
+
 
 
 }
@@ -471,6 +1039,9 @@ pub struct Tree {
     pub node_handler: FastNodePager,
     pub record_handler: RecordPager,
     pub database: Database,
+    pub vectors: Option<VectorStore>,
+    pub fingerprints: Option<FingerprintStore>,
+    pub attributes: Option<AttributeStore>,
     pub root: PagePointer,
     pub config: TreeConfig,
 }
@@ -479,37 +1050,76 @@ pub struct Tree {
 pub struct NearestNeighbors {
     pub distances: Vec<f32>,
     pub records: Vec<Option<CompoundRecord>>,
+    ///false if these came from an approximate search that stopped early
+    pub exact: bool,
+    ///Tanimoto similarity to the query fingerprint, if results were re-ranked by it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tanimoto: Option<Vec<f32>>,
+    ///Source datasets offering each hit, if the build named any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub catalogs: Option<Vec<Vec<String>>>,
+    ///Identifiers of duplicates merged into each hit, if any hit has them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aliases: Option<Vec<Vec<String>>>,
 }
 
 impl NearestNeighbors {
 
-    fn from_top_hits(top_hits: TopHits, database: &ImmutDatabase) -> Self {
+    pub(crate) fn from_top_hits(top_hits: TopHits, database: &ImmutDatabase, exact: bool) -> Result<Self, String> {
+
+        return Self::from_hits(top_hits.into_sorted_vec(), database, exact);
+    }
+
+    ///Keeps the order of `hits`, which is usually closest first. Errors if any hit's compound
+    ///can't be read from the database, rather than returning fewer results than asked for.
+    fn from_hits(hits: Vec<Hit>, database: &ImmutDatabase, exact: bool) -> Result<Self, String> {
 
         let mut distances: Vec<f32> = Vec::new();
         let mut records: Vec<Option<CompoundRecord>> = Vec::new();
+        let mut catalogs: Vec<Vec<String>> = Vec::new();
+        let mut aliases: Vec<Vec<String>> = Vec::new();
 
-        for i in 0..top_hits.records.len() {
+        let indices: Vec<CompoundIndex> = hits.iter().map(|x| x.record.index).collect();
+        let database_records = database.query_many(&indices);
 
-                let record = top_hits.records[i].as_ref().unwrap();
-                
-                let database_record = database.query(&top_hits.records[i].as_ref().unwrap().index).unwrap();
+        for (hit, database_record) in hits.into_iter().zip(database_records) {
+
+                distances.push(hit.distance);
+
+                let database_record = database_record?;
 
                 let compound_record = CompoundRecord {
-                    smiles: database_record.smiles.clone(),
-                    compound_identifier: database_record.identifier.clone(),
-                    descriptor: record.descriptor.clone(),
-                    length: record.length,
+                    smiles: database_record.smiles,
+                    compound_identifier: database_record.identifier,
+                    descriptor: hit.record.descriptor,
+                    length: hit.record.length,
+                    dataset: None,
                 };
 
-                distances.push(top_hits.distances[i]);
                 records.push(Some(compound_record));
+                catalogs.push(dataset_names(&database.datasets, database_record.sources));
+                aliases.push(database_record.aliases.iter().map(|x| x.to_string()).collect());
 
         }
 
-        return Self {
+        let catalogs = match database.datasets.is_empty() {
+            true => None,
+            false => Some(catalogs),
+        };
+
+        let aliases = match aliases.iter().any(|x| !x.is_empty()) {
+            true => Some(aliases),
+            false => None,
+        };
+
+        return Ok(Self {
             distances,
             records,
-        }
+            exact,
+            tanimoto: None,
+            catalogs,
+            aliases,
+        });
     }
 
     pub fn to_yaml(&self) -> String {
@@ -517,8 +1127,9 @@ impl NearestNeighbors {
         let mut s = String::new();
         for i in 0..self.records.len() {
 
+            //compounds the database couldn't return are left out
             let record = match &self.records[i] {
-                None => {panic!()},
+                None => continue,
                 Some(x) => x.clone(),
             };
             let identifier_string = record.compound_identifier.to_string();
@@ -527,6 +1138,15 @@ impl NearestNeighbors {
             s = s + &format!("    smiles: {}\n", &smiles);
             s = s + &format!("    embedding: {}\n", &record.descriptor.yaml());
             s = s + &format!("    distance: {}\n", &self.distances[i]).to_string();
+            if let Some(tanimoto) = &self.tanimoto {
+                s = s + &format!("    tanimoto: {}\n", &tanimoto[i]);
+            }
+            if let Some(catalogs) = &self.catalogs {
+                s = s + &format!("    catalogs: {:?}\n", &catalogs[i]);
+            }
+            if let Some(aliases) = &self.aliases {
+                s = s + &format!("    aliases: {:?}\n", &aliases[i]);
+            }
         }
 
         return s;
@@ -540,8 +1160,9 @@ impl NearestNeighbors {
         s += "{";
         for i in 0..self.records.len() {
 
+            //compounds the database couldn't return are left out
             let record = match &self.records[i] {
-                None => {panic!()},
+                None => continue,
                 Some(x) => x.clone(),
             };
 
@@ -563,107 +1184,178 @@ impl NearestNeighbors {
 }
 
 
-///struct for keeping N- top closest points
-///
-///handles distance sorting and truncating to N items
+///A single candidate neighbor found during a search
+#[derive(Debug, Clone)]
+pub struct Hit {
+    pub distance: f32,
+    pub record: TreeRecord,
+    pub pointer: PagePointer,
+}
+
+impl PartialEq for Hit {
+    fn eq(&self, other: &Self) -> bool {
+        return self.distance == other.distance;
+    }
+}
+
+impl Eq for Hit {}
+
+impl PartialOrd for Hit {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        return Some(self.cmp(other));
+    }
+}
+
+impl Ord for Hit {
+    fn cmp(&self, other: &Self) -> Ordering {
+        return self.distance.total_cmp(&other.distance);
+    }
+}
+
+///Collects the closest hits seen during a search
+///
+///A max heap keyed on distance, so the current worst hit is on top and can be evicted in log time.
+///Bounded by `max_length` for kNN queries and by `max_distance` for radius queries; either or
+///both can be set. Holds fewer than `max_length` hits if fewer points were visited.
 #[derive(Debug)]
 pub struct TopHits {
-    pub max_length: usize,
-    pub distances: Vec<f32>,
-    pub records: Vec<Option<TreeRecord>>,
-    pub pointers: Vec<Option<PagePointer>>,
+    pub max_length: Option<usize>,
+    pub max_distance: f32,
+    heap: BinaryHeap<Hit>,
 }
 
 impl TopHits {
 
-    ///Distances are initially set to max f32 value
-    /// Behavior is undefined if we don't visit at least `max_lengths` records
+    ///Keeps the `max_length` closest hits
     pub fn new(max_length: usize) -> Self {
-
-        let mut distances: Vec<f32> = Vec::new();
-        let mut records: Vec<Option<TreeRecord>> = Vec::new();
-        let mut pointers: Vec<Option<PagePointer>> = Vec::new();
-        for _ in 0..max_length {
-            distances.push(f32::MAX);
-            records.push(None);
-            pointers.push(None);
-        }
         return Self {
-            max_length,
-            distances,
-            records,
-            pointers,
+            max_length: Some(max_length),
+            max_distance: f32::MAX,
+            heap: BinaryHeap::with_capacity(max_length.min(1 << 16) + 1),
         }
     }
 
-    ///Internal method for adding a record to the list
-    ///
-    ///Undefined if called without checking if we can via `try_add`
-    fn _add(&mut self, distance: f32, record: &TreeRecord, page_pointer: &PagePointer) -> Result<(), String> {
-        //println!("ADDING");
-
-        //find insertion point
-        let mut insert_index: Option<usize> = None;
-        for (i, item) in self.distances.iter().enumerate() {
-            if item > &distance {
-                insert_index = Some(i);
-                break;
-            }
+    ///Keeps every hit closer than `max_distance`
+    pub fn within_radius(max_distance: f32) -> Self {
+        return Self {
+            max_length: None,
+            max_distance,
+            heap: BinaryHeap::new(),
         }
+    }
 
-        let insert_index = match insert_index {
-            None => {panic!()},
-            Some(x) => x,
-        };
-
-        self.distances.insert(insert_index, distance);
-        self.records.insert(insert_index, Some(record.clone()));
-        self.pointers.insert(insert_index, Some(page_pointer.clone()));
+    pub fn len(&self) -> usize {
+        return self.heap.len();
+    }
 
-        //trim to correct size
-        self.distances.truncate(self.max_length);
-        self.records.truncate(self.max_length);
-        self.pointers.truncate(self.max_length);
+    pub fn is_empty(&self) -> bool {
+        return self.heap.is_empty();
+    }
 
-        Ok(())
+    fn is_full(&self) -> bool {
+        return match self.max_length {
+            Some(x) => self.heap.len() >= x,
+            None => false,
+        }
     }
 
     ///Public method to be called on every record for consideration as a neighbor
     pub fn try_add(&mut self, distance: f32, record: &TreeRecord, page_pointer: &PagePointer) -> Result<(), String> {
 
-        let worst_best_distance = self.get_highest_dist();
-        if distance < worst_best_distance {
-            self._add(distance, record, page_pointer)?;
+        if self.max_length == Some(0) || distance >= self.get_highest_dist() {
+            return Ok(());
+        }
+
+        if self.is_full() {
+            self.heap.pop();
         }
 
+        self.heap.push(Hit {
+            distance,
+            record: record.clone(),
+            pointer: page_pointer.clone(),
+        });
+
         Ok(())
     }
 
+    ///`try_add` for a record still encoded in a page, decoded only if it's kept. A full heap
+    ///decodes into the descriptor of the hit it pushes out, so it doesn't allocate.
+    pub fn try_add_encoded(&mut self, distance: f32, record_slice: &[u8], codec: &DescriptorCodec, page_pointer: &PagePointer) -> Result<(), String> {
+
+        if self.max_length == Some(0) || distance >= self.get_highest_dist() {
+            return Ok(());
+        }
+
+        let mut record = match self.is_full() {
+            true => self.heap.pop().unwrap().record,
+            false => TreeRecord::default(codec.length),
+        };
+
+        record.index = TreeRecord::decode_index(record_slice);
+        record.length = codec.length;
+        codec.decode_into(&record_slice[layout::DESCRIPTOR_START..codec.record_size()], &mut record.descriptor);
+
+        self.heap.push(Hit {
+            distance,
+            record,
+            pointer: page_pointer.clone(),
+        });
+
+        Ok(())
+    }
 
     ///# Returns
     ///
-    ///the highest distance of the list (e.g. the Nth highest distance where N=`max_length`
-    ///
-    ///Should be constant time access, it's just looking at the back of the vector?
+    ///the distance a new hit has to beat to be kept: the current worst hit once the heap is
+    ///full, `max_distance` until then
     pub fn get_highest_dist(&self) -> f32 {
 
-        return self.distances[self.max_length - 1];
+        if self.max_length == Some(0) {
+            return 0.0;
+        }
+
+        return match self.is_full() {
+            true => self.heap.peek().unwrap().distance,
+            false => self.max_distance,
+        }
+    }
+
+    ///Hits sorted closest first
+    pub fn into_sorted_vec(self) -> Vec<Hit> {
+        return self.heap.into_sorted_vec();
+    }
+
+    ///Hits in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = &Hit> {
+        return self.heap.iter();
+    }
+
+    ///Empties the heap but keeps its allocation, so it can be reused for the next query
+    pub fn drain_sorted(&mut self) -> Vec<Hit> {
+        let mut hits: Vec<Hit> = self.heap.drain().collect();
+        hits.sort();
+        return hits;
+    }
+
+    ///Distances sorted closest first
+    pub fn distances(&self) -> Vec<f32> {
+        let mut distances: Vec<f32> = self.heap.iter().map(|x| x.distance).collect();
+        distances.sort_by(|a, b| a.total_cmp(b));
+        return distances;
     }
 
     pub fn to_json(&self) -> String {
 
+        let hits = self.heap.clone().into_sorted_vec();
+
         let mut s = String::new();
         s += "{";
-        for i in 0..self.records.len() {
-
-            let record = match &self.records[i] {
-                None => {panic!()},
-                Some(x) => x.clone(),
-            };
+        for hit in hits.iter() {
 
-            let index = record.index.to_string();
+            let index = hit.record.index.to_string();
             s = s + &format!("  \"{:?}\": {{\n", index);
-            s = s + &format!("  \"distance\": \"{}\"", &self.distances[i]).to_string();
+            s = s + &format!("  \"distance\": \"{}\"", &hit.distance).to_string();
             s = s + "," + "\n";
         s += "},\n";
         }
@@ -680,35 +1372,39 @@ fn get_smiles(index: &CompoundIdentifier) -> String {
     return "not implemented".to_string();
 }
 
-#[derive(Debug)]
-pub enum Direction {
-    Left,
-    Right,
-}
-
-#[derive(Debug)]
-pub enum NodeAction {
-    CheckIgnoredBranch,
-    Descend,
-
-}
-
 impl Tree {
 
     pub fn read_from_directory(directory_name: String) -> Self {
 
         let config_filename = directory_name.clone() + "/config.yaml";
-        let config = TreeConfig::from_file(config_filename);
+        let mut config = TreeConfig::from_file(config_filename);
+        config.load_codebook();
         dbg!(&config);
 
+        if config.index_type != IndexType::KdTree {
+            panic!("{} holds a {:?} index, not a kd tree", directory_name, config.index_type);
+        }
+
         let node_filename = config.get_node_filename();
         let record_filename = config.get_record_filename();
 
         dbg!(&node_filename);
         dbg!(&record_filename);
 
-        let node_handler = FastNodePager::from_file(&node_filename).unwrap();
-        let record_handler = RecordPager::new(record_filename, config.record_page_length, config.desc_length, false, config.cache_limit).unwrap();
+        let node_handler = FastNodePager::from_file(&node_filename, &config.node_layout().unwrap()).unwrap();
+        let record_handler = RecordPager::new(record_filename, config.record_page_length, config.get_codec(), false, config.cache_limit).unwrap();
+
+        let vectors = match config.reranks() {
+            true => Some(VectorStore::open_append(&config.get_vectors_filename(), config.desc_length).unwrap()),
+            false => None,
+        };
+
+        let fingerprints = config.fingerprint_bits.map(|x| FingerprintStore::open_append(&config.get_fingerprints_filename(), x).unwrap());
+
+        let attributes = match config.attributes.is_empty() {
+            true => None,
+            false => Some(AttributeStore::open_append(&config.get_attributes_filename(), &config.get_attribute_categories_filename(), &config.attributes).unwrap()),
+        };
 
         let database = Database::open(&config.get_database_filename());
 
@@ -716,6 +1412,9 @@ impl Tree {
             node_handler,
             record_handler, 
             database,
+            vectors,
+            fingerprints,
+            attributes,
             root: PagePointer::Node(0),
             config,
             };
@@ -729,7 +1428,14 @@ impl Tree {
     }
 
 
-    pub fn create_with_config(config: TreeConfig) -> Self {
+    ///Panics if the directory exists or the node and page formats can't represent the config
+    pub fn create_with_config(mut config: TreeConfig) -> Self {
+
+        config.format_version = layout::FORMAT_VERSION;
+
+        if let Err(e) = config.validate() {
+            panic!("Invalid tree config: {}", e);
+        }
 
         let dir_path = Path::new(&config.directory);
 
@@ -750,8 +1456,21 @@ impl Tree {
     pub fn flush(&mut self) {
 
         let node_filename = self.config.get_node_filename();
-        self.node_handler.to_file(&node_filename).unwrap();
+        self.node_handler.to_file(&node_filename, &self.config.node_layout().unwrap(), self.config.node_file_format).unwrap();
         self.record_handler.flush();
+        self.database.flush().unwrap();
+
+        if let Some(vectors) = &mut self.vectors {
+            vectors.flush().unwrap();
+        }
+
+        if let Some(fingerprints) = &mut self.fingerprints {
+            fingerprints.flush().unwrap();
+        }
+
+        if let Some(attributes) = &mut self.attributes {
+            attributes.flush().unwrap();
+        }
 
     }
 
@@ -761,22 +1480,47 @@ impl Tree {
         let config_filename = config.get_config_filename();
 
         let node_handler = FastNodePager::new();
-        let mut record_handler = RecordPager::new(record_filename, config.record_page_length, config.desc_length, true, config.cache_limit).unwrap();
+        let codec = config.get_codec();
+        let mut record_handler = RecordPager::new(record_filename, config.record_page_length, codec.clone(), true, config.cache_limit).unwrap();
 
-        let first_record_page = RecordPage::new(config.record_page_length, config.desc_length);
+        let first_record_page = RecordPage::with_codec(config.record_page_length, &codec);
         //record_handler.write_page(&first_record_page).unwrap();
         record_handler.add_page(&first_record_page).unwrap();
 
         config.to_file(config_filename);
 
-        let database_filename = config.directory.clone() + "/db.db";
+        if config.split_strategy == SplitStrategy::PcaRotated && config.rotation.is_none() {
+            panic!("pca_rotated splits need a fitted rotation");
+        }
+
+        if config.descriptor_encoding == DescriptorEncoding::Pq {
+            match &config.product_quantizer {
+                Some(x) => x.to_file(&config.get_codebook_filename()).unwrap(),
+                None => panic!("pq encoding needs a trained codebook"),
+            }
+        }
+
+        let database = Database::create(&config.get_database_filename(), config.database_format).unwrap();
+
+        let vectors = match config.reranks() {
+            true => Some(VectorStore::create(&config.get_vectors_filename(), config.desc_length).unwrap()),
+            false => None,
+        };
+
+        let fingerprints = config.fingerprint_bits.map(|x| FingerprintStore::create(&config.get_fingerprints_filename(), x).unwrap());
 
-        let database = Database::new(&database_filename);
+        let attributes = match config.attributes.is_empty() {
+            true => None,
+            false => Some(AttributeStore::create(&config.get_attributes_filename(), &config.get_attribute_categories_filename(), &config.attributes).unwrap()),
+        };
 
         return Self {
             node_handler,
             record_handler, 
             database,
+            vectors,
+            fingerprints,
+            attributes,
             root: PagePointer::Leaf(0),
             config,
         };
@@ -838,14 +1582,43 @@ impl Tree {
     ///Returns whether or not the exact provided descriptor is in the tree
     pub fn record_in_tree(&mut self, record: &CompoundRecord) -> Result<bool, String> {
 
-        let mut curr_pointer: PagePointer = self.root.clone();
+        return Ok(self.find_descriptor(&record.descriptor)?.is_some());
+    }
+
+    ///Index of a stored record with exactly this descriptor. With a lossy `descriptor_encoding`,
+    ///records that encode identically are checked against the full precision vectors; trees that
+    ///don't keep them count every such record as a match.
+    pub fn find_descriptor(&mut self, descriptor: &Descriptor) -> Result<Option<CompoundIndex>, String> {
 
-        loop {
+        let exact = self.config.to_tree_space(descriptor);
+        let descriptor = self.record_handler.codec.round_trip(&exact);
+
+        //a descriptor equal to a split value can be in either child
+        let mut to_visit: Vec<PagePointer> = vec![self.root.clone()];
+
+        while let Some(curr_pointer) = to_visit.pop() {
             match curr_pointer {
                 PagePointer::Leaf(index) => {
 
                     let page: RecordPage = self.record_handler.get_record_page(&index).unwrap();
-                    return Ok(page.descriptor_in_page(&record.descriptor));
+
+                    for record in page.get_records() {
+                        if record.descriptor != descriptor {
+                            continue;
+                        }
+
+                        let vectors = match &mut self.vectors {
+                            Some(x) => x,
+                            None => return Ok(Some(record.index)),
+                        };
+
+                        //the latest vectors may still be buffered
+                        vectors.flush()?;
+
+                        if vectors.get(record.index)? == exact {
+                            return Ok(Some(record.index));
+                        }
+                    }
 
                 },
                 PagePointer::Node(index) => {
@@ -853,16 +1626,21 @@ impl Tree {
                     let node = self.node_handler.get_node(&index).unwrap().clone();
 
                     let axis = node.split_axis;
-                    let this_value = record.descriptor.data[axis];
+                    let this_value = descriptor.data[axis];
                     let split_value = node.split_value;
 
-                    match this_value <= split_value {
-                        true => curr_pointer = node.left_child_pointer,
-                        false => curr_pointer = node.right_child_pointer,
+                    if this_value <= split_value {
+                        to_visit.push(node.left_child_pointer);
+                    }
+
+                    if this_value >= split_value {
+                        to_visit.push(node.right_child_pointer);
                     }
                 }
             }
         }
+
+        return Ok(None);
     }
 
     fn dist_to_axis(&self, split_axis: usize, split_value: f32, descriptor: &Descriptor) -> f32 {
@@ -887,6 +1665,121 @@ impl Tree {
 
     }
 
+    ///Stores nodes in blocks of `node_block_height` levels so searches read fewer, closer nodes.
+    ///Meant to be run once a build is done, before `flush`.
+    pub fn reorder_nodes(&mut self) -> Result<(), String> {
+
+        if let PagePointer::Node(x) = self.root {
+            if x != 0 {
+                return Err(format!("Root is node {}, reordering expects it at 0", x));
+            }
+        }
+
+        return self.node_handler.reorder_blocked(self.config.node_block_height()?);
+    }
+
+    ///Walks the tree from the root and checks that every node and record page is reached exactly
+    ///once, pointers and split axes are in range, and, unless descriptors are stored lossily,
+    ///every record is on its side of each split above it.
+    pub fn check_integrity(&self) -> Result<IntegrityReport, String> {
+
+        let num_nodes = self.node_handler.len();
+        let num_record_pages = self.record_handler.len();
+
+        let mut node_seen = vec![false; num_nodes];
+        let mut page_seen = vec![false; num_record_pages];
+        let mut num_records = 0;
+
+        //a lossy encoding can store a value on the other side of a split it was routed by
+        let check_records = !self.config.descriptor_encoding.is_lossy();
+
+        //pointers still to check, with the splits above them
+        let mut to_visit: Vec<(PagePointer, SplitPath)> = vec![(self.root.clone(), Vec::new())];
+
+        while let Some((pointer, path)) = to_visit.pop() {
+
+            match pointer {
+                PagePointer::Node(index) => {
+
+                    if index >= num_nodes {
+                        return Err(format!("Pointer to missing node {}", index));
+                    }
+
+                    if node_seen[index] {
+                        return Err(format!("Node {} is reached more than once", index));
+                    }
+                    node_seen[index] = true;
+
+                    let node = self.node_handler.get_node(&index)?;
+
+                    if node.split_axis >= self.config.desc_length {
+                        return Err(format!("Node {} splits on axis {}, descriptors have {}", index, node.split_axis, self.config.desc_length));
+                    }
+
+                    if node.split_value.is_nan() {
+                        return Err(format!("Node {} has a NaN split value", index));
+                    }
+
+                    let mut left_path = path.clone();
+                    left_path.push((node.split_axis, node.split_value, true));
+
+                    let mut right_path = path;
+                    right_path.push((node.split_axis, node.split_value, false));
+
+                    to_visit.push((node.right_child_pointer.clone(), right_path));
+                    to_visit.push((node.left_child_pointer.clone(), left_path));
+                },
+                PagePointer::Leaf(index) => {
+
+                    if index >= num_record_pages {
+                        return Err(format!("Pointer to missing record page {}", index));
+                    }
+
+                    if page_seen[index] {
+                        return Err(format!("Record page {} is reached more than once", index));
+                    }
+                    page_seen[index] = true;
+
+                    let page = self.record_handler.get_record_page(&index).map_err(|e| format!("Could not read record page {}: {:?}", index, e))?;
+                    num_records += page.len();
+
+                    if !check_records {
+                        continue;
+                    }
+
+                    for record in page.get_records() {
+                        for (axis, value, went_left) in path.iter() {
+
+                            let x = record.descriptor.data[*axis];
+                            let on_its_side = match went_left {
+                                true => x <= *value,
+                                false => x >= *value,
+                            };
+
+                            if !on_its_side {
+                                return Err(format!("Record {} in page {} is on the wrong side of a split on axis {} at {}", record.index, index, axis, value));
+                            }
+                        }
+                    }
+                },
+            }
+        }
+
+        if let Some(x) = node_seen.iter().position(|x| !x) {
+            return Err(format!("Node {} is unreachable from the root", x));
+        }
+
+        if let Some(x) = page_seen.iter().position(|x| !x) {
+            return Err(format!("Record page {} is unreachable from the root", x));
+        }
+
+        return Ok(IntegrityReport {
+            num_nodes,
+            num_record_pages,
+            num_records,
+        });
+    }
+
     pub fn print_record_page(&mut self, page: RecordPage) {
 
         let records = page.get_records();
@@ -902,7 +1795,7 @@ impl Tree {
 
     pub fn get_records_per_page(&mut self) -> usize {
 
-        return (self.config.record_page_length - layout::PAGE_DATA_START) / TreeRecord::compute_record_size(self.config.desc_length);
+        return (self.config.record_page_length - layout::PAGE_DATA_START) / self.record_handler.codec.record_size();
 
     }
 
@@ -913,9 +1806,65 @@ impl Tree {
     ///children.
     pub fn add_record(&mut self, record: &CompoundRecord) -> Result<(), String> {
 
+        return self.add_record_with_extras(record, None, None);
+    }
+
+    ///For trees with `fingerprint_bits` set, which need a fingerprint for every compound
+    pub fn add_record_with_fingerprint(&mut self, record: &CompoundRecord, fingerprint: &Fingerprint) -> Result<(), String> {
+
+        return self.add_record_with_extras(record, Some(fingerprint), None);
+    }
+
+    ///For trees with `attributes`, one value per column. Records added without them get missing
+    ///values, which no filter passes.
+    pub fn add_record_with_attributes(&mut self, record: &CompoundRecord, fingerprint: Option<&Fingerprint>, attributes: &[AttributeValue]) -> Result<(), String> {
+
+        return self.add_record_with_extras(record, fingerprint, Some(attributes));
+    }
+
+    fn add_record_with_extras(&mut self, record: &CompoundRecord, fingerprint: Option<&Fingerprint>, attributes: Option<&[AttributeValue]>) -> Result<(), String> {
+
+        //check before anything is written, so the side files stay in step with the database
+        match (&self.fingerprints, fingerprint) {
+            (Some(store), Some(x)) if x.num_bits != store.num_bits() => {
+                return Err(format!("Fingerprint has {} bits, tree keeps {}", x.num_bits, store.num_bits()));
+            },
+            (Some(_), None) => return Err("Tree keeps fingerprints, but none was given".to_string()),
+            (None, Some(_)) => return Err("Tree doesn't keep fingerprints".to_string()),
+            _ => {},
+        }
+
+        match (&self.attributes, attributes) {
+            (Some(store), Some(x)) if x.len() != store.columns().len() => {
+                return Err(format!("Got {} attribute values, tree keeps {}", x.len(), store.columns().len()));
+            },
+            (None, Some(_)) => return Err("Tree doesn't keep attributes".to_string()),
+            _ => {},
+        }
+
         let index = self.database.add_compound_record(record)?;
 
-        let tree_record = record.get_tree_record(&index);
+        let descriptor = self.config.to_tree_space(&record.descriptor);
+
+        if let Some(vectors) = &mut self.vectors {
+            vectors.append(index, &descriptor)?;
+        }
+
+        if let (Some(store), Some(x)) = (&mut self.fingerprints, fingerprint) {
+            store.append(index, x)?;
+        }
+
+        if let Some(store) = &mut self.attributes {
+            let missing = vec![AttributeValue::Missing; store.columns().len()];
+            store.append(index, attributes.unwrap_or(&missing))?;
+        }
+
+        //route on the value as stored, so lossy encodings can't end up on the wrong side of a split
+        let mut tree_record = record.get_tree_record(&index);
+        tree_record.descriptor = self.record_handler.codec.round_trip(&descriptor);
+
+        //bounds of the leaf the record lands in, for sliding midpoint splits
+        let mut cell = Cell::unbounded(self.config.desc_length);
 
         let mut curr_pointer = self.root.clone();
 
@@ -941,7 +1890,7 @@ impl Tree {
                     //dbg!("POST CHECK", &curr_pointer);
                     match page.is_full() {
                         true => { //println!("NEED TO SPLIT");
-                            let _ = &self.split(page, &curr_pointer, &last_pointer, last_was_left);},
+                            self.split(page, &curr_pointer, &last_pointer, last_was_left, &cell)?;},
                         //false => { self.record_handler.write_page_at_offset(&page, &index).unwrap(); },
                         false => { self.record_handler.update_page(&page, &index).unwrap(); },
                     }
@@ -953,9 +1902,11 @@ impl Tree {
                     let node = self.node_handler.get_node(&index).unwrap().clone();
 
                     let axis = node.split_axis;
-                    let this_value = record.descriptor.data[axis];
+                    let this_value = tree_record.descriptor.data[axis];
                     let split_value = node.split_value;
 
+                    cell.descend(axis, split_value, this_value <= split_value);
+
                     match this_value <= split_value {
 
                         true => {
@@ -1047,8 +1998,8 @@ impl Tree {
             match curr_tup.level >= max_depth {
                 true => {//dbg!("MAX LEVEL REACHED"); //add in some leaf nodes and call it a day
 
-                    let left_record_page = RecordPage::new(self.config.record_page_length, self.config.desc_length);
-                    let right_record_page = RecordPage::new(self.config.record_page_length, self.config.desc_length);
+                    let left_record_page = RecordPage::with_codec(self.config.record_page_length, &self.record_handler.codec);
+                    let right_record_page = RecordPage::with_codec(self.config.record_page_length, &self.record_handler.codec);
 
                     let left_page_pointer = self.record_handler.add_page(&left_record_page).unwrap();
                     let right_page_pointer = self.record_handler.add_page(&right_record_page).unwrap();
@@ -1122,15 +2073,10 @@ impl Tree {
         }
     }
 
-    ///Internal method to take a single full RecordPage, find its median at the "next" axis, and
-    ///split the records along that median. This is really the only place where new internal nodes
-    ///are created.
-    pub fn split(&mut self, page: RecordPage, this_pointer: &PagePointer, parent_pointer: &PagePointer, last_was_left: bool) -> Result<(), String> {
-
-
-        //TODO: handle most of the values along the split axis being identical
-        //randomly choose left or right?
-
+    ///Internal method to take a single full RecordPage, choose an axis and value with the tree's
+    ///`split_strategy`, and split the records on them. `cell` is the region the leaf covers. This
+    ///is really the only place where new internal nodes are created.
+    pub fn split(&mut self, page: RecordPage, this_pointer: &PagePointer, parent_pointer: &PagePointer, last_was_left: bool, cell: &Cell) -> Result<(), String> {
 
         //determine the split axis
         let parent_node: Option<InternalNode> = match &parent_pointer {
@@ -1144,56 +2090,55 @@ impl Tree {
             }
         };
 
-
-        let split_axis = match &parent_node {
-            Some(x) => (x.split_axis + 1) % self.config.desc_length,
-            None => 0,
-        };
-
         //let records: Vec::<(CompoundIdentifier, f32)> = page.get_records().iter().map(|x| (x.compound_identifier.clone(), x.descriptor.data[split_axis])).collect();
         //dbg!(records);
 
-        //determine split value
+        //determine split axis and value
         let records = page.get_records();
 
-        let mut values: Vec<_> = records.iter().map(|x| x.descriptor.data[split_axis]).collect();
-
-        //because f32 doesn't like being compared
-        values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let split = choose_split(self.config.split_strategy, &records, parent_node.as_ref().map(|x| x.split_axis), cell);
 
-        let median = match values.len() % 2 {
-            0 => {
-                let idx_b: usize  = values.len() / 2;
-                let idx_a = idx_b - 1;
-
-                (values[idx_a] + values[idx_b] as f32) / 2.0
-            },
-            _ => {
-                let idx: usize  = values.len() / 2;
-                values[idx]
-            },
+        //`None` means every record is identical
+        let (split_axis, mut median) = match split {
+            Some(x) => x,
+            None => (0, records[0].descriptor.data[0]),
         };
 
+        let num_clean_left = records.iter().filter(|x| x.descriptor.data[split_axis] <= median).count();
+        let num_clean_right = records.len() - num_clean_left;
 
-        let left_record_idxs: Vec<_> = (0..records.len()).filter(|x| records[*x].descriptor.data[split_axis] <= median).collect();
-        let right_record_idxs: Vec<_> = (0..records.len()).filter(|x| records[*x].descriptor.data[split_axis] > median).collect();
-
-        assert_eq!(left_record_idxs.len() + right_record_idxs.len(), records.len());
+        //when most records share a value, everything up to it goes to one side and that page stays
+        //nearly full. Instead split by rank, breaking ties by position in the page. Records equal to
+        //the split value then sit in both children, which search and `record_in_tree` allow for
+        //since the split value is inside both children.
+        let by_rank = split.is_none() || num_clean_left.min(num_clean_right) < records.len() / 4;
 
         let mut left_records: Vec<TreeRecord> = Vec::with_capacity((records.len() / 2) + 1);
         let mut right_records: Vec<TreeRecord> = Vec::with_capacity((records.len() / 2) + 1);
 
-        //consume records and allocate into left and right pages
-        for x in records.into_iter() {
-            let is_left = x.descriptor.data[split_axis] <= median; 
-            match is_left {
-                true => {left_records.push(x)},
-                false => {right_records.push(x)},
-            }
+        match by_rank {
+            true => {
+                let mut records = records;
+                records.sort_by(|a, b| a.descriptor.data[split_axis].total_cmp(&b.descriptor.data[split_axis]));
+
+                right_records = records.split_off(records.len() / 2);
+                left_records = records;
+
+                median = left_records[left_records.len() - 1].descriptor.data[split_axis];
+            },
+            false => {
+                //consume records and allocate into left and right pages
+                for x in records.into_iter() {
+                    match x.descriptor.data[split_axis] <= median {
+                        true => {left_records.push(x)},
+                        false => {right_records.push(x)},
+                    }
+                }
+            },
         }
 
         //make new left record page at current offset
-        let mut left_record_page = RecordPage::new(self.config.record_page_length, self.config.desc_length);
+        let mut left_record_page = RecordPage::with_codec(self.config.record_page_length, &self.record_handler.codec);
         for record in left_records.iter() {
             left_record_page.add_record(record)?;
         }
@@ -1206,7 +2151,7 @@ impl Tree {
         self.record_handler.update_page(&left_record_page, this_index).unwrap();
         
         //make new right record page at next offset
-        let mut right_record_page = RecordPage::new(self.config.record_page_length, self.config.desc_length);
+        let mut right_record_page = RecordPage::with_codec(self.config.record_page_length, &self.record_handler.codec);
         for record in right_records.iter() {
             right_record_page.add_record(record)?;
         }
@@ -1260,6 +2205,9 @@ impl Tree {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::encoding::{DescriptorEncoding, ScalarQuantizer};
+    use crate::split::PcaFitter;
+    use crate::attributes::AttributeKind;
     use test::Bencher;
     use crate::data::{CompoundIdentifier, Descriptor};
     use kdam::tqdm;
@@ -1329,6 +2277,7 @@ mod tests {
 
             let answer = tree.record_in_tree(&cr_to_find).unwrap();
             assert_eq!(answer, true);
+            assert_eq!(tree.find_descriptor(&cr_to_find.descriptor).unwrap(), Some(2002));
 
             for _ in 0..2000 {
 
@@ -1445,6 +2394,7 @@ mod tests {
                 descriptor,
                 smiles: "no smiles".to_string(),
                 length: n,
+                dataset: None,
             };
 
             records.push(cr);
@@ -1678,6 +2628,7 @@ mod tests {
                 descriptor,
                 smiles: "no smiles".to_string(),
                 length: n,
+                dataset: None,
             };
 
             records.push(cr);
@@ -1820,6 +2771,7 @@ mod tests {
                 smiles: "no smiles".to_string(),
                 descriptor,
                 length: n,
+                dataset: None,
             };
 
             records.push(cr);
@@ -1896,6 +2848,861 @@ mod tests {
         }
     }
 
+    #[test]
+    fn quick_best_first_matches_brute_force() {
+
+        for n in [8, 16] {
+
+            let mut config = TreeConfig::default();
+            config.desc_length = n;
+            config.directory = "/tmp/qbf/".to_string();
+
+            let mut build_tree = Tree::force_create_with_config(config.clone());
+
+            for _ in 0..20000 {
+                let cr = CompoundRecord::random(n);
+                build_tree.add_record(&cr).unwrap();
+            }
+
+            build_tree.flush();
+
+            let tree = ImmutTree::read_from_directory(config.directory.clone());
+
+            for _ in 0..20 {
+
+                let query = Descriptor::random(n);
+
+                let mut expected = TopHits::new(25);
+                for page_index in 0..tree.record_handler.len() {
+                    let page = tree.record_handler.get_record_page_no_cache(&page_index).unwrap();
+                    for record in page.get_records() {
+                        let dist = query.distance(&record.descriptor);
+                        expected.try_add(dist, &record, &PagePointer::Leaf(page_index)).unwrap();
+                    }
+                }
+
+                let (hits, _) = tree.get_top_hits(&query, 25);
+
+                assert_eq!(hits.distances(), expected.distances());
+            }
+        }
+    }
+
+    ///The depth-first traversal `get_top_hits` used before it went best-first: descend to the
+    ///query's leaf, then revisit the skipped branches in the order they were passed
+    fn depth_first_top_hits(tree: &ImmutTree, query_descriptor: &Descriptor, n: usize) -> (TopHits, QueryStats) {
+
+        let mut hits = TopHits::new(n);
+        let mut stats = QueryStats::default();
+
+        let mut nodes_to_check: std::collections::VecDeque<(PagePointer, Option<bool>)> = std::collections::VecDeque::new();
+        nodes_to_check.push_front((tree.root.clone(), None));
+
+        while let Some((curr_pointer, ignored_left)) = nodes_to_check.pop_front() {
+
+            match (curr_pointer.clone(), ignored_left) {
+                (PagePointer::Leaf(index), _) => {
+
+                    stats.record_pages_visited += 1;
+
+                    let page = tree.record_handler.get_record_page(&index).unwrap();
+                    for record in page.get_records() {
+                        let dist = query_descriptor.distance(&record.descriptor);
+                        hits.try_add(dist, &record, &curr_pointer).unwrap();
+                    }
+                },
+                (PagePointer::Node(index), None) => {
+
+                    stats.nodes_visited += 1;
+
+                    let node = tree.node_handler.get_node(&index).unwrap();
+
+                    let go_left = query_descriptor.data[node.split_axis] <= node.split_value;
+                    let near = match go_left {
+                        true => node.left_child_pointer,
+                        false => node.right_child_pointer,
+                    };
+
+                    nodes_to_check.push_front((near, None));
+                    nodes_to_check.push_back((curr_pointer, Some(!go_left)));
+                },
+                (PagePointer::Node(index), Some(left)) => {
+
+                    let node = tree.node_handler.get_node(&index).unwrap();
+
+                    let dist = tree.dist_to_axis(node.split_axis, node.split_value, query_descriptor);
+
+                    if dist < hits.get_highest_dist() {
+                        let far = match left {
+                            true => node.left_child_pointer,
+                            false => node.right_child_pointer,
+                        };
+                        nodes_to_check.push_front((far, None));
+                    }
+                },
+            }
+        }
+
+        return (hits, stats);
+    }
+
+    #[test]
+    fn quick_best_first_visits_fewer_pages() {
+
+        for n in [8, 16] {
+
+            let mut config = TreeConfig::default();
+            config.desc_length = n;
+            config.directory = "/tmp/qbf_pages/".to_string();
+
+            let mut build_tree = Tree::force_create_with_config(config.clone());
+
+            for _ in 0..20000 {
+                let cr = CompoundRecord::random(n);
+                build_tree.add_record(&cr).unwrap();
+            }
+
+            build_tree.flush();
+
+            let tree = ImmutTree::read_from_directory(config.directory.clone());
+
+            let mut best_first_pages = 0;
+            let mut depth_first_pages = 0;
+
+            for _ in 0..50 {
+
+                let query = Descriptor::random(n);
+
+                let (hits, stats) = tree.get_top_hits(&query, 25);
+                let (expected, old_stats) = depth_first_top_hits(&tree, &query, 25);
+
+                assert_eq!(hits.distances(), expected.distances());
+
+                //only leaves that could hold a closer hit than the final 25th are scanned
+                assert!(stats.record_pages_visited <= old_stats.record_pages_visited);
+
+                best_first_pages += stats.record_pages_visited;
+                depth_first_pages += old_stats.record_pages_visited;
+            }
+
+            println!("n={}: best-first visited {} record pages, depth-first {}", n, best_first_pages, depth_first_pages);
+        }
+    }
+
+    #[test]
+    fn quick_split_strategies_match_brute_force() {
+
+        let n = 8;
+
+        //uneven variances so the strategies actually pick different axes
+        let records: Vec<CompoundRecord> = (0..10000).map(|_| {
+            let mut record = CompoundRecord::random(n);
+            for i in 0..n {
+                record.descriptor.data[i] *= 0.5_f32.powi(i as i32);
+            }
+            record
+        }).collect();
+        let queries: Vec<Descriptor> = (0..20).map(|i| records[i * 100].descriptor.clone()).collect();
+
+        let mut fitter = PcaFitter::new(n);
+        for record in records.iter() {
+            fitter.update(&record.descriptor);
+        }
+        let rotation = fitter.fit().unwrap();
+
+        for strategy in [SplitStrategy::Cyclic, SplitStrategy::MaxVariance, SplitStrategy::MaxSpread, SplitStrategy::SlidingMidpoint, SplitStrategy::PcaRotated] {
+
+            let mut config = TreeConfig::default();
+            config.desc_length = n;
+            config.directory = "/tmp/qss/".to_string();
+            config.split_strategy = strategy;
+            config.rotation = match strategy {
+                SplitStrategy::PcaRotated => Some(rotation.clone()),
+                _ => None,
+            };
+
+            let mut build_tree = Tree::force_create_with_config(config.clone());
+
+            for record in records.iter() {
+                build_tree.add_record(record).unwrap();
+            }
+
+            build_tree.flush();
+
+            let tree = ImmutTree::read_from_directory(config.directory.clone());
+
+            for query in queries.iter() {
+
+                let mut expected: Vec<f32> = records.iter().map(|x| query.distance(&x.descriptor)).collect();
+                expected.sort_by(|a, b| a.total_cmp(b));
+
+                let nn = tree.get_nearest_neighbors(query, 10);
+
+                //rotated trees only match up to rounding
+                for (found, expected) in nn.distances.iter().zip(expected.iter()) {
+                    assert!((found - expected).abs() < 1e-4, "{:?}: {} vs {}", strategy, found, expected);
+                }
+
+                //descriptors come back in the caller's space
+                let closest = nn.records[0].as_ref().unwrap();
+                assert!(closest.descriptor.distance(query) < 1e-4);
+            }
+        }
+    }
+
+    #[test]
+    fn quick_tied_split_values() {
+
+        let n = 8;
+
+        //mostly zeros, plenty of exact duplicates, and a block of identical descriptors bigger
+        //than a page
+        let mut records: Vec<CompoundRecord> = Vec::new();
+        for i in 0..6000 {
+            let mut record = CompoundRecord::random(n);
+            for (j, x) in record.descriptor.data.iter_mut().enumerate() {
+                *x = match (i * 7 + j * 13) % 10 {
+                    0 => 1.0,
+                    1 => 0.5,
+                    _ => 0.0,
+                };
+            }
+            if i % 3 == 0 {
+                record.descriptor.data = vec![0.25; n];
+            }
+            records.push(record);
+        }
+
+        //the first split is along axis 0, where nine in ten records are tied
+        let mut config = TreeConfig::default();
+        config.desc_length = n;
+        config.directory = "/tmp/qts/".to_string();
+
+        let mut build_tree = Tree::force_create_with_config(config.clone());
+
+        let mut i = 0;
+        while build_tree.record_handler.len() < 2 {
+            let mut record = CompoundRecord::random(n);
+            if i % 10 != 0 {
+                record.descriptor.data[0] = 0.0;
+            }
+            build_tree.add_record(&record).unwrap();
+            i += 1;
+        }
+
+        let left = build_tree.record_handler.get_record_page(&0).unwrap();
+        let right = build_tree.record_handler.get_record_page(&1).unwrap();
+        assert!(left.len().abs_diff(right.len()) <= 1);
+
+        for strategy in [SplitStrategy::Cyclic, SplitStrategy::MaxVariance, SplitStrategy::SlidingMidpoint] {
+
+            let mut config = config.clone();
+            config.split_strategy = strategy;
+
+            let mut build_tree = Tree::force_create_with_config(config.clone());
+
+            for record in records.iter() {
+                build_tree.add_record(record).unwrap();
+            }
+
+            build_tree.flush();
+
+            let mut tree = ImmutTree::read_from_directory(config.directory.clone());
+
+            //descriptors are stored exactly, and ties on either side of a split are still found
+            for record in records.iter().step_by(37) {
+                assert!(tree.record_in_tree(record).unwrap());
+            }
+
+            //splits keep pages at least a quarter full, even along tied axes
+            let mut num_stored = 0;
+            for page_index in 0..tree.record_handler.len() {
+                let page = tree.record_handler.get_record_page_no_cache(&page_index).unwrap();
+                assert!(page.len() >= page.get_capacity() / 4, "{:?}: {} of {}", strategy, page.len(), page.get_capacity());
+                num_stored += page.len();
+            }
+            assert_eq!(num_stored, records.len());
+
+            for record in records.iter().step_by(300) {
+
+                let query = &record.descriptor;
+
+                let mut expected: Vec<f32> = records.iter().map(|x| query.distance(&x.descriptor)).collect();
+                expected.sort_by(|a, b| a.total_cmp(b));
+
+                let nn = tree.get_nearest_neighbors(query, 50);
+
+                assert_eq!(nn.distances, expected[..50].to_vec());
+            }
+        }
+    }
+
+    #[test]
+    fn quick_source_datasets() {
+
+        let n = 8;
+
+        let mut config = TreeConfig::default();
+        config.desc_length = n;
+        config.directory = "/tmp/qsd/".to_string();
+
+        let mut build_tree = Tree::force_create_with_config(config.clone());
+
+        let names = ["enamine", "mcule", "chemspace"];
+        for name in names.iter() {
+            build_tree.database.dataset_id(name).unwrap();
+        }
+        assert_eq!(build_tree.database.dataset_id("mcule").unwrap(), 1);
+
+        let mut records: Vec<CompoundRecord> = Vec::new();
+        for i in 0..6000 {
+            let mut record = CompoundRecord::random(n);
+            record.dataset = Some((i % 3) as u8);
+            build_tree.add_record(&record).unwrap();
+            records.push(record);
+        }
+
+        //record 0 is from enamine, and now also from chemspace under another name
+        let alias = CompoundIdentifier::from_str("CS-0");
+        build_tree.database.add_alias(&0, &alias, Some(2)).unwrap();
+        assert!(build_tree.database.add_alias(&6000, &alias, Some(2)).is_err());
+
+        build_tree.flush();
+
+        let tree = ImmutTree::read_from_directory(config.directory.clone());
+
+        let nn = tree.get_nearest_neighbors(&records[0].descriptor, 3);
+        assert_eq!(nn.catalogs.as_ref().unwrap()[0], vec!["enamine".to_string(), "chemspace".to_string()]);
+        assert_eq!(nn.aliases.as_ref().unwrap()[0], vec!["CS-0".to_string()]);
+        assert!(nn.aliases.as_ref().unwrap()[1].is_empty());
+
+        //a hit the database can't return is an error, not a shorter result
+        let (top_hits, _) = tree.get_top_hits_best_first(&records[0].descriptor, 3, &SearchParams::exact());
+        let mut hits = top_hits.into_sorted_vec();
+        hits[1].record.index = tree.database.len();
+        assert!(NearestNeighbors::from_hits(hits, &tree.database, true).is_err());
+
+        let mask = tree.database.dataset_mask(&["mcule".to_string()]).unwrap();
+        assert!(tree.database.dataset_mask(&["zinc".to_string()]).is_err());
+
+        let mut params = SearchParams::exact();
+        params.sources = Some(mask);
+
+        for query in records.iter().step_by(500) {
+
+            let (nn, _) = tree.get_nearest_neighbors_with_params(&query.descriptor, 10, &params).unwrap();
+
+            let mut expected: Vec<f32> = records.iter()
+                .filter(|x| x.dataset == Some(1))
+                .map(|x| query.descriptor.distance(&x.descriptor))
+                .collect();
+            expected.sort_by(|a, b| a.total_cmp(b));
+
+            assert_eq!(nn.distances, expected[..10].to_vec());
+            assert!(nn.catalogs.unwrap().iter().all(|x| x.contains(&"mcule".to_string())));
+        }
+    }
+
+    #[test]
+    fn quick_attribute_filter() {
+
+        let n = 8;
+
+        let mut config = TreeConfig::default();
+        config.desc_length = n;
+        config.directory = "/tmp/qaf/".to_string();
+        config.fingerprint_bits = Some(64);
+        config.attributes = vec![
+            AttributeColumn { name: "mw".to_string(), kind: AttributeKind::Numeric },
+            AttributeColumn { name: "vendor".to_string(), kind: AttributeKind::Categorical },
+        ];
+
+        let mut build_tree = Tree::force_create_with_config(config.clone());
+
+        let vendors = ["enamine", "mcule", "chemspace", ""];
+
+        let mut records: Vec<CompoundRecord> = Vec::new();
+        let mut fingerprints: Vec<Fingerprint> = Vec::new();
+        let mut rows: Vec<(f32, &str)> = Vec::new();
+        for i in 0..6000 {
+            let record = CompoundRecord::random(n);
+            let fingerprint = Fingerprint::random(64, 0.2);
+            let row = ((i % 600) as f32, vendors[i % 4]);
+
+            let raw = [row.0.to_string(), row.1.to_string()];
+            let values = build_tree.attributes.as_mut().unwrap().parse_row(&raw).unwrap();
+            build_tree.add_record_with_attributes(&record, Some(&fingerprint), &values).unwrap();
+
+            records.push(record);
+            fingerprints.push(fingerprint);
+            rows.push(row);
+        }
+
+        //the row is checked before the record goes in
+        assert!(build_tree.add_record_with_attributes(&CompoundRecord::random(n), Some(&Fingerprint::random(64, 0.2)), &[AttributeValue::Missing]).is_err());
+        assert_eq!(build_tree.database.len(), 6000);
+
+        build_tree.flush();
+
+        let tree = ImmutTree::read_from_directory(config.directory.clone());
+
+        let store = tree.attributes.as_ref().unwrap();
+        let mut params = SearchParams::exact();
+        params.filter = Some(store.parse_filter("mw<100,vendor=mcule|chemspace").unwrap());
+
+        let passes = |i: usize| rows[i].0 < 100.0 && (rows[i].1 == "mcule" || rows[i].1 == "chemspace");
+
+        for query in records.iter().step_by(500) {
+
+            let mut expected: Vec<f32> = (0..records.len())
+                .filter(|i| passes(*i))
+                .map(|i| query.descriptor.distance(&records[i].descriptor))
+                .collect();
+            expected.sort_by(|a, b| a.total_cmp(b));
+
+            let (nn, _) = tree.get_nearest_neighbors_with_params(&query.descriptor, 10, &params).unwrap();
+            assert_eq!(nn.distances, expected[..10].to_vec());
+
+            let radius = expected[20];
+            let nn = tree.get_neighbors_within_radius_with_params(&query.descriptor, radius, &params).unwrap();
+            let within: Vec<f32> = expected.iter().filter(|x| **x < radius).cloned().collect();
+            assert_eq!(nn.distances, within);
+        }
+
+        //Tanimoto candidates are filtered too, so even the query itself is left out if it fails
+        for (i, query) in records.iter().enumerate().step_by(500) {
+
+            let nn = tree.get_nearest_neighbors_by_tanimoto(&query.descriptor, &fingerprints[i], 10, &params).unwrap();
+            assert_eq!(nn.records.len(), 10);
+
+            for record in nn.records.iter() {
+                let j = records.iter().position(|x| x.compound_identifier == record.as_ref().unwrap().compound_identifier).unwrap();
+                assert!(passes(j));
+            }
+        }
+
+        //compounds without a vendor pass no vendor condition, not even !=
+        params.filter = Some(store.parse_filter("vendor!=enamine").unwrap());
+        let nn = tree.get_neighbors_within_radius_with_params(&records[0].descriptor, f32::MAX, &params).unwrap();
+        assert_eq!(nn.distances.len(), 3000);
+    }
+
+    #[test]
+    fn quick_top_hits_heap() {
+
+        let n = 8;
+        let pointer = PagePointer::Leaf(0);
+
+        let mut hits = TopHits::new(3);
+        for distance in [5.0, 1.0, 4.0, 2.0, 3.0] {
+            hits.try_add(distance, &TreeRecord::random(n), &pointer).unwrap();
+        }
+        assert_eq!(hits.distances(), vec![1.0, 2.0, 3.0]);
+        assert_eq!(hits.get_highest_dist(), 3.0);
+
+        //fewer candidates than k gives partial results
+        let mut hits = TopHits::new(10);
+        hits.try_add(1.0, &TreeRecord::random(n), &pointer).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits.get_highest_dist(), f32::MAX);
+
+        let mut hits = TopHits::new(0);
+        hits.try_add(1.0, &TreeRecord::random(n), &pointer).unwrap();
+        assert!(hits.is_empty());
+
+        let mut hits = TopHits::within_radius(2.5);
+        for distance in [5.0, 1.0, 4.0, 2.0, 3.0] {
+            hits.try_add(distance, &TreeRecord::random(n), &pointer).unwrap();
+        }
+        assert_eq!(hits.drain_sorted().iter().map(|x| x.distance).collect::<Vec<_>>(), vec![1.0, 2.0]);
+        assert!(hits.is_empty());
+    }
+
+    #[test]
+    fn quick_radius_and_batch_queries() {
+
+        let n = 8;
+
+        let mut config = TreeConfig::default();
+        config.desc_length = n;
+        config.directory = "/tmp/qrb/".to_string();
+
+        let mut build_tree = Tree::force_create_with_config(config.clone());
+
+        for _ in 0..20000 {
+            let cr = CompoundRecord::random(n);
+            build_tree.add_record(&cr).unwrap();
+        }
+
+        build_tree.flush();
+
+        let tree = ImmutTree::read_from_directory(config.directory.clone());
+
+        let queries: Vec<Descriptor> = (0..10).map(|_| Descriptor::random(n)).collect();
+
+        //k larger than the tree
+        let nn = tree.get_nearest_neighbors(&queries[0], 25000);
+        assert_eq!(nn.records.len(), 20000);
+        assert!(nn.distances.windows(2).all(|x| x[0] <= x[1]));
+
+        let radius = 0.6;
+        for query in queries.iter() {
+
+            let mut expected = 0;
+            for page_index in 0..tree.record_handler.len() {
+                let page = tree.record_handler.get_record_page_no_cache(&page_index).unwrap();
+                for record in page.get_records() {
+                    if query.distance(&record.descriptor) < radius {
+                        expected += 1;
+                    }
+                }
+            }
+
+            let nn = tree.get_neighbors_within_radius(query, radius);
+            assert_eq!(nn.records.len(), expected);
+            assert!(nn.distances.iter().all(|x| *x < radius));
+        }
+
+        let batch = tree.get_nearest_neighbors_batch(&queries, 10).unwrap();
+        assert_eq!(batch.len(), queries.len());
+        for (query, nn) in queries.iter().zip(batch.iter()) {
+            assert_eq!(nn.distances, tree.get_nearest_neighbors(query, 10).distances);
+        }
+    }
+
+    #[test]
+    fn quick_tanimoto_rerank() {
+
+        let n = 8;
+        let num_bits = 256;
+
+        let mut config = TreeConfig::default();
+        config.desc_length = n;
+        config.directory = "/tmp/qtr/".to_string();
+        config.fingerprint_bits = Some(num_bits);
+
+        let mut build_tree = Tree::force_create_with_config(config.clone());
+        build_tree.database.dataset_id("enamine").unwrap();
+        build_tree.database.dataset_id("mcule").unwrap();
+
+        let records: Vec<CompoundRecord> = (0..5000).map(|i| {
+            let mut record = CompoundRecord::random(n);
+            record.dataset = Some((i % 2) as u8);
+            record
+        }).collect();
+        let fingerprints: Vec<Fingerprint> = (0..5000).map(|_| Fingerprint::random(num_bits, 0.1)).collect();
+
+        assert!(build_tree.add_record(&records[0]).is_err());
+        assert!(build_tree.add_record_with_fingerprint(&records[0], &Fingerprint::random(64, 0.1)).is_err());
+
+        for (record, fingerprint) in records.iter().zip(fingerprints.iter()) {
+            build_tree.add_record_with_fingerprint(record, fingerprint).unwrap();
+        }
+
+        build_tree.flush();
+
+        let tree = ImmutTree::read_from_directory(config.directory.clone());
+        assert_eq!(tree.fingerprints.as_ref().unwrap().len(), 5000);
+
+        for i in 0..10 {
+
+            let query = &records[i * 100];
+            let nn = tree.get_nearest_neighbors_by_tanimoto(&query.descriptor, &fingerprints[i * 100], 10, &SearchParams::exact()).unwrap();
+            let tanimoto = nn.tanimoto.clone().unwrap();
+
+            //the query compound is its own nearest descriptor, so it's a candidate and ranks first
+            assert_eq!(tanimoto[0], 1.0);
+            assert_eq!(nn.records[0].as_ref().unwrap().compound_identifier, query.compound_identifier);
+            assert!(tanimoto.windows(2).all(|x| x[0] >= x[1]));
+
+            //the best of the 100 nearest descriptors by fingerprint
+            let candidates = tree.get_nearest_neighbors(&query.descriptor, 100);
+            let mut expected: Vec<f32> = Vec::new();
+            for (distance, record) in candidates.distances.iter().zip(candidates.records.iter()) {
+                let j = records.iter().position(|x| x.compound_identifier == record.as_ref().unwrap().compound_identifier).unwrap();
+                assert_eq!(*distance, query.descriptor.distance(&records[j].descriptor));
+                expected.push(fingerprints[i * 100].tanimoto(&fingerprints[j]));
+            }
+            expected.sort_by(|a, b| b.total_cmp(a));
+            assert_eq!(tanimoto, expected[..10].to_vec());
+        }
+
+        //candidates only come from the requested sources. Record 100 is from enamine, so it's left out
+        let mut params = SearchParams::exact();
+        params.sources = Some(tree.database.dataset_mask(&["mcule".to_string()]).unwrap());
+
+        let nn = tree.get_nearest_neighbors_by_tanimoto(&records[100].descriptor, &fingerprints[100], 10, &params).unwrap();
+        assert_eq!(nn.records.len(), 10);
+        assert!(nn.tanimoto.unwrap()[0] < 1.0);
+        assert!(nn.catalogs.unwrap().iter().all(|x| x == &vec!["mcule".to_string()]));
+
+        assert!(tree.get_nearest_neighbors_by_tanimoto(&records[0].descriptor, &Fingerprint::random(64, 0.1), 10, &SearchParams::exact()).is_err());
+    }
+
+    #[test]
+    fn quick_lossy_encodings_rerank() {
+
+        let n = 16;
+
+        let records: Vec<CompoundRecord> = (0..20000).map(|_| CompoundRecord::random(n)).collect();
+        let queries: Vec<Descriptor> = (0..20).map(|_| Descriptor::random(n)).collect();
+
+        let mut quantizer = ScalarQuantizer::empty(n);
+        for record in records.iter() {
+            quantizer.update(&record.descriptor);
+        }
+
+        let mut num_pages: Vec<usize> = Vec::new();
+
+        for encoding in [DescriptorEncoding::F32, DescriptorEncoding::F16, DescriptorEncoding::Int8] {
+
+            let mut config = TreeConfig::default();
+            config.desc_length = n;
+            config.directory = "/tmp/qle/".to_string();
+            config.descriptor_encoding = encoding;
+            config.quantizer = Some(quantizer.clone());
+            config.keep_full_vectors = true;
+
+            let mut build_tree = Tree::force_create_with_config(config.clone());
+            for record in records.iter() {
+                build_tree.add_record(record).unwrap();
+            }
+            build_tree.flush();
+
+            let mut tree = ImmutTree::read_from_directory(config.directory.clone());
+            num_pages.push(tree.record_handler.len());
+
+            //lookups compare against the descriptors as stored, not the originals
+            for record in records.iter().step_by(1000) {
+                assert!(tree.record_in_tree(record).unwrap(), "{:?}", encoding);
+            }
+
+            let mut found: usize = 0;
+            for query in queries.iter() {
+
+                let mut expected: Vec<f32> = records.iter().map(|x| query.distance(&x.descriptor)).collect();
+                expected.sort_by(|a, b| a.total_cmp(b));
+                expected.truncate(10);
+
+                let nn = tree.get_nearest_neighbors(query, 10);
+                assert_eq!(nn.distances.len(), 10);
+
+                //re-ranked distances are exact, so anything found matches the true distances
+                found += nn.distances.iter().filter(|x| expected.contains(x)).count();
+            }
+
+            let recall = found as f32 / (10 * queries.len()) as f32;
+            match encoding {
+                DescriptorEncoding::Int8 => assert!(recall > 0.9),
+                _ => assert!(recall > 0.99),
+            }
+        }
+
+        assert!(num_pages[1] * 3 < num_pages[0] * 2);
+        assert!(num_pages[2] * 2 < num_pages[0]);
+    }
+
+    #[test]
+    fn quick_find_descriptor_lossy() {
+
+        let n = 8;
+
+        let records: Vec<CompoundRecord> = (0..2000).map(|_| CompoundRecord::random(n)).collect();
+
+        let mut quantizer = ScalarQuantizer::empty(n);
+        for record in records.iter() {
+            quantizer.update(&record.descriptor);
+        }
+
+        //a near copy of the first record that quantizes to the same code
+        let mut near = records[0].clone();
+        near.descriptor.data[0] += 1e-6;
+
+        for keep_full_vectors in [true, false] {
+
+            let mut config = TreeConfig::default();
+            config.desc_length = n;
+            config.directory = "/tmp/qfdl/".to_string();
+            config.descriptor_encoding = DescriptorEncoding::Int8;
+            config.quantizer = Some(quantizer.clone());
+            config.keep_full_vectors = keep_full_vectors;
+
+            let mut tree = Tree::force_create_with_config(config.clone());
+            for record in records.iter() {
+                tree.add_record(record).unwrap();
+            }
+
+            let codec = config.get_codec();
+            assert_ne!(near.descriptor, records[0].descriptor);
+            assert_eq!(codec.round_trip(&near.descriptor), codec.round_trip(&records[0].descriptor));
+
+            assert_eq!(tree.find_descriptor(&records[0].descriptor).unwrap(), Some(0));
+            assert_eq!(tree.find_descriptor(&records[1500].descriptor).unwrap(), Some(1500));
+
+            //only the full precision vectors can tell the two apart
+            let expected = match keep_full_vectors {
+                true => None,
+                false => Some(0),
+            };
+            assert_eq!(tree.find_descriptor(&near.descriptor).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn quick_approximate_nn() {
+
+        let n = 8;
+
+        let mut config = TreeConfig::default();
+        config.desc_length = n;
+        config.directory = "/tmp/qann/".to_string();
+
+        let mut build_tree = Tree::force_create_with_config(config.clone());
+
+        for _ in 0..20000 {
+            let cr = CompoundRecord::random(n);
+            build_tree.add_record(&cr).unwrap();
+        }
+
+        build_tree.flush();
+
+        let tree = ImmutTree::read_from_directory(config.directory.clone());
+
+        for _ in 0..20 {
+
+            let query = Descriptor::random(n);
+
+            let (exact, exact_stats) = tree.get_nearest_neighbors_with_stats(&query, 10);
+            assert!(exact_stats.exact);
+
+            //the default params are an exact search
+            let (best_first, stats) = tree.get_nearest_neighbors_with_params(&query, 10, &SearchParams::default()).unwrap();
+            assert!(stats.exact);
+            assert_eq!(best_first.distances, exact.distances);
+
+            //a page budget is respected
+            let budget = SearchParams { max_record_pages: Some(2), epsilon: 0.0, ..SearchParams::exact() };
+            let (approx, stats) = tree.get_nearest_neighbors_with_params(&query, 10, &budget).unwrap();
+            assert!(stats.record_pages_visited <= 2);
+            assert_eq!(approx.exact, stats.exact);
+
+            //approximate hits can't beat the exact ones
+            let loose = SearchParams { max_record_pages: None, epsilon: 1.0, ..SearchParams::exact() };
+            let (approx, _) = tree.get_nearest_neighbors_with_params(&query, 10, &loose).unwrap();
+            for i in 0..10 {
+                assert!(approx.distances[i] >= exact.distances[i]);
+            }
+        }
+    }
+
+    #[test]
+    fn quick_reorder_nodes() {
+
+        let n = 8;
+
+        let mut config = TreeConfig::default();
+        config.desc_length = n;
+        config.directory = "/tmp/qrn/".to_string();
+
+        let mut build_tree = Tree::force_create_with_config(config.clone());
+
+        for _ in 0..20000 {
+            let cr = CompoundRecord::random(n);
+            build_tree.add_record(&cr).unwrap();
+        }
+
+        build_tree.flush();
+
+        let before = build_tree.check_integrity().unwrap();
+        assert_eq!(before.num_records, 20000);
+
+        let queries: Vec<Descriptor> = (0..20).map(|_| Descriptor::random(n)).collect();
+        let tree = ImmutTree::read_from_directory(config.directory.clone());
+        let expected: Vec<Vec<f32>> = queries.iter().map(|x| tree.get_nearest_neighbors(x, 10).distances).collect();
+
+        let original = build_tree.node_handler.store.clone();
+        build_tree.reorder_nodes().unwrap();
+        build_tree.flush();
+
+        assert_ne!(build_tree.node_handler.store, original);
+        assert_eq!(build_tree.check_integrity().unwrap(), before);
+
+        //the first block is breadth first from the root
+        let root = &build_tree.node_handler.store[0];
+        assert_eq!(root.left_child_pointer, PagePointer::Node(1));
+        assert_eq!(root.right_child_pointer, PagePointer::Node(2));
+
+        let tree = ImmutTree::read_from_directory(config.directory.clone());
+        for (query, distances) in queries.iter().zip(expected) {
+            assert_eq!(tree.get_nearest_neighbors(query, 10).distances, distances);
+        }
+
+        //a node with two parents is caught by both
+        let mut broken = build_tree.node_handler.store[1].clone();
+        broken.right_child_pointer = broken.left_child_pointer.clone();
+        build_tree.node_handler.update_node(&1, &broken).unwrap();
+
+        assert!(build_tree.check_integrity().is_err());
+        assert!(build_tree.reorder_nodes().is_err());
+    }
+
+    #[test]
+    fn quick_wide_descriptors() {
+
+        //more dimensions than a legacy node can name as a split axis
+        let n = 300;
+
+        let mut config = TreeConfig::default();
+        config.desc_length = n;
+        config.record_page_length = 65536;
+        config.directory = "/tmp/qwd/".to_string();
+        config.split_strategy = SplitStrategy::MaxVariance;
+
+        let mut build_tree = Tree::force_create_with_config(config.clone());
+
+        let mut descriptors: Vec<Descriptor> = Vec::new();
+        for _ in 0..2000 {
+            let cr = CompoundRecord::random(n);
+            descriptors.push(cr.descriptor.clone());
+            build_tree.add_record(&cr).unwrap();
+        }
+
+        build_tree.flush();
+
+        assert!(build_tree.node_handler.store.iter().any(|x| x.split_axis >= 256));
+
+        let tree = ImmutTree::read_from_directory(config.directory.clone());
+        assert_eq!(tree.config.format_version, layout::FORMAT_VERSION);
+
+        for _ in 0..5 {
+            let query = Descriptor::random(n);
+
+            let nearest = descriptors.iter().map(|x| x.distance(&query)).min_by(|a, b| a.total_cmp(b)).unwrap();
+            let nn = tree.get_nearest_neighbors(&query, 1);
+            assert_eq!(nn.distances[0], nearest);
+        }
+
+        //configs the node and page formats can't represent are rejected
+        let mut bad = config.clone();
+        bad.format_version = layout::LEGACY_FORMAT_VERSION;
+        assert!(bad.validate().is_err());
+
+        let mut bad = config.clone();
+        bad.desc_length = 70000;
+        bad.record_page_length = 1 << 20;
+        assert!(bad.validate().is_err());
+
+        let mut bad = config.clone();
+        bad.record_page_length = 2000;
+        assert!(bad.validate().is_err());
+
+        let mut bad = config.clone();
+        bad.format_version = layout::FORMAT_VERSION + 1;
+        assert!(bad.validate().is_err());
+
+        assert!(config.validate().is_ok());
+    }
+
 
     /*
     #[test]
@@ -1,5 +1,7 @@
 use kd_tree::tree;
 use kd_tree::data::Descriptor;
+use kd_tree::database::ImmutDatabase;
+use kd_tree::fingerprint::{Fingerprint, ImmutFingerprintIndex};
 
 use std::convert::Infallible;
 use std::sync::{Arc, Mutex};
@@ -54,13 +56,27 @@ async fn handle_request(req: Request<Body>, dirname: String) -> Result<Response<
 
 async fn dispatch_test(dirname: &String) -> Result<Response<Body>> {
 
-    let tree = tree::ImmutTree::read_from_directory(dirname.clone());
-    let length = tree.config.desc_length;
-    let descriptor = Descriptor::random(length);
+    let config = tree::TreeConfig::from_file(dirname.clone() + "/config.yaml");
 
-    dbg!(&descriptor);
+    let nn = match config.index_type {
+        tree::IndexType::KdTree => {
+            let tree = tree::ImmutTree::read_from_directory(dirname.clone());
+            let descriptor = Descriptor::random(config.desc_length);
 
-    let nn = tree.get_nearest_neighbors(&descriptor, 10);
+            dbg!(&descriptor);
+
+            tree.get_nearest_neighbors_with_params(&descriptor, 10, &tree::SearchParams::exact()).map(|x| x.0)
+        },
+        tree::IndexType::Fingerprint => {
+            let index = ImmutFingerprintIndex::read_from_directory(dirname.clone());
+            index.get_nearest_neighbors_with_params(&Fingerprint::random(config.desc_length, 0.05), 10, &tree::SearchParams::exact())
+        },
+    };
+
+    let nn = match nn {
+        Ok(x) => x,
+        Err(message) => return Ok(Response::new(Body::from(message.as_bytes().to_vec()))),
+    };
     let s = serde_yaml::to_string(&nn).unwrap();
     //let s = nn.to_yaml();
 
@@ -68,7 +84,7 @@ async fn dispatch_test(dirname: &String) -> Result<Response<Body>> {
 }
 async fn dispatch_nn(req: Request<Body>, dirname: &String) -> Result<Response<Body>> {
 
-    let tree = tree::ImmutTree::read_from_directory(dirname.clone());
+    let config = tree::TreeConfig::from_file(dirname.clone() + "/config.yaml");
     dbg!("in dispatch_nn");
     let path = req.uri().path().to_string();
 
@@ -84,7 +100,109 @@ async fn dispatch_nn(req: Request<Body>, dirname: &String) -> Result<Response<Bo
         _ => (),
     };
 
-    let smiles_request = format!("http://localhost:5000/smiles/salsa16/{}", smiles);
+    //fingerprints come back from the embedding service as 0/1 vectors
+    let model = match config.embedding_model() {
+        Ok(x) => x,
+        Err(message) => return Ok(Response::new(Body::from(message.as_bytes().to_vec()))),
+    };
+
+    let embedding = match fetch_embedding(&model, &smiles).await {
+        Ok(x) => x,
+        Err(message) => return Ok(Response::new(Body::from(message.as_bytes().to_vec()))),
+    };
+
+    let nn = match config.index_type {
+        tree::IndexType::KdTree => {
+            let tree = tree::ImmutTree::read_from_directory(dirname.clone());
+
+            if embedding.len() != config.desc_length {
+                return Ok(Response::new(Body::from(format!("Expected a {} value descriptor", config.desc_length).as_bytes().to_vec())));
+            }
+
+            let descriptor = Descriptor{ data: embedding.clone(), length: embedding.len()};
+
+            dbg!(&descriptor);
+
+            /*
+            let mut mg = tree.lock().unwrap();
+            let nn = mg.get_nearest_neighbors(&descriptor, num_nn);
+            */
+            let mut params = parse_search_params(req.uri().query());
+
+            if let Err(message) = apply_requested_datasets(&mut params, &tree.database, req.uri().query()) {
+                return Ok(Response::new(Body::from(message.as_bytes().to_vec())));
+            }
+
+            if let Some(text) = requested_filter(req.uri().query()) {
+                let filter = match &tree.attributes {
+                    Some(store) => store.parse_filter(&text),
+                    None => Err("Tree doesn't keep attributes to filter on".to_string()),
+                };
+
+                params.filter = match filter {
+                    Ok(x) => Some(x),
+                    Err(message) => return Ok(Response::new(Body::from(message.as_bytes().to_vec()))),
+                };
+            }
+
+            match (wants_tanimoto_rerank(req.uri().query()), config.fingerprint_bits) {
+                (true, Some(_)) => {
+                    let fingerprint_model = match &config.fingerprint_model {
+                        Some(x) => x,
+                        None => return Ok(Response::new(Body::from("Tree config doesn't name the fingerprint_model to re-rank with".to_string().as_bytes().to_vec()))),
+                    };
+
+                    let fingerprint = match fetch_embedding(fingerprint_model, &smiles).await {
+                        Ok(x) => Fingerprint::from_dense(&x),
+                        Err(message) => return Ok(Response::new(Body::from(message.as_bytes().to_vec()))),
+                    };
+
+                    match tree.get_nearest_neighbors_by_tanimoto(&descriptor, &fingerprint, num_nn, &params) {
+                        Ok(nn) => nn,
+                        Err(message) => return Ok(Response::new(Body::from(message.as_bytes().to_vec()))),
+                    }
+                },
+                (true, None) => return Ok(Response::new(Body::from("Tree doesn't keep fingerprints to re-rank with".to_string().as_bytes().to_vec()))),
+                (false, _) => match tree.get_nearest_neighbors_with_params(&descriptor, num_nn, &params) {
+                    Ok((nn, _stats)) => nn,
+                    Err(message) => return Ok(Response::new(Body::from(message.as_bytes().to_vec()))),
+                },
+            }
+        },
+        tree::IndexType::Fingerprint => {
+            let index = ImmutFingerprintIndex::read_from_directory(dirname.clone());
+            let fingerprint = Fingerprint::from_dense(&embedding);
+
+            if fingerprint.num_bits != config.desc_length {
+                return Ok(Response::new(Body::from(format!("Expected a {} bit fingerprint", config.desc_length).as_bytes().to_vec())));
+            }
+
+            let mut params = tree::SearchParams::exact();
+
+            if let Err(message) = apply_requested_datasets(&mut params, &index.database, req.uri().query()) {
+                return Ok(Response::new(Body::from(message.as_bytes().to_vec())));
+            }
+
+            if requested_filter(req.uri().query()).is_some() {
+                return Ok(Response::new(Body::from("Fingerprint indexes don't keep attributes to filter on".to_string().as_bytes().to_vec())));
+            }
+
+            match index.get_nearest_neighbors_with_params(&fingerprint, num_nn, &params) {
+                Ok(nn) => nn,
+                Err(message) => return Ok(Response::new(Body::from(message.as_bytes().to_vec()))),
+            }
+        },
+    };
+    let s = nn.to_yaml();
+
+    Ok(Response::new(Body::from(s.as_bytes().to_vec())))
+}
+
+///Asks the embedding service for `model`'s embedding of `smiles`, returning the service's
+///message if it can't make one
+async fn fetch_embedding(model: &str, smiles: &str) -> std::result::Result<Vec<f32>, String> {
+
+    let smiles_request = format!("http://localhost:5000/smiles/{}/{}", model, smiles);
     dbg!(&smiles_request);
 
     let response = reqwest::get(&smiles_request).await.unwrap();
@@ -94,28 +212,120 @@ async fn dispatch_nn(req: Request<Body>, dirname: &String) -> Result<Response<Bo
         reqwest::StatusCode::OK => (),
         _ => {
             let message = response.text().await.unwrap();
-                return Ok(Response::new(Body::from(message.to_string().as_bytes().to_vec())));
-            }
-
+            return Err(message);
+        },
     }
+
     let embedding = response.text().await.unwrap();
     dbg!(&embedding);
 
     let embedding: Vec<f32> = serde_json::from_str(&embedding).unwrap();
     dbg!(&embedding);
 
-    let descriptor = Descriptor{ data: embedding.clone(), length: embedding.len()};
+    return Ok(embedding);
+}
 
-    dbg!(&descriptor);
+///`rerank=tanimoto` in the query string re-ranks descriptor neighbors by fingerprint similarity,
+///e.g. `/nn/10/CCO?rerank=tanimoto`
+fn wants_tanimoto_rerank(query: Option<&str>) -> bool {
 
-    /*
-    let mut mg = tree.lock().unwrap();
-    let nn = mg.get_nearest_neighbors(&descriptor, num_nn);
-    */
-    let nn = tree.get_nearest_neighbors(&descriptor, num_nn);
-    let s = nn.to_yaml();
+    return match query {
+        Some(x) => x.split("&").any(|pair| pair == "rerank=tanimoto"),
+        None => false,
+    }
+}
 
-    Ok(Response::new(Body::from(s.as_bytes().to_vec())))
+///Source datasets to restrict results to, e.g. `/nn/10/CCO?source=enamine,mcule`
+fn requested_datasets(query: Option<&str>) -> Option<Vec<String>> {
+
+    let query = query?;
+
+    for pair in query.split("&") {
+        if let Some(value) = pair.strip_prefix("source=") {
+            return Some(value.split(",").filter(|x| !x.is_empty()).map(|x| x.to_string()).collect());
+        }
+    }
+
+    return None;
+}
+
+///Restricts `params` to the datasets named by `?source=`, if there are any
+fn apply_requested_datasets(params: &mut tree::SearchParams, database: &ImmutDatabase, query: Option<&str>) -> std::result::Result<(), String> {
+
+    if let Some(names) = requested_datasets(query) {
+        params.sources = Some(database.dataset_mask(&names)?);
+    }
+
+    return Ok(());
+}
+
+///Attribute filter to apply, e.g. `/nn/10/CCO?where=mw<450,vendor=enamine|mcule`. Escaped
+///characters like `%3C` for `<` are decoded.
+fn requested_filter(query: Option<&str>) -> Option<String> {
+
+    let query = query?;
+
+    for pair in query.split("&") {
+        if let Some(value) = pair.strip_prefix("where=") {
+            return Some(percent_decode(value));
+        }
+    }
+
+    return None;
+}
+
+///Decodes `%XX` escapes, leaving malformed ones as they are
+fn percent_decode(text: &str) -> String {
+
+    let bytes = text.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes[i] == b'%' && i + 2 < bytes.len() {
+            true => std::str::from_utf8(&bytes[i + 1..i + 3]).ok().and_then(|x| u8::from_str_radix(x, 16).ok()),
+            false => None,
+        };
+
+        match escaped {
+            Some(x) => {
+                decoded.push(x);
+                i += 3;
+            },
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            },
+        }
+    }
+
+    return String::from_utf8_lossy(&decoded).to_string();
+}
+
+///Reads `max_pages` and `epsilon` from the query string, e.g. `/nn/10/CCO?max_pages=50&epsilon=0.1`.
+///Anything missing or unparseable falls back to an exact search.
+fn parse_search_params(query: Option<&str>) -> tree::SearchParams {
+
+    let mut params = tree::SearchParams::exact();
+
+    let query = match query {
+        Some(x) => x,
+        None => return params,
+    };
+
+    for pair in query.split("&") {
+        let mut kv = pair.splitn(2, "=");
+        let key = kv.next().unwrap_or("");
+        let value = kv.next().unwrap_or("");
+
+        match key {
+            "max_pages" => params.max_record_pages = value.parse::<usize>().ok(),
+            "epsilon" => params.epsilon = value.parse::<f32>().unwrap_or(0.0),
+            _ => (),
+        }
+    }
+
+    return params;
 }
 
 async fn dispatch_range(req: Request<Body>, tree: Arc<Mutex<tree::ImmutTree>>) -> Result<Response<Body>> {